@@ -1,31 +1,45 @@
 //use clap::{arg, App, Command, AppSettings, SubCommand};
 use clap::Command;
 #[allow(unused_imports)]
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 // how it's done in scopeguard
 //#[macro_use(vect)] extern crate rtmacros;
 // also works?
 use rtlib::vect;
 use std::{
     io::{stderr, Write},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
-use rayon::prelude::*;
-
-use rtlib::bvh::Bvh;
+use rtlib::animation::{frame_path, frame_times, CameraPath, Interpolation};
+use rtlib::aov::{write_aov, Aov, AovPixel};
 use rtlib::camera::Camera;
+use rtlib::checkpoint::Checkpoint;
+use rtlib::denoise::denoise;
+use rtlib::distributed::{Coordinator, Worker};
+use rtlib::environment::{Background, Environment};
+use rtlib::film::{Filter, FilterKind};
+use rtlib::firefly::MedianOfMeans;
+use rtlib::framebuffer::Framebuffer;
 use rtlib::hitlist::HitList;
 #[allow(unused_imports)]
 use rtlib::materials::{Dielectric, Lambertian, Metal};
+use rtlib::output::{save_image, ImageFormat};
+use rtlib::progress::RenderControl;
+use rtlib::render::{Crop, RenderSettings, Renderer};
+use rtlib::sampler::SamplerKind;
+use rtlib::sky::{sun_direction, Sky, SUN_RADIUS};
 #[allow(unused_imports)]
 use rtlib::sphere::Sphere;
+use rtlib::stats::RenderStats;
+use rtlib::tonemap::{DisplayTransform, ToneMapperType, Transfer};
 #[allow(unused_imports)]
 use rtlib::util::{
-    color, color_just_attenuation, cornell_box, cornell_smoke, earth_scene, final_scene,
+    color_just_attenuation, cornell_box, cornell_smoke, earth_scene, final_scene,
     one_million_ants_er_spheres, random_scene, simple_light_scene, two_perlin_spheres, two_spheres,
-    write_color, Image,
+    Image, Integrator,
 };
 use rtlib::vec3::Color;
 
@@ -43,6 +57,40 @@ fn main() {
         stop: f32,
         texture: Option<Image>,
         interior_light: Color,
+        integrator: Integrator,
+        photons: usize,
+        gather_radius: f64,
+        noise_threshold: f64,
+        min_samples: i32,
+        max_samples: i32,
+        sampler: SamplerKind,
+        seed: Option<u64>,
+        checkpoint: Option<PathBuf>,
+        resume: bool,
+        checkpoint_interval: f64,
+        tile_size: i32,
+        aovs: Vec<Aov>,
+        aov_prefix: String,
+        denoise: bool,
+        hdr: Option<PathBuf>,
+        output: Option<PathBuf>,
+        display: DisplayTransform,
+        filter: Option<Filter>,
+        spectral: bool,
+        environment: Option<PathBuf>,
+        environment_rotation: f64,
+        environment_intensity: f64,
+        sky: Option<Sky>,
+        clamp: Option<f64>,
+        median_of_means: Option<MedianOfMeans>,
+        stats_json: Option<PathBuf>,
+        time_limit: Option<f64>,
+        crop: Option<Crop>,
+        crop_full: bool,
+        serve: Option<String>,
+        local_workers: usize,
+        worker: Option<String>,
+        worker_timeout: Option<f64>,
     }
 
     let mut ri = RenderInfo {
@@ -57,6 +105,40 @@ fn main() {
         texture: None,
         // use interior lighthing by default
         interior_light: Color::new(1.0, 1.0, 1.0),
+        integrator: Integrator::Path,
+        photons: 200_000,
+        gather_radius: 1.0,
+        noise_threshold: 0.0,
+        min_samples: 16,
+        max_samples: 2000,
+        sampler: SamplerKind::Independent,
+        seed: None,
+        checkpoint: None,
+        resume: false,
+        checkpoint_interval: 60.0,
+        tile_size: 32,
+        aovs: Vec::new(),
+        aov_prefix: "aov".to_string(),
+        denoise: false,
+        hdr: None,
+        output: None,
+        display: DisplayTransform::default(),
+        filter: None,
+        spectral: false,
+        environment: None,
+        environment_rotation: 0.0,
+        environment_intensity: 1.0,
+        sky: None,
+        clamp: None,
+        median_of_means: None,
+        stats_json: None,
+        time_limit: None,
+        crop: None,
+        crop_full: false,
+        serve: None,
+        local_workers: 0,
+        worker: None,
+        worker_timeout: None,
     };
 
     let cmd = clap::Command::new("rt")
@@ -73,6 +155,20 @@ fn main() {
                 .required(false)
                 .default_value("500")
                 .validator(|s| s.parse::<i32>())
+            ).arg(
+                clap::arg!(--noise_threshold <THRESHOLD> "Turns on adaptive sampling. Pixels stop getting samples once their estimated relative error is below this (0.01 is about 1%), and the rest of the num_samples budget goes to the noisy ones. 0 turns it off. Default: 0")
+                .required(false)
+                .default_value("0")
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--min_samples <SAMPLES> "With adaptive sampling, the samples every pixel gets before checking if it's done. Default: 16")
+                .required(false)
+                .default_value("16")
+                .validator(|s| s.parse::<i32>())
+            ).arg(
+                clap::arg!(--max_samples <SAMPLES> "With adaptive sampling, the most samples a single pixel can get. Default: 4 times num_samples")
+                .required(false)
+                .validator(|s| s.parse::<i32>())
             ).arg(
                 clap::arg!(-v --vfov <FOV> "Vertical FOV. Defaults: 8.0")
                 .required(false)
@@ -106,6 +202,219 @@ fn main() {
                 clap::arg!(--explicit_lighting <TRUEorFALSE> "If you scene explicitly uses lights, set this to true. If you want to see all objects without worrying about lighting, set to false.")
                 .required(false)
                 .default_value("false")
+            ).arg(
+                clap::arg!(--integrator <INTEGRATOR> "How light is gathered. 'path' only finds lights by bouncing, 'direct' also sends shadow rays at the lights from diffuse surfaces, 'mis' does both and weighs them, 'bdpt' also traces paths out from the lights and joins them to the camera's, 'photon' is 'direct' with caustics from a photon map. Default: path")
+                .required(false)
+                .default_value("path")
+                .validator(|s| s.parse::<Integrator>())
+            ).arg(
+                clap::arg!(--photons <COUNT> "Number of photons to send out from the lights for the 'photon' integrator. Default: 200000")
+                .required(false)
+                .default_value("200000")
+                .validator(|s| s.parse::<usize>())
+            ).arg(
+                clap::arg!(--gather_radius <RADIUS> "How far around a point photons are gathered from, in scene units, for the 'photon' integrator. Smaller is sharper but noisier. Default: 1.0")
+                .required(false)
+                .default_value("1.0")
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--sampler <SAMPLER> "Where the random numbers for each sample come from. 'independent' is plain random numbers, 'stratified', 'halton' and 'sobol' spread the samples of each pixel out more evenly, which is less noisy for the same number of samples. Default: independent")
+                .required(false)
+                .default_value("independent")
+                .validator(|s| s.parse::<SamplerKind>())
+            ).arg(
+                clap::arg!(--seed <SEED> "Seed for everything random: the scene, the BVH and the samples. The same seed and arguments always give the same image, whatever the number of threads. Without one a seed is picked and printed, so the render can be repeated.")
+                .required(false)
+                .validator(|s| s.parse::<u64>())
+            ).arg(
+                clap::arg!(--checkpoint <FILE> "Every so often save the samples taken so far to this file, so a render that gets stopped can be carried on with --resume.")
+                .required(false)
+                .allow_invalid_utf8(true)
+            ).arg(
+                clap::arg!(--resume "Carry on from the --checkpoint file instead of starting over. Use the same arguments as before. With a larger --num_samples it adds samples to a render that already finished.")
+                .required(false)
+                .requires("checkpoint")
+            ).arg(
+                clap::arg!(--checkpoint_interval <SECONDS> "How often to save the checkpoint. Default: 60")
+                .required(false)
+                .default_value("60")
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--time_limit <SECONDS> "Stop rendering after this long and write out the image as it is, with however many samples each pixel got. The checkpoint is saved too, so it can be resumed.")
+                .required(false)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(seconds) if seconds > 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a number of seconds above 0", s)),
+                })
+            ).arg(
+                clap::arg!(--tile_size <PIXELS> "The image is rendered in square tiles this many pixels across. Default: 32")
+                .required(false)
+                .default_value("32")
+                .validator(|s| s.parse::<i32>())
+            ).arg(
+                clap::arg!(--crop <WINDOW> "Only render part of the image, with the camera still framing the whole of it, to look at one spot without waiting for the rest. x0,y0,x1,y1 from the top left corner, either in pixels like 100,50,300,200 or as fractions of the width and height like 0.25,0.1,0.75,0.6. Just that part is written out.")
+                .required(false)
+                .validator(|s| s.parse::<Crop>())
+            ).arg(
+                clap::arg!(--crop_full "Write out the whole image with only the --crop filled in, and black everywhere else, instead of just the crop.")
+                .required(false)
+                .requires("crop")
+            ).arg(
+                clap::arg!(--aov <LIST> "Extra passes to write out, each to an image of its own, from what the camera rays hit first. A comma separated list of depth, normal, albedo, position, uv and front_face, or 'all'.")
+                .required(false)
+                .validator(Aov::parse_list)
+            ).arg(
+                clap::arg!(--aov_prefix <PREFIX> "Where the --aov images go. The depth pass is written to PREFIX_depth.ppm and so on. Default: aov")
+                .required(false)
+                .default_value("aov")
+            ).arg(
+                clap::arg!(--denoise "Smooth out the noise before writing the image, keeping to the edges of objects and textures. Meant for quick previews with few samples.")
+                .required(false)
+            ).arg(
+                clap::arg!(--hdr <FILE> "Also save the image as linear floating point, without clamping the highlights, for grading and tone mapping later. A .pfm (Portable FloatMap) or .hdr (Radiance RGBE) file.")
+                .required(false)
+                .allow_invalid_utf8(true)
+            ).arg(
+                clap::arg!(-o --output <FILE> "Save the image to this file instead of printing it as text (P3) on stdout. The extension picks the format: .ppm (binary), .bmp, .png, or .pfm and .hdr for floating point.")
+                .required(false)
+                .validator(|s| ImageFormat::from_path(Path::new(s)))
+            ).arg(
+                clap::arg!(--tonemap <OPERATOR> "How light brighter than white is brought into range for the 8 bit image. 'linear' clips it, 'reinhard' and 'reinhard_extended' roll it off, 'hable' is a filmic curve and 'aces' the fitted ACES one. Default: linear")
+                .required(false)
+                .default_value("linear")
+                .validator(|s| s.parse::<ToneMapperType>())
+            ).arg(
+                clap::arg!(--exposure <STOPS> "Brighten (or darken, if negative) the image before tone mapping, each stop doubling the light. Default: 0")
+                .required(false)
+                .default_value("0")
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--white_point <LEVEL> "The brightness that comes out white with --tonemap reinhard_extended. Default: 4")
+                .required(false)
+                .default_value("4")
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--transfer <FUNCTION> "How the tone mapped values are encoded: 'gamma2' (a square root, as it's always been), 'srgb' for the real sRGB curve, or 'linear'. Default: gamma2")
+                .required(false)
+                .default_value("gamma2")
+                .validator(|s| s.parse::<Transfer>())
+            ).arg(
+                clap::arg!(--filter <FILTER> "Spread each sample over the pixels around it with a reconstruction filter, instead of just averaging the samples in each pixel: box, tent, gaussian, mitchell or lanczos. Helps with thin things that alias, like million_spheres.")
+                .required(false)
+                .conflicts_with("denoise")
+                .validator(|s| s.parse::<FilterKind>())
+            ).arg(
+                clap::arg!(--filter_radius <PIXELS> "How far the --filter reaches. Default: 0.5 for box, 1 for tent, 1.5 for gaussian, 2 for mitchell and 3 for lanczos")
+                .required(false)
+                .requires("filter")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(radius) if radius > 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a radius bigger than 0", s)),
+                })
+            ).arg(
+                clap::arg!(--spectral "Follow a single wavelength of light down each path instead of red, green and blue together, so glass splits white light into colors. Noisier, and works with the path, direct and mis integrators.")
+                .required(false)
+            ).arg(
+                clap::arg!(--environment <FILE> "Light the scene with an equirectangular (latitude/longitude) panorama, seen by every ray that misses the scene, instead of the interior light. A .hdr or .pfm keeps bright suns bright, other images are loaded like textures. The direct and mis integrators sample it like a light.")
+                .required(false)
+                .allow_invalid_utf8(true)
+            ).arg(
+                clap::arg!(--environment_rotation <DEGREES> "Turn the --environment around the vertical axis. Default: 0")
+                .required(false)
+                .requires("environment")
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--environment_intensity <SCALE> "Multiply the light from the --environment. Default: 1")
+                .required(false)
+                .requires("environment")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(scale) if scale >= 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a scale of 0 or more", s)),
+                })
+            ).arg(
+                clap::arg!(--sky "Light the scene with a clear daylight sky and the sun (the Preetham model) instead of the interior light, with flat ground below the horizon. The direct and mis integrators sample the sun like a light.")
+                .required(false)
+                .conflicts_with("environment")
+            ).arg(
+                clap::arg!(--sun_elevation <DEGREES> "How high the sun is for --sky, from 0 on the horizon to 90 straight up. Default: 45")
+                .required(false)
+                .requires("sky")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(degrees) if (0.0..=90.0).contains(&degrees) => Ok(()),
+                    _ => Err(format!("'{}' isn't an elevation from 0 to 90", s)),
+                })
+            ).arg(
+                clap::arg!(--sun_azimuth <DEGREES> "Where around the horizon the sun is for --sky, 0 towards +z and 90 towards +x. Default: 0")
+                .required(false)
+                .requires("sky")
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--turbidity <TURBIDITY> "How hazy the --sky is, from 2 for a very clear day to 10. Default: 3")
+                .required(false)
+                .requires("sky")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(t) if (1.7..=10.0).contains(&t) => Ok(()),
+                    _ => Err(format!("'{}' isn't a turbidity from 1.7 to 10", s)),
+                })
+            ).arg(
+                clap::arg!(--ground_albedo <ALBEDO> "How much light the ground under the --sky reflects, from 0 to 1. Default: 0.3")
+                .required(false)
+                .requires("sky")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(albedo) if (0.0..=1.0).contains(&albedo) => Ok(()),
+                    _ => Err(format!("'{}' isn't an albedo from 0 to 1", s)),
+                })
+            ).arg(
+                clap::arg!(--sun_radius <DEGREES> "How big the sun of the --sky looks, from its middle to the edge. A bigger sun is just as bright overall, but softer and less noisy with the path integrator. Default: 0.2665, the real one")
+                .required(false)
+                .requires("sky")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(degrees) if degrees > 0.0 && degrees < 90.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a radius between 0 and 90", s)),
+                })
+            ).arg(
+                clap::arg!(--clamp <LUMINANCE> "Scale down any sample brighter than this, keeping its color, so the odd very bright path (a firefly) can't leave a speck in the image. Makes bright highlights a bit darker. Try 10 to 100.")
+                .required(false)
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(max) if max > 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a luminance above 0", s)),
+                })
+            ).arg(
+                clap::arg!(--median_of_means <GROUPS> "Deal each pixel's samples out into this many groups and use the median of their averages instead of the plain average, which ignores the odd firefly. Needs a few samples per group, an odd number like 5 works best.")
+                .required(false)
+                .conflicts_with_all(&["filter", "denoise"])
+                .validator(|s| match s.parse::<usize>() {
+                    Ok(groups) if groups >= 2 => Ok(()),
+                    _ => Err(format!("'{}' isn't 2 or more groups", s)),
+                })
+            ).arg(
+                clap::arg!(--stats_json <FILE> "Also write the render statistics printed at the end (ray counts, rays per second and path lengths) to this file as JSON.")
+                .required(false)
+                .conflicts_with("serve")
+                .allow_invalid_utf8(true)
+            ).arg(
+                clap::arg!(--serve <ADDRESS> "Don't render here, hand the tiles out to worker processes that connect to this address (like 0.0.0.0:7878) and put together what they send back. The image is the same as rendering it all in one process. Start the workers with --worker and the same options and scene, or with --local_workers.")
+                .required(false)
+            ).arg(
+                clap::arg!(--local_workers <N> "Start this many --worker processes on this machine for --serve.")
+                .required(false)
+                .requires("serve")
+                .validator(|s| s.parse::<usize>())
+            ).arg(
+                clap::arg!(--worker_timeout <SECONDS> "Give up on a worker that's taken this long to send back a tile and hand it to another one. Workers that exit or lose their connection are noticed straight away. Default: wait as long as it takes")
+                .required(false)
+                .requires("serve")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(seconds) if seconds > 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a number of seconds above 0", s)),
+                })
+            ).arg(
+                clap::arg!(--worker <ADDRESS> "Render tiles for the --serve process at this address instead of an image of its own. It has to be given the same options and scene, the seed comes from the coordinator.")
+                .required(false)
+                .conflicts_with("serve")
             )
         .subcommand_required(true)
         .subcommand(
@@ -147,6 +456,67 @@ fn main() {
             Command::new("million_spheres")
             .about("Generate 1_000_000 spheres and render them. Mostly a benchmark.")
             );
+    // the same scenes again, for animating
+    let scenes: Vec<Command> = cmd.get_subcommands().cloned().collect();
+    let cmd = cmd.subcommand(
+        Command::new("animate")
+            .about("Render numbered frames of one of the scenes over a stretch of time, each with the shutter open for just that frame, with the camera following --keyframes or going round in a --turntable. Like 'rt --num_samples 100 animate --turntable 4 cornell_box'.")
+            .arg(
+                clap::arg!(--keyframes <FILE> "Move the camera along the keyframes in this file. One a line, as 'time look_from_x y z look_at_x y z', and then maybe vfov, focus distance and aperture, which are otherwise the scene's. Lines starting with # are comments.")
+                .required(false)
+                .allow_invalid_utf8(true)
+            ).arg(
+                clap::arg!(--turntable <SECONDS> "Take the camera once around what it's looking at, in this many seconds from time 0, keeping the same height and distance.")
+                .required(false)
+                .conflicts_with("keyframes")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(seconds) if seconds > 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a number of seconds above 0", s)),
+                })
+            ).arg(
+                clap::arg!(--interpolation <INTERPOLATION> "How the camera gets from one keyframe to the next. 'linear' goes straight there, 'catmull_rom' is a smooth curve through all of them. Default: catmull_rom")
+                .required(false)
+                .default_value("catmull_rom")
+                .validator(|s| s.parse::<Interpolation>())
+            ).arg(
+                clap::arg!(--start <TIME> "Time of the first frame, in seconds. Default: the first keyframe, or 0")
+                .required(false)
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--end <TIME> "Time the animation ends, in seconds. The last frame is the one before it, so a turntable doesn't repeat its first frame. Default: the last keyframe, the end of the turntable, or 1")
+                .required(false)
+                .allow_hyphen_values(true)
+                .validator(|s| s.parse::<f64>())
+            ).arg(
+                clap::arg!(--fps <FPS> "Frames a second. Default: 24")
+                .required(false)
+                .default_value("24")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(fps) if fps > 0.0 => Ok(()),
+                    _ => Err(format!("'{}' isn't a number of frames a second above 0", s)),
+                })
+            ).arg(
+                clap::arg!(--shutter <FRACTION> "How much of each frame the shutter's open for, which is how much anything moving is blurred. 0 is a still, 1 is the whole frame. Default: 0.5")
+                .required(false)
+                .default_value("0.5")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(()),
+                    _ => Err(format!("'{}' isn't a fraction from 0 to 1", s)),
+                })
+            ).arg(
+                clap::arg!(--frames <PATTERN> "Where the frames go. The #s are replaced with the frame's number, starting from 1, and the extension picks the format like --output. Default: frame_####.ppm")
+                .required(false)
+                .default_value("frame_####.ppm")
+                .validator(|s| match ImageFormat::from_path(Path::new(s)) {
+                    Ok(_) if s.contains('#') => Ok(()),
+                    Ok(_) => Err(format!("'{}' needs #s where the frame number goes", s)),
+                    Err(err) => Err(err),
+                })
+            )
+            .subcommand_required(true)
+            .subcommands(scenes),
+    );
 
     let matches = cmd.get_matches();
 
@@ -161,6 +531,13 @@ fn main() {
     ri.depth = matches
         .value_of_t("max_depth")
         .expect("Maximum depth is required.");
+    ri.noise_threshold = matches
+        .value_of_t("noise_threshold")
+        .expect("Noise threshold is required.");
+    ri.min_samples = matches
+        .value_of_t("min_samples")
+        .expect("Minimum samples is required.");
+    ri.max_samples = matches.value_of_t("max_samples").unwrap_or(4 * ri.samples);
     ri.vfov = matches
         .value_of_t("vfov")
         .expect("Vertical FOV is required.");
@@ -179,6 +556,107 @@ fn main() {
         ri.texture = Some(Image::new(&config_path.display()));
     }
 
+    ri.integrator = matches
+        .value_of_t("integrator")
+        .expect("Integrator required.");
+    ri.spectral = matches.is_present("spectral");
+    if ri.spectral
+        && matches!(
+            ri.integrator,
+            Integrator::Bidirectional | Integrator::PhotonMapping
+        )
+    {
+        // light paths and photons start at the lights, without a wavelength
+        eprintln!(
+            "--spectral doesn't work with the {} integrator, use path, direct or mis",
+            ri.integrator
+        );
+        std::process::exit(1);
+    }
+    ri.environment = matches.value_of_os("environment").map(PathBuf::from);
+    ri.environment_rotation = matches.value_of_t("environment_rotation").unwrap_or(0.0);
+    ri.environment_intensity = matches.value_of_t("environment_intensity").unwrap_or(1.0);
+    if matches.is_present("sky") {
+        let sun = sun_direction(
+            matches.value_of_t("sun_elevation").unwrap_or(45.0),
+            matches.value_of_t("sun_azimuth").unwrap_or(0.0),
+        );
+        ri.sky = Some(
+            Sky::new(
+                &sun,
+                matches.value_of_t("turbidity").unwrap_or(3.0),
+                matches.value_of_t("ground_albedo").unwrap_or(0.3),
+            )
+            .with_sun_radius(matches.value_of_t("sun_radius").unwrap_or(SUN_RADIUS)),
+        );
+    }
+    if (ri.environment.is_some() || ri.sky.is_some()) && ri.integrator == Integrator::Bidirectional
+    {
+        // the light paths would have to start out in the environment too
+        eprintln!("--environment and --sky don't work with the bdpt integrator");
+        std::process::exit(1);
+    }
+    ri.photons = matches
+        .value_of_t("photons")
+        .expect("Photon count required.");
+    ri.gather_radius = matches
+        .value_of_t("gather_radius")
+        .expect("Gather radius required.");
+    ri.sampler = matches.value_of_t("sampler").expect("Sampler required.");
+    ri.seed = matches.value_of_t("seed").ok();
+    ri.checkpoint = matches.value_of_os("checkpoint").map(PathBuf::from);
+    ri.resume = matches.is_present("resume");
+    ri.checkpoint_interval = matches
+        .value_of_t("checkpoint_interval")
+        .expect("Checkpoint interval required.");
+    ri.tile_size = matches
+        .value_of_t("tile_size")
+        .expect("Tile size required.");
+    ri.time_limit = matches.value_of_t("time_limit").ok();
+    ri.crop = matches.value_of_t("crop").ok();
+    ri.crop_full = matches.is_present("crop_full");
+    ri.serve = matches.value_of("serve").map(str::to_string);
+    ri.local_workers = matches.value_of_t("local_workers").unwrap_or(0);
+    ri.worker = matches.value_of("worker").map(str::to_string);
+    ri.worker_timeout = matches.value_of_t("worker_timeout").ok();
+    if let Some(list) = matches.value_of("aov") {
+        ri.aovs = Aov::parse_list(list).expect("AOV list already checked.");
+    }
+    ri.denoise = matches.is_present("denoise");
+    ri.hdr = matches.value_of_os("hdr").map(PathBuf::from);
+    ri.output = matches.value_of("output").map(PathBuf::from);
+    ri.display = DisplayTransform::new(
+        matches
+            .value_of_t::<ToneMapperType>("tonemap")
+            .expect("Tone mapper required.")
+            .white_point(
+                matches
+                    .value_of_t("white_point")
+                    .expect("White point required."),
+            ),
+        matches.value_of_t("exposure").expect("Exposure required."),
+        matches.value_of_t("transfer").expect("Transfer required."),
+    );
+    if let Some(kind) = matches.value_of("filter") {
+        let kind: FilterKind = kind.parse().expect("Filter already checked.");
+        ri.filter = Some(Filter::new(
+            kind,
+            matches
+                .value_of_t("filter_radius")
+                .unwrap_or_else(|_| kind.default_radius()),
+        ));
+    }
+    ri.clamp = matches.value_of_t("clamp").ok();
+    ri.stats_json = matches.value_of_os("stats_json").map(PathBuf::from);
+    ri.median_of_means = matches
+        .value_of_t("median_of_means")
+        .ok()
+        .map(MedianOfMeans::new);
+    ri.aov_prefix = matches
+        .value_of("aov_prefix")
+        .expect("AOV prefix required.")
+        .to_string();
+
     let el: bool = matches
         .value_of_t("explicit_lighting")
         .expect("Lighting type required.");
@@ -200,20 +678,162 @@ fn main() {
             stop: ri.stop,
             texture: ri.texture,
             interior_light: ri.interior_light,
+            integrator: ri.integrator,
+            photons: ri.photons,
+            gather_radius: ri.gather_radius,
+            noise_threshold: ri.noise_threshold,
+            min_samples: ri.min_samples,
+            max_samples: ri.max_samples,
+            sampler: ri.sampler,
+            seed: ri.seed,
+            checkpoint: ri.checkpoint,
+            resume: ri.resume,
+            checkpoint_interval: ri.checkpoint_interval,
+            tile_size: ri.tile_size,
+            aovs: ri.aovs,
+            aov_prefix: ri.aov_prefix,
+            denoise: ri.denoise,
+            hdr: ri.hdr,
+            output: ri.output,
+            display: ri.display,
+            filter: ri.filter,
+            spectral: ri.spectral,
+            environment: ri.environment,
+            environment_rotation: ri.environment_rotation,
+            environment_intensity: ri.environment_intensity,
+            sky: ri.sky,
+            clamp: ri.clamp,
+            median_of_means: ri.median_of_means,
+            stats_json: ri.stats_json,
+            time_limit: ri.time_limit,
+            crop: ri.crop,
+            crop_full: ri.crop_full,
+            serve: ri.serve,
+            local_workers: ri.local_workers,
+            worker: ri.worker,
+            worker_timeout: ri.worker_timeout,
         };
     }
     // make read only
     let ri = ri;
 
+    // rt animate <scene> is the scene's frames, otherwise it's one image
+    let animate = match matches.subcommand() {
+        Some(("animate", animate)) => Some(animate),
+        _ => None,
+    };
+    let scene_matches = animate.unwrap_or(&matches);
+    if animate.is_some()
+        && (ri.output.is_some()
+            || ri.hdr.is_some()
+            || !ri.aovs.is_empty()
+            || ri.checkpoint.is_some()
+            || ri.serve.is_some()
+            || ri.worker.is_some())
+    {
+        eprintln!("rt animate writes the frames to --frames, it doesn't work with --output, --hdr, --aov, --checkpoint, --serve or --worker");
+        std::process::exit(1);
+    }
+
     //eprintln!("Render info after arg parsing. {:?}", ri);
 
     // For error handling
     let stdout = std::io::stdout();
     let mut handle = stdout.lock();
-    let mut rng = rand::thread_rng();
+    // a worker renders whatever its coordinator is, from the same seed
+    let worker = ri.worker.as_ref().map(|address| {
+        Worker::connect(address).unwrap_or_else(|err| {
+            eprintln!(
+                "Couldn't connect to the coordinator at {}: {}",
+                address, err
+            );
+            std::process::exit(1);
+        })
+    });
+    // a resumed render has to carry on with the seed it was started with
+    let resumed = ri
+        .checkpoint
+        .as_ref()
+        .filter(|_| ri.resume && worker.is_none())
+        .map(|path| {
+            Checkpoint::load(path).unwrap_or_else(|err| {
+                eprintln!("Couldn't load checkpoint {}: {}", path.display(), err);
+                std::process::exit(1);
+            })
+        });
+    if let (Some(resumed), Some(seed)) = (&resumed, ri.seed) {
+        if resumed.seed != seed {
+            eprintln!(
+                "The checkpoint was started with --seed {}, not {}.",
+                resumed.seed, seed
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // everything random comes from this, so the seed decides the whole render
+    let seed = worker
+        .as_ref()
+        .map(Worker::seed)
+        .or(resumed.as_ref().map(|resumed| resumed.seed))
+        .or(ri.seed)
+        .unwrap_or_else(|| rand::thread_rng().gen());
+    eprintln!("Seed: {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    // The workers are started now, so they build their scenes while we
+    // build ours. They're given the same arguments as us, apart from the
+    // ones for serving.
+    let coordinator = ri.serve.as_ref().map(|address| {
+        let mut coordinator = Coordinator::bind(address)
+            .unwrap_or_else(|err| {
+                eprintln!("Couldn't listen for workers on {}: {}", address, err);
+                std::process::exit(1);
+            })
+            .with_log(|message| eprintln!("\n{}", message));
+        if let Some(seconds) = ri.worker_timeout {
+            coordinator = coordinator.with_timeout(Duration::from_secs_f64(seconds));
+        }
+        coordinator
+    });
+    let mut local_workers = Vec::new();
+    if let Some(coordinator) = &coordinator {
+        let mut address = coordinator.local_addr().expect("Listening already.");
+        eprintln!("Waiting for workers on {}", address);
+        if address.ip().is_unspecified() {
+            address.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+        }
+        let mut args = Vec::new();
+        let mut skip = false;
+        for arg in std::env::args_os().skip(1) {
+            let flag = arg.to_string_lossy();
+            let flag = flag.split('=').next().unwrap_or_default();
+            if skip {
+                skip = false;
+            } else if ["--serve", "--local_workers", "--worker_timeout"].contains(&flag) {
+                // the value's in the next argument, unless it was --serve=...
+                skip = !arg.to_string_lossy().contains('=');
+            } else {
+                args.push(arg);
+            }
+        }
+        for _ in 0..ri.local_workers {
+            let child = std::process::Command::new(std::env::current_exe().expect("Running."))
+                .arg("--worker")
+                .arg(address.to_string())
+                .args(&args)
+                .stdout(std::process::Stdio::null())
+                .spawn()
+                .unwrap_or_else(|err| {
+                    eprintln!("Couldn't start a worker: {}", err);
+                    std::process::exit(1);
+                });
+            local_workers.push(child);
+        }
+    }
 
     // we'll use randopm_scene as the default
-    let mut world: Arc<HitList>;
+    let world: HitList;
 
     // if you make it 2000x1000 that's 100x, and then 100 more samples of each,
     // and then test against all the objects again for differaction. And then
@@ -234,8 +854,6 @@ fn main() {
     let IMAGE_WIDTH: i32 = ri.width; // image width
     #[allow(non_snake_case)]
     let mut IMAGE_HEIGHT: i32 = (IMAGE_WIDTH as f64 / ASPECT_RATIO) as i32;
-    #[allow(non_snake_case)]
-    let mut NUM_PIXELS: i32 = IMAGE_WIDTH * IMAGE_HEIGHT;
     let mut look_from = vect!(25.0, 2.5, 5.0);
     let mut look_at = rtmacros::vect!(3.0, 0.75, 0.75);
     let vup = rtmacros::vect!(0.0, 1.0, 0.0);
@@ -258,10 +876,21 @@ fn main() {
         stop_time_in_sec,
     );
 
+    // what a checkpoint has to agree on for its samples to belong to this render
+    let scene = match scene_matches.subcommand() {
+        Some(("random_scene", matches)) => format!(
+            "random_scene checkerboard {} movingspheres {}",
+            matches.is_present("checkerboard"),
+            matches.is_present("movingspheres")
+        ),
+        Some((name, _)) => name.to_string(),
+        None => unreachable!("clap should ensure we don't get here"),
+    };
+
     // now we handle which scene we want to render. That is really how we make the world.
     // may need matches later for other subcommands
     #[allow(unused_variables)]
-    let matches = match matches.subcommand() {
+    let matches = match scene_matches.subcommand() {
         Some(("random_scene", matches)) => {
             // this is already the default, so we only have to modify if the
             // checkerboard option is selected
@@ -273,7 +902,7 @@ fn main() {
             if matches.is_present("movingspheres") {
                 movingspheres = true;
             }
-            world = Arc::new(random_scene(&mut rng, checkerboard, movingspheres));
+            world = random_scene(&mut rng, checkerboard, movingspheres);
             matches
         }
         Some(("two_spheres", matches)) => {
@@ -293,7 +922,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(two_spheres());
+            world = two_spheres();
             matches
         }
         Some(("two_perlin_spheres", matches)) => {
@@ -313,7 +942,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(two_perlin_spheres());
+            world = two_perlin_spheres(&mut rng);
             matches
         }
         Some(("earth_scene", matches)) => {
@@ -332,7 +961,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(earth_scene());
+            world = earth_scene();
             matches
         }
         Some(("simple_light_scene", matches)) => {
@@ -353,7 +982,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(simple_light_scene());
+            world = simple_light_scene(&mut rng);
             matches
         }
         Some(("cornell_box", matches)) => {
@@ -373,7 +1002,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(cornell_box());
+            world = cornell_box();
             matches
         }
         Some(("cornell_smoke", matches)) => {
@@ -393,7 +1022,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(cornell_smoke());
+            world = cornell_smoke();
             matches
         }
         Some(("final_scene", matches)) => {
@@ -402,7 +1031,6 @@ fn main() {
             dist_to_focus = 10.0;
             APERTURE = 0.0;
             IMAGE_HEIGHT = IMAGE_WIDTH;
-            NUM_PIXELS = IMAGE_WIDTH * IMAGE_HEIGHT;
             interior_light = Color::new(0.0, 0.0, 0.0);
             camera = Camera::new(
                 look_from,
@@ -415,7 +1043,7 @@ fn main() {
                 0.0,
                 1.0,
             );
-            world = Arc::new(final_scene());
+            world = final_scene(&mut rng);
             matches
         }
         Some(("million_spheres", matches)) => {
@@ -435,7 +1063,7 @@ fn main() {
                 0.0,
                 0.0,
             );
-            world = Arc::new(one_million_ants_er_spheres(&mut rng));
+            world = one_million_ants_er_spheres(&mut rng);
             matches
         }
         _ => unreachable!("clap should ensure we don't get here"),
@@ -446,78 +1074,398 @@ fn main() {
     let camera = camera;
     //eprintln!("Camera before start: {:?}", &camera);
     let interior_light = interior_light;
+    // an environment map or the sky takes over from the interior light,
+    // whatever the scene
+    let background = match (&ri.environment, &ri.sky) {
+        (Some(path), _) => {
+            let environment = Environment::load(path).unwrap_or_else(|err| {
+                eprintln!("Couldn't load environment {}: {}", path.display(), err);
+                std::process::exit(1);
+            });
+            Background::Environment(Arc::new(
+                environment
+                    .with_rotation(ri.environment_rotation)
+                    .with_intensity(ri.environment_intensity),
+            ))
+        }
+        (None, Some(sky)) => Background::Sky(Arc::new(sky.clone())),
+        (None, None) => Background::Constant(interior_light),
+    };
+    let mut render_settings = RenderSettings::new(IMAGE_WIDTH, IMAGE_HEIGHT);
+    render_settings.samples = SAMPLES_PER_PIXEL;
+    render_settings.max_depth = MAX_DEPTH;
+    render_settings.integrator = ri.integrator;
+    render_settings.sampler = ri.sampler;
+    // every pixel gets a sampler of its own, seeded from this
+    render_settings.seed = rng.gen();
+    render_settings.noise_threshold = ri.noise_threshold;
+    render_settings.min_samples = ri.min_samples;
+    render_settings.max_samples = ri.max_samples;
+    render_settings.tile_size = ri.tile_size;
+    render_settings.filter = ri.filter;
+    render_settings.spectral = ri.spectral;
+    render_settings.clamp = ri.clamp;
+    render_settings.median_of_means = ri.median_of_means;
+    render_settings.photons = ri.photons;
+    render_settings.gather_radius = ri.gather_radius;
+    render_settings.background = background;
+    render_settings.crop = ri.crop;
+    if let Some(crop) = ri.crop {
+        if crop.window(IMAGE_WIDTH, IMAGE_HEIGHT).is_none() {
+            eprintln!(
+                "The crop {} is outside the {}x{} image.",
+                crop, IMAGE_WIDTH, IMAGE_HEIGHT
+            );
+            std::process::exit(1);
+        }
+    }
 
-    let mut bvh = Bvh::new();
-    bvh.add_hitlist(&mut world, start_time_in_sec, stop_time_in_sec);
-    let world = Arc::new(bvh.build());
-
-    // Render
-    println!("P3\n{} {}\n255", IMAGE_WIDTH, IMAGE_HEIGHT);
-
-    let n_finished = Arc::new(std::sync::atomic::AtomicI32::new(0));
+    // Progress goes to stderr. The render is timed from here, for the
+    // summary at the end and --time_limit, and counted from here too.
+    let mut control = RenderControl::new().with_progress(|progress| {
+        eprint!("\r{}", progress);
+        stderr().flush().unwrap();
+    });
+    if let Some(seconds) = ri.time_limit {
+        control = control.with_time_budget(Duration::from_secs_f64(seconds));
+    }
+    let stats_before = RenderStats::collect();
 
-    // we use the riter because origin is at the lower left
-    // to maintain a right handed coordinate system
-    let pixels = (0..IMAGE_HEIGHT)
-        .into_par_iter()
-        .rev()
-        .map(move |j| {
-            let world = world.clone();
-            let n_finished = n_finished.clone();
-            (0..IMAGE_WIDTH).into_par_iter().map(move |i| {
-                let mut rng = rand::thread_rng();
-                let mut pixel_color = Color::default();
-                for _ in 0..SAMPLES_PER_PIXEL {
-                    let u = (i as f64 + rng.gen::<f64>()) / (IMAGE_WIDTH - 1) as f64;
-                    let v = (j as f64 + rng.gen::<f64>()) / (IMAGE_HEIGHT - 1) as f64;
-                    let r = camera.get_ray(u, v);
-                    pixel_color += color(&r, world.as_ref(), MAX_DEPTH, &interior_light);
-                }
+    // Each frame is a render of its own, with the shutter open from when the
+    // frame starts and the camera where it is halfway through that.
+    if let Some(animate) = animate {
+        let interpolation = animate
+            .value_of_t("interpolation")
+            .expect("Interpolation required.");
+        let (path, start, end) = if let Some(file) = animate.value_of_os("keyframes") {
+            let file = Path::new(file);
+            let path = std::fs::read_to_string(file)
+                .map_err(|err| err.to_string())
+                .and_then(|text| CameraPath::parse(&text, &camera, interpolation))
+                .unwrap_or_else(|err| {
+                    eprintln!("Couldn't load keyframes {}: {}", file.display(), err);
+                    std::process::exit(1);
+                });
+            let (start, end) = (path.start(), path.end());
+            (Some(path), start, end)
+        } else if let Ok(seconds) = animate.value_of_t::<f64>("turntable") {
+            (
+                Some(CameraPath::turntable(&camera, 0.0, seconds)),
+                0.0,
+                seconds,
+            )
+        } else {
+            (None, 0.0, 1.0)
+        };
+        let start = animate.value_of_t("start").unwrap_or(start);
+        let end = animate.value_of_t("end").unwrap_or(end);
+        if end < start {
+            eprintln!(
+                "The animation ends at {}, before it starts at {}.",
+                end, start
+            );
+            std::process::exit(1);
+        }
+        let fps: f64 = animate.value_of_t("fps").expect("FPS required.");
+        let shutter = animate.value_of_t("shutter").expect("Shutter required.");
+        let pattern = animate.value_of("frames").expect("Frames required.");
+        let frames = frame_times(start, end, fps, shutter);
+        let mut render_time = 0.0;
+        let mut render_stats = RenderStats::default();
+        for (n, &(open, close)) in frames.iter().enumerate() {
+            let middle = (open + close) / 2.0;
+            let keyframe = match &path {
+                Some(path) => path.at(middle),
+                None => camera.keyframe(middle),
+            };
+            let mut settings = render_settings.clone();
+            // a frame's noise isn't the same as the last one's
+            settings.seed = rng.gen();
+            eprintln!("Frame {}/{} at {:.3}s", n + 1, frames.len(), open);
+            let renderer = Renderer::new(
+                world.clone(),
+                camera.moved_to(&keyframe, open, close),
+                settings,
+            );
+            let mut state = renderer.checkpoint(seed, String::new());
+            // each frame's ETA is for that frame, --time_limit is for all of them
+            control.restart_progress();
+            let frame_before = RenderStats::collect();
+            renderer.render_into(&mut state, &control, &mut |_| {});
+            // the denoiser's extra pass and saving the frame don't count
+            render_time += control.progress_elapsed().as_secs_f64();
+            render_stats.add(&RenderStats::collect().since(&frame_before));
+            let film = if ri.denoise {
+                let aov_pixels = renderer.aov_pixels(SAMPLES_PER_PIXEL.clamp(1, 16));
+                Framebuffer::from_pixels(
+                    IMAGE_WIDTH,
+                    IMAGE_HEIGHT,
+                    denoise(IMAGE_WIDTH, &state.pixels, &aov_pixels),
+                )
+            } else {
+                renderer.image(&state)
+            };
+            let film = if ri.crop.is_some() && !ri.crop_full {
+                film.crop(&renderer.window())
+            } else {
+                film
+            };
+            let file = frame_path(pattern, n + 1);
+            save_image(&file, &film, &ri.display).unwrap_or_else(|err| {
+                panic!(
+                    "Oops, error {} saving frame {} to {}",
+                    err,
+                    n + 1,
+                    file.display()
+                )
+            });
+            eprintln!("\nWrote {}", file.display());
+            if let Some(reason) = control.stop_reason() {
+                eprintln!(
+                    "Stopped early ({}) after {:.1?}, the last frame has what was rendered so far",
+                    reason,
+                    control.elapsed()
+                );
+                break;
+            }
+        }
+        eprint!("{}", render_stats.summary(render_time));
+        if let Some(path) = &ri.stats_json {
+            std::fs::write(path, render_stats.to_json(render_time)).unwrap_or_else(|err| {
+                panic!(
+                    "Oops, error {} writing the stats to {}",
+                    err,
+                    path.display()
+                )
+            });
+            eprintln!("Wrote {}", path.display());
+        }
+        eprintln!("Done");
+        return;
+    }
 
-                let n = n_finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    // builds the BVH, and the photon map if there is one
+    let renderer = Renderer::new(world, camera, render_settings);
 
-                if n % (NUM_PIXELS / 1000) == 0 || n == NUM_PIXELS - 1 {
-                    eprint!(
-                        "\rCalculated {}/{} pixels ({:.1?}%)",
-                        n + 1,
-                        NUM_PIXELS,
-                        (n + 1) as f64 / NUM_PIXELS as f64 * 100.0,
-                    );
-                    stderr().flush().unwrap();
-                }
+    // the render as it stands, which is what gets saved to the checkpoint
+    let mut settings = format!(
+        "{} depth {} integrator {} sampler {} vfov {} aperture {} time {} {} light {} photons {} radius {}",
+        scene,
+        MAX_DEPTH,
+        ri.integrator,
+        ri.sampler,
+        vfov,
+        APERTURE,
+        start_time_in_sec,
+        stop_time_in_sec,
+        interior_light,
+        ri.photons,
+        ri.gather_radius,
+    );
+    if let Some(filter) = ri.filter {
+        settings += &format!(" filter {}", filter);
+    }
+    if ri.spectral {
+        settings += " spectral";
+    }
+    if let Some(path) = &ri.environment {
+        settings += &format!(
+            " environment {} rotation {} intensity {}",
+            path.display(),
+            ri.environment_rotation,
+            ri.environment_intensity
+        );
+    }
+    if let Some(sky) = &ri.sky {
+        settings += &format!(" {}", sky);
+    }
+    if let Some(max) = ri.clamp {
+        settings += &format!(" clamp {}", max);
+    }
+    if let Some(mom) = ri.median_of_means {
+        settings += &format!(" median_of_means {}", mom.groups);
+    }
+    if let Some(crop) = ri.crop {
+        settings += &format!(" crop {}", crop);
+    }
+    if let Some(worker) = worker {
+        let tiles = worker.run(&renderer, &settings).unwrap_or_else(|err| {
+            eprintln!("Lost the coordinator: {}", err);
+            std::process::exit(1);
+        });
+        eprintln!("Rendered {} tiles", tiles);
+        let render_stats = RenderStats::collect().since(&stats_before);
+        eprint!("{}", render_stats.summary(control.elapsed().as_secs_f64()));
+        return;
+    }
+    let mut state = renderer.checkpoint(seed, settings);
+    if let Some(resumed) = resumed {
+        if (resumed.width, resumed.height) != (state.width, state.height)
+            || resumed.settings != state.settings
+            || resumed.film.len() != state.film.len()
+            || resumed.groups.len() != state.groups.len()
+        {
+            eprintln!(
+                "The checkpoint is for a different render.\n  It has: {}x{} {}\n  This is: {}x{} {}",
+                resumed.width,
+                resumed.height,
+                resumed.settings,
+                state.width,
+                state.height,
+                state.settings
+            );
+            std::process::exit(1);
+        }
+        state.pixels = resumed.pixels;
+        state.film = resumed.film;
+        state.groups = resumed.groups;
+    }
+    let save = |state: &Checkpoint| {
+        if let Some(path) = &ri.checkpoint {
+            state.save(path).unwrap_or_else(|err| {
+                eprintln!("\nCouldn't save checkpoint {}: {}", path.display(), err)
+            });
+        }
+    };
+    let interval = std::time::Duration::from_secs_f64(ri.checkpoint_interval.max(0.0));
+    let mut last_save = std::time::Instant::now();
+    let mut saved = |state: &Checkpoint| {
+        if last_save.elapsed() >= interval {
+            save(state);
+            last_save = std::time::Instant::now();
+        }
+    };
+    match &coordinator {
+        Some(coordinator) => coordinator
+            .render_into(&renderer, &mut state, &control, &mut saved)
+            .unwrap_or_else(|err| panic!("Oops, error {} handing out tiles", err)),
+        None => renderer.render_into(&mut state, &control, &mut saved),
+    }
+    // stops listening, so workers that only turned up once it was all done
+    // aren't left waiting to be let in
+    drop(coordinator);
+    for mut child in local_workers {
+        let _ = child.wait();
+    }
+    eprintln!();
+    if let Some(reason) = control.stop_reason() {
+        eprintln!(
+            "Stopped early ({}) after {:.1?}, the image has what was rendered so far",
+            reason,
+            control.elapsed()
+        );
+    }
+    // so a finished render can be given more samples later
+    save(&state);
+    // the extra passes below aren't part of the render's time or its stats
+    let render_time = control.elapsed().as_secs_f64();
+    let render_stats = RenderStats::collect().since(&stats_before);
 
-                pixel_color
-            })
-        })
-        .flatten();
+    // the denoiser is guided by the extra passes too. With a crop, only what's
+    // in it is written out, unless --crop_full says the whole image.
+    let window = renderer.window();
+    let cropped = ri.crop.is_some() && !ri.crop_full;
+    let mut aov_pixels: Vec<AovPixel> = Vec::new();
+    if !ri.aovs.is_empty() || ri.denoise {
+        let aov_samples = SAMPLES_PER_PIXEL.clamp(1, 16);
+        aov_pixels = renderer.aov_pixels(aov_samples);
+        let (aov_width, written) = if cropped {
+            (
+                window.x1 - window.x0,
+                window
+                    .indices(IMAGE_WIDTH)
+                    .map(|index| aov_pixels[index])
+                    .collect(),
+            )
+        } else {
+            (IMAGE_WIDTH, aov_pixels.clone())
+        };
+        for aov in &ri.aovs {
+            let path = format!("{}_{}.ppm", ri.aov_prefix, aov);
+            std::fs::File::create(&path)
+                .and_then(|file| {
+                    let mut file = std::io::BufWriter::new(file);
+                    write_aov(&mut file, *aov, aov_width, &written)?;
+                    file.flush()
+                })
+                .unwrap_or_else(|err| {
+                    panic!("Oops, error {} writing the {} pass to {}", err, aov, path)
+                });
+            eprintln!("Wrote the {} pass to {}", aov, path);
+        }
+    }
 
-    let mut pixel_vec = Vec::with_capacity(NUM_PIXELS as usize);
-    pixel_vec.par_extend(pixels);
+    // the finished image, still linear and unclamped
+    let film = if ri.denoise {
+        eprintln!("Denoising");
+        Framebuffer::from_pixels(
+            IMAGE_WIDTH,
+            IMAGE_HEIGHT,
+            denoise(IMAGE_WIDTH, &state.pixels, &aov_pixels),
+        )
+    } else {
+        renderer.image(&state)
+    };
+    let film = if cropped { film.crop(&window) } else { film };
+    #[allow(non_snake_case)]
+    let NUM_PIXELS: i32 = film.width() * film.height();
+    if let Some(path) = &ri.hdr {
+        film.save(path).unwrap_or_else(|err| {
+            panic!("Oops, error {} saving the image to {}", err, path.display())
+        });
+        eprintln!("Wrote {}", path.display());
+    }
 
-    eprintln!();
+    if let Some(path) = &ri.output {
+        save_image(path, &film, &ri.display).unwrap_or_else(|err| {
+            panic!("Oops, error {} saving the image to {}", err, path.display())
+        });
+        eprintln!("Wrote {}", path.display());
+    } else {
+        writeln!(handle, "P3\n{} {}\n255", film.width(), film.height())
+            .unwrap_or_else(|err| panic!("Oops, error {} writing the image header", err));
+        for (i, &pixel_color) in film.pixels().iter().enumerate() {
+            if i as i32 % (NUM_PIXELS / 1000).max(1) == 0 || i as i32 == NUM_PIXELS - 1 {
+                eprint!(
+                    "\rWriting pixel {}/{} ({:.1?}%)",
+                    (i + 1),
+                    NUM_PIXELS,
+                    (i + 1) as f64 / NUM_PIXELS as f64 * 100.0,
+                );
+                stderr().flush().unwrap();
+            }
 
-    for (i, pixel_color) in pixel_vec.into_iter().enumerate() {
-        if i as i32 % (NUM_PIXELS / 1000) == 0 || i as i32 == NUM_PIXELS - 1 {
-            eprint!(
-                "\rWriting pixel {}/{} ({:.1?}%)",
-                (i + 1),
-                NUM_PIXELS,
-                (i + 1) as f64 / NUM_PIXELS as f64 * 100.0,
-            );
-            stderr().flush().unwrap();
+            let [r, g, b] = ri.display.to_rgb8(&pixel_color);
+            writeln!(handle, "{} {} {}", r, g, b).unwrap_or_else(|err| {
+                panic!(
+                    "Oops, error {} saving color {} for pixel {}/{}",
+                    err,
+                    pixel_color,
+                    i + 1,
+                    NUM_PIXELS
+                )
+            })
         }
-
-        write_color(&mut handle, pixel_color, SAMPLES_PER_PIXEL).unwrap_or_else(|err| {
+        eprintln!();
+    }
+    // the rays were all traced in the workers, nothing was counted here
+    if ri.serve.is_some() {
+        eprintln!(
+            "Render time: {:.2}s, the workers count the rays and print their own stats",
+            render_time
+        );
+    } else {
+        eprint!("{}", render_stats.summary(render_time));
+    }
+    if let Some(path) = &ri.stats_json {
+        std::fs::write(path, render_stats.to_json(render_time)).unwrap_or_else(|err| {
             panic!(
-                "Oops, error {} saving color {} for pixel {}/{}",
+                "Oops, error {} writing the stats to {}",
                 err,
-                pixel_color,
-                i + 1,
-                NUM_PIXELS
+                path.display()
             )
-        })
+        });
+        eprintln!("Wrote {}", path.display());
     }
-
-    eprintln!();
     eprintln!("Done");
 }
@@ -0,0 +1,32 @@
+use std::process::Command;
+
+// the denoiser works on the plain per pixel averages, so it can't be mixed
+// with a reconstruction filter and clap has to turn the pair down
+#[test]
+fn test_filter_conflicts_with_denoise() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rt"))
+        .args(["--filter", "gaussian", "--denoise", "cornell_box"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}
+
+// the coordinator doesn't trace any rays itself, so it has no stats to write
+#[test]
+fn test_stats_json_conflicts_with_serve() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rt"))
+        .args([
+            "--serve",
+            "127.0.0.1:0",
+            "--stats_json",
+            "stats.json",
+            "cornell_box",
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}
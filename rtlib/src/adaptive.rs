@@ -0,0 +1,219 @@
+use crate::vec3::Color;
+use std::io::{self, Read, Write};
+
+// Adaptive sampling. Instead of giving every pixel the same number of samples,
+// we keep a running mean and variance for each one (Welford's method, so we
+// don't have to hang on to the samples) and stop on the ones whose mean has
+// settled down. What's saved goes to the pixels that are still noisy.
+
+// the dimmest a channel is treated as when judging relative error, about one
+// step of the 8 bit output. Otherwise near black pixels would never converge.
+const DARKEST: f64 = 1.0 / 256.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PixelStats {
+    count: i32,
+    mean: Color,
+    // sum of squared differences from the mean, per channel
+    m2: Color,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, sample: &Color) {
+        self.count += 1;
+        let delta = *sample - self.mean;
+        self.mean += delta / self.count as f64;
+        let delta2 = *sample - self.mean;
+        self.m2 += delta * delta2;
+    }
+
+    pub fn count(&self) -> i32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Color {
+        self.mean
+    }
+
+    // what the samples add up to, for write_color() along with count()
+    pub fn sum(&self) -> Color {
+        self.mean * self.count as f64
+    }
+
+    // the sample variance of each channel
+    pub fn variance(&self) -> Color {
+        if self.count < 2 {
+            return Color::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        }
+        self.m2 / (self.count - 1) as f64
+    }
+
+    // The standard error of the mean over the mean itself, for whichever
+    // channel is worst. 0.01 means the pixel is likely within about 1% of
+    // where it would end up with endless samples.
+    pub fn relative_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let variance = self.variance();
+        let n = self.count as f64;
+        let error = |var: f64, mean: f64| (var / n).sqrt() / mean.abs().max(DARKEST);
+        error(variance.x, self.mean.x)
+            .max(error(variance.y, self.mean.y))
+            .max(error(variance.z, self.mean.z))
+    }
+
+    pub fn converged(&self, noise_threshold: f64) -> bool {
+        self.relative_error() <= noise_threshold
+    }
+
+    // Everything needed to carry on adding samples later, for checkpoints.
+    // Little endian, so a checkpoint can be moved between machines.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(&self.count.to_le_bytes())?;
+        for v in [self.mean, self.m2] {
+            for c in [v.x, v.y, v.z] {
+                w.write_all(&c.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut count = [0u8; 4];
+        r.read_exact(&mut count)?;
+        let mut read_color = || -> io::Result<Color> {
+            let mut c = [0.0; 3];
+            for x in c.iter_mut() {
+                let mut bytes = [0u8; 8];
+                r.read_exact(&mut bytes)?;
+                *x = f64::from_le_bytes(bytes);
+            }
+            Ok(Color::new(c[0], c[1], c[2]))
+        };
+        let mean = read_color()?;
+        let m2 = read_color()?;
+        Ok(PixelStats {
+            count: i32::from_le_bytes(count),
+            mean,
+            m2,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveSampling {
+    pub min_samples: i32,
+    pub max_samples: i32,
+    pub noise_threshold: f64,
+}
+
+impl AdaptiveSampling {
+    // how many samples a pass gives each noisy pixel at most, so we get to
+    // look again before spending too much on any of them
+    pub const BATCH: i32 = 16;
+
+    pub fn new(min_samples: i32, max_samples: i32, noise_threshold: f64) -> Self {
+        AdaptiveSampling {
+            min_samples,
+            max_samples: max_samples.max(min_samples),
+            noise_threshold,
+        }
+    }
+
+    pub fn needs_more(&self, stats: &PixelStats) -> bool {
+        stats.count() < self.min_samples
+            || (stats.count() < self.max_samples && !stats.converged(self.noise_threshold))
+    }
+
+    // How many samples each of the noisy pixels gets in the next pass, sharing
+    // out what's left of the budget between them. 0 once it's all spent.
+    pub fn batch(&self, budget_left: i64, noisy: usize) -> i32 {
+        if budget_left <= 0 || noisy == 0 {
+            return 0;
+        }
+        (budget_left / noisy as i64).clamp(1, Self::BATCH as i64) as i32
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AdaptiveSampling, PixelStats};
+    use crate::{vec3::Color, vect};
+    use rand::Rng;
+
+    #[test]
+    fn test_pixel_stats() {
+        // the running numbers have to match working it out the long way
+        let mut rng = rand::thread_rng();
+        let samples: Vec<Color> = (0..1000)
+            .map(|_| vect!(rng.gen::<f64>(), 2.0 * rng.gen::<f64>(), 0.5))
+            .collect();
+        let mut stats = PixelStats::default();
+        samples.iter().for_each(|s| stats.add(s));
+        let n = samples.len() as f64;
+        let mean = samples.iter().fold(Color::default(), |acc, s| acc + *s) / n;
+        let variance = samples
+            .iter()
+            .fold(Color::default(), |acc, s| acc + (*s - mean) * (*s - mean))
+            / (n - 1.0);
+
+        assert_eq!(stats.count(), 1000);
+        assert!((stats.mean() - mean).length() < 1e-9);
+        assert!((stats.sum() - mean * n).length() < 1e-6);
+        assert!((stats.variance() - variance).length() < 1e-9);
+        // z never changes, so it can't be the noisiest
+        let expected = (variance.x / n).sqrt() / mean.x;
+        let expected = expected.max((variance.y / n).sqrt() / mean.y);
+        assert!((stats.relative_error() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_converged() {
+        let mut stats = PixelStats::default();
+        stats.add(&vect!(0.5, 0.5, 0.5));
+        // one sample says nothing about the noise
+        assert!(!stats.converged(0.01));
+        stats.add(&vect!(0.5, 0.5, 0.5));
+        assert!(stats.converged(0.0));
+
+        let mut black = PixelStats::default();
+        black.add(&vect!(0, 0, 0));
+        black.add(&vect!(0, 0, 0));
+        assert!(black.converged(0.01));
+
+        let mut noisy = PixelStats::default();
+        noisy.add(&vect!(0, 0, 0));
+        noisy.add(&vect!(1, 1, 1));
+        assert!(!noisy.converged(0.01));
+    }
+
+    #[test]
+    fn test_adaptive_sampling() {
+        let adaptive = AdaptiveSampling::new(4, 64, 0.01);
+        let mut stats = PixelStats::default();
+        for _ in 0..3 {
+            stats.add(&vect!(0.5, 0.5, 0.5));
+        }
+        // converged already, but not at the minimum yet
+        assert!(adaptive.needs_more(&stats));
+        stats.add(&vect!(0.5, 0.5, 0.5));
+        assert!(!adaptive.needs_more(&stats));
+
+        let mut noisy = PixelStats::default();
+        for i in 0..64 {
+            noisy.add(&vect!((i % 2) as f64, 0, 0));
+        }
+        // still noisy, but out of samples
+        assert!(!adaptive.needs_more(&noisy));
+
+        assert_eq!(adaptive.batch(1000, 10), AdaptiveSampling::BATCH);
+        assert_eq!(adaptive.batch(50, 10), 5);
+        assert_eq!(adaptive.batch(5, 10), 1);
+        assert_eq!(adaptive.batch(0, 10), 0);
+        assert_eq!(adaptive.batch(100, 0), 0);
+
+        // max can't be below min
+        assert_eq!(AdaptiveSampling::new(8, 2, 0.1).max_samples, 8);
+    }
+}
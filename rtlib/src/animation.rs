@@ -0,0 +1,384 @@
+use crate::camera::Camera;
+use crate::vec3::{unit_vector, Vec3};
+use std::f64::consts::PI;
+use std::path::PathBuf;
+
+// Cameras that move. A path goes through keyframes, each saying where the
+// camera is and how it's set up at some time, and is interpolated in
+// between, so every frame of an animation gets a camera of its own. Before
+// the first keyframe and after the last the camera stays where it is.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time: f64,
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    // top to bottom in degrees
+    pub vfov: f64,
+    pub focus_dist: f64,
+    pub aperture: f64,
+}
+
+impl Keyframe {
+    // everything that gets interpolated, one after the other
+    fn values(&self) -> [f64; 9] {
+        [
+            self.look_from.x,
+            self.look_from.y,
+            self.look_from.z,
+            self.look_at.x,
+            self.look_at.y,
+            self.look_at.z,
+            self.vfov,
+            self.focus_dist,
+            self.aperture,
+        ]
+    }
+
+    // a spline can overshoot, so it's kept to a camera that makes sense
+    fn from_values(time: f64, v: [f64; 9]) -> Self {
+        Keyframe {
+            time,
+            look_from: Vec3::new(v[0], v[1], v[2]),
+            look_at: Vec3::new(v[3], v[4], v[5]),
+            vfov: v[6].clamp(1e-3, 179.0),
+            focus_dist: v[7].max(1e-6),
+            aperture: v[8].max(0.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    // straight from one keyframe to the next, turning sharply at each
+    Linear,
+    // a Catmull-Rom spline through the keyframes, without the sharp turns
+    #[default]
+    CatmullRom,
+}
+
+impl std::str::FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Interpolation::Linear),
+            "catmull_rom" | "catmull-rom" => Ok(Interpolation::CatmullRom),
+            _ => Err(format!(
+                "unknown interpolation '{}', expected one of: linear, catmull_rom",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interpolation::Linear => write!(f, "linear"),
+            Interpolation::CatmullRom => write!(f, "catmull_rom"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraPath {
+    // in time order
+    keyframes: Vec<Keyframe>,
+    interpolation: Interpolation,
+}
+
+impl CameraPath {
+    // there has to be at least one keyframe, and no two at the same time
+    pub fn new(mut keyframes: Vec<Keyframe>, interpolation: Interpolation) -> Result<Self, String> {
+        if keyframes.is_empty() {
+            return Err("a camera path needs at least one keyframe".to_string());
+        }
+        if keyframes.iter().any(|key| !key.time.is_finite()) {
+            return Err("keyframe times have to be numbers".to_string());
+        }
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        if let Some(pair) = keyframes
+            .windows(2)
+            .find(|pair| pair[0].time == pair[1].time)
+        {
+            return Err(format!(
+                "there's more than one keyframe at {}",
+                pair[0].time
+            ));
+        }
+        Ok(CameraPath {
+            keyframes,
+            interpolation,
+        })
+    }
+
+    // One keyframe a line, as
+    //   time  look_from x y z  look_at x y z  [vfov [focus_dist [aperture]]]
+    // separated by spaces or commas. The ones left off the end are the same
+    // as `camera`'s. Everything after a # is a comment.
+    pub fn parse(
+        text: &str,
+        camera: &Camera,
+        interpolation: Interpolation,
+    ) -> Result<Self, String> {
+        let mut keyframes = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let v = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|part| !part.is_empty())
+                .map(|part| part.parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("line {}: {}", n + 1, err))?;
+            if v.is_empty() {
+                continue;
+            }
+            if !(7..=10).contains(&v.len()) {
+                return Err(format!(
+                    "line {}: expected time, look_from x y z, look_at x y z and then maybe vfov, focus_dist and aperture, not {} numbers",
+                    n + 1,
+                    v.len()
+                ));
+            }
+            let mut key = camera.keyframe(v[0]);
+            key.look_from = Vec3::new(v[1], v[2], v[3]);
+            key.look_at = Vec3::new(v[4], v[5], v[6]);
+            if let Some(&vfov) = v.get(7) {
+                key.vfov = vfov;
+            }
+            if let Some(&focus_dist) = v.get(8) {
+                key.focus_dist = focus_dist;
+            }
+            if let Some(&aperture) = v.get(9) {
+                key.aperture = aperture;
+            }
+            keyframes.push(key);
+        }
+        CameraPath::new(keyframes, interpolation)
+    }
+
+    // Once around whatever `camera` is looking at, about its up direction,
+    // starting where it is at time0 and back there at time1.
+    pub fn turntable(camera: &Camera, time0: f64, time1: f64) -> Self {
+        // A keyframe every 10 degrees is close enough to a circle with the
+        // spline, and with one past either end it doesn't slow at the ends.
+        const STEPS: i32 = 36;
+        let start = camera.keyframe(time0);
+        let axis = unit_vector(&camera.vup());
+        let arm = start.look_from - start.look_at;
+        let keyframes = (-1..=STEPS + 1)
+            .map(|i| {
+                let fraction = i as f64 / STEPS as f64;
+                let (sin, cos) = (2.0 * PI * fraction).sin_cos();
+                // Rodrigues' rotation of the arm about the axis
+                let turned =
+                    arm * cos + axis.cross(&arm) * sin + axis * (axis.dot(&arm) * (1.0 - cos));
+                Keyframe {
+                    time: time0 + (time1 - time0) * fraction,
+                    look_from: start.look_at + turned,
+                    ..start
+                }
+            })
+            .collect();
+        CameraPath {
+            keyframes,
+            interpolation: Interpolation::CatmullRom,
+        }
+    }
+
+    // when the first and last keyframes are
+    pub fn start(&self) -> f64 {
+        self.keyframes[0].time
+    }
+
+    pub fn end(&self) -> f64 {
+        self.keyframes[self.keyframes.len() - 1].time
+    }
+
+    pub fn at(&self, time: f64) -> Keyframe {
+        let keys = &self.keyframes;
+        // the first keyframe after time
+        let next = keys.partition_point(|key| key.time <= time);
+        if next == 0 {
+            return Keyframe { time, ..keys[0] };
+        }
+        if next == keys.len() {
+            return Keyframe {
+                time,
+                ..keys[keys.len() - 1]
+            };
+        }
+        let (k1, k2) = (&keys[next - 1], &keys[next]);
+        let (p1, p2) = (k1.values(), k2.values());
+        let span = k2.time - k1.time;
+        let u = (time - k1.time) / span;
+        let values = match self.interpolation {
+            Interpolation::Linear => std::array::from_fn(|i| p1[i] + (p2[i] - p1[i]) * u),
+            Interpolation::CatmullRom => {
+                // How fast it's going through k1 and k2, from the keyframes
+                // either side of them, scaled to this span since they don't
+                // have to be evenly spaced. At the ends of the path there's
+                // nothing the other side, so it heads straight for the next.
+                let m1: [f64; 9] = match next.checked_sub(2).map(|k0| &keys[k0]) {
+                    Some(k0) => {
+                        let p0 = k0.values();
+                        std::array::from_fn(|i| (p2[i] - p0[i]) * span / (k2.time - k0.time))
+                    }
+                    None => std::array::from_fn(|i| p2[i] - p1[i]),
+                };
+                let m2: [f64; 9] = match keys.get(next + 1) {
+                    Some(k3) => {
+                        let p3 = k3.values();
+                        std::array::from_fn(|i| (p3[i] - p1[i]) * span / (k3.time - k1.time))
+                    }
+                    None => std::array::from_fn(|i| p2[i] - p1[i]),
+                };
+                // the cubic Hermite basis
+                let (u2, u3) = (u * u, u * u * u);
+                let h00 = 2.0 * u3 - 3.0 * u2 + 1.0;
+                let h10 = u3 - 2.0 * u2 + u;
+                let h01 = -2.0 * u3 + 3.0 * u2;
+                let h11 = u3 - u2;
+                std::array::from_fn(|i| h00 * p1[i] + h10 * m1[i] + h01 * p2[i] + h11 * m2[i])
+            }
+        };
+        Keyframe::from_values(time, values)
+    }
+}
+
+// When the shutter opens and closes for each frame from start to end, fps
+// frames a second, with it open for `shutter` of a frame (0.5 is the usual
+// half a frame). There's no frame right at the end, so a loop that ends
+// where it started doesn't have that frame twice.
+pub fn frame_times(start: f64, end: f64, fps: f64, shutter: f64) -> Vec<(f64, f64)> {
+    let frames = ((end - start) * fps - 1e-6).ceil().max(1.0) as usize;
+    (0..frames)
+        .map(|n| {
+            let open = start + n as f64 / fps;
+            (open, open + shutter / fps)
+        })
+        .collect()
+}
+
+// The file a frame goes to, the #s in `pattern` replaced with its number,
+// padded with zeros to as many digits as there are #s.
+pub fn frame_path(pattern: &str, number: usize) -> PathBuf {
+    match pattern.find('#') {
+        Some(first) => {
+            let rest = pattern[first..].trim_start_matches('#');
+            let digits = pattern.len() - first - rest.len();
+            PathBuf::from(format!(
+                "{}{:0digits$}{}",
+                &pattern[..first],
+                number,
+                rest,
+                digits = digits
+            ))
+        }
+        None => PathBuf::from(pattern),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{frame_path, frame_times, CameraPath, Interpolation};
+    use crate::camera::Camera;
+    use crate::vect;
+    use std::path::PathBuf;
+
+    fn camera() -> Camera {
+        Camera::new(
+            vect!(0, 0, 10),
+            vect!(0, 0, 0),
+            vect!(0, 1, 0),
+            40.0,
+            2.0,
+            0.5,
+            10.0,
+            0.0,
+            0.0,
+        )
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let text = "# t  from  at  vfov\n\
+                    0  0 0 10  0 0 0\n\
+                    2  10 0 0  0 0 0  20\n\
+                    3, 0, 0, -10, 0, 1, 0, 30, 5, 0\n\
+                    \n\
+                    1  7 0 7  0 0 0  # no vfov, so the camera's\n";
+        let linear = CameraPath::parse(text, &camera(), Interpolation::Linear).unwrap();
+        let smooth = CameraPath::parse(text, &camera(), Interpolation::CatmullRom).unwrap();
+        assert_eq!((linear.start(), linear.end()), (0.0, 3.0));
+
+        for path in [&linear, &smooth] {
+            // through every keyframe, and still before and after
+            let key = path.at(1.0);
+            assert_eq!(
+                (key.look_from, key.vfov, key.aperture),
+                (vect!(7, 0, 7), 40.0, 0.5)
+            );
+            assert_eq!(path.at(3.0).look_at, vect!(0, 1, 0));
+            assert_eq!(path.at(-1.0).look_from, vect!(0, 0, 10));
+            assert_eq!(path.at(9.0).focus_dist, 5.0);
+            assert_eq!(path.at(9.0).time, 9.0);
+        }
+        assert_eq!(linear.at(1.5).look_from, vect!(8.5, 0, 3.5));
+        assert_eq!(linear.at(2.5).vfov, 25.0);
+
+        // the spline doesn't turn sharply at a keyframe like the lines do
+        let turn = |path: &CameraPath| {
+            let (before, at, after) = (path.at(0.99), path.at(1.0), path.at(1.01));
+            ((at.look_from - before.look_from) - (after.look_from - at.look_from)).length()
+        };
+        assert!(turn(&linear) > 0.05);
+        assert!(turn(&smooth) < turn(&linear) / 20.0);
+
+        assert!(CameraPath::parse("0 1 2 3", &camera(), Interpolation::Linear).is_err());
+        assert!(CameraPath::parse("0 1 2 3 4 5 x", &camera(), Interpolation::Linear).is_err());
+        let twice = "1  0 0 1  0 0 0\n1  0 0 2  0 0 0";
+        assert!(CameraPath::parse(twice, &camera(), Interpolation::Linear).is_err());
+        assert!(CameraPath::new(Vec::new(), Interpolation::Linear).is_err());
+        assert_eq!("catmull_rom".parse(), Ok(Interpolation::CatmullRom));
+        assert!("cubic".parse::<Interpolation>().is_err());
+    }
+
+    #[test]
+    fn test_turntable() {
+        let camera = camera();
+        let path = CameraPath::turntable(&camera, 2.0, 6.0);
+        for n in 0..=40 {
+            let key = path.at(2.0 + n as f64 * 0.1);
+            // the same distance away and height the whole way round
+            assert!(((key.look_from - key.look_at).length() - 10.0).abs() < 1e-3);
+            assert!(key.look_from.y.abs() < 1e-9);
+            assert_eq!(key.look_at, vect!(0, 0, 0));
+        }
+        assert!((path.at(3.0).look_from - vect!(10, 0, 0)).length() < 1e-9);
+        assert!((path.at(6.0).look_from - vect!(0, 0, 10)).length() < 1e-9);
+
+        // and the camera at a keyframe is the one it came from
+        let moved = camera.moved_to(&path.at(2.0), 0.0, 0.0);
+        assert!((moved.lower_left_corner - camera.lower_left_corner).length() < 1e-9);
+        assert!((moved.horizontal - camera.horizontal).length() < 1e-9);
+        assert_eq!(moved.keyframe(2.0), path.at(2.0));
+    }
+
+    #[test]
+    fn test_frames() {
+        let frames = frame_times(1.0, 2.0, 24.0, 0.5);
+        assert_eq!(frames.len(), 24);
+        assert_eq!(frames[0], (1.0, 1.0 + 0.5 / 24.0));
+        assert!((frames[23].0 - (2.0 - 1.0 / 24.0)).abs() < 1e-12);
+        assert_eq!(frame_times(0.0, 0.0, 24.0, 0.5).len(), 1);
+        assert_eq!(frame_times(0.0, 0.1, 24.0, 0.0).len(), 3);
+
+        assert_eq!(
+            frame_path("out/f_####.png", 7),
+            PathBuf::from("out/f_0007.png")
+        );
+        assert_eq!(frame_path("#.ppm", 12), PathBuf::from("12.ppm"));
+    }
+}
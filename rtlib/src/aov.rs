@@ -0,0 +1,252 @@
+use crate::hittable::HitRecord;
+use crate::materials::Material;
+use crate::ray::Ray;
+use crate::textures::Texture;
+use crate::vec3::{Color, Vec3};
+use crate::vect;
+use std::io::{self, Write};
+
+// Arbitrary output variables. Besides the beauty pass, compositing and
+// denoising want to know what each pixel is looking at: how far away it is,
+// which way the surface faces, the color of the material without any
+// lighting, and so on. These all come from the first thing each camera ray
+// hits, averaged over the pixel's samples like the beauty pass.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aov {
+    // distance from the camera along the ray
+    Depth,
+    // the shading normal, pointing out of the surface
+    Normal,
+    // the material's albedo, no lighting
+    Albedo,
+    // where it was hit, in world space
+    Position,
+    // texture coordinates, u in red and v in green
+    Uv,
+    // white where the ray hit the outside of a surface, black for the inside
+    FrontFace,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Depth,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::Position,
+        Aov::Uv,
+        Aov::FrontFace,
+    ];
+
+    // a comma separated list, like "depth,normal,albedo". "all" for every one.
+    pub fn parse_list(s: &str) -> Result<Vec<Aov>, String> {
+        if s.trim() == "all" {
+            return Ok(Aov::ALL.to_vec());
+        }
+        let mut aovs = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let aov = name.parse::<Aov>()?;
+            if !aovs.contains(&aov) {
+                aovs.push(aov);
+            }
+        }
+        Ok(aovs)
+    }
+}
+
+impl std::str::FromStr for Aov {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "depth" | "z" => Ok(Aov::Depth),
+            "normal" => Ok(Aov::Normal),
+            "albedo" => Ok(Aov::Albedo),
+            "position" => Ok(Aov::Position),
+            "uv" => Ok(Aov::Uv),
+            "front_face" => Ok(Aov::FrontFace),
+            _ => Err(format!(
+                "unknown aov '{}', expected one of: depth, normal, albedo, position, uv, front_face, all",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Aov {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Aov::Depth => write!(f, "depth"),
+            Aov::Normal => write!(f, "normal"),
+            Aov::Albedo => write!(f, "albedo"),
+            Aov::Position => write!(f, "position"),
+            Aov::Uv => write!(f, "uv"),
+            Aov::FrontFace => write!(f, "front_face"),
+        }
+    }
+}
+
+// Running sums for one pixel. Only samples that hit something count, so
+// pixels that see nothing but background stay black.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AovPixel {
+    hits: i32,
+    depth: f64,
+    normal: Vec3,
+    albedo: Color,
+    position: Vec3,
+    uv: Vec3,
+    front_face: f64,
+}
+
+impl AovPixel {
+    pub fn add(&mut self, ray: &Ray, rec: Option<&HitRecord>) {
+        let rec = match rec {
+            Some(rec) => rec,
+            None => return,
+        };
+        let (u, v) = rec
+            .texture_coord
+            .as_ref()
+            .map_or((0.0, 0.0), |uv| (uv.u, uv.v));
+        self.hits += 1;
+        // the camera's rays aren't unit length
+        self.depth += rec.t * ray.direction().length();
+        self.normal += rec.normal;
+        self.albedo += rec.material.albedo().value(u, v, &rec.p);
+        self.position += rec.p;
+        self.uv += vect!(u, v, 0.0);
+        // the shapes hand back outward normals and leave rec.front_face
+        // alone, so we work it out here
+        let front_face = ray.direction().dot(&rec.normal) < 0.0;
+        self.front_face += if front_face { 1.0 } else { 0.0 };
+    }
+
+    pub fn hits(&self) -> i32 {
+        self.hits
+    }
+
+    // the average over the samples that hit something, as it is in the scene
+    pub fn value(&self, aov: Aov) -> Vec3 {
+        if self.hits == 0 {
+            return Vec3::default();
+        }
+        let n = self.hits as f64;
+        match aov {
+            Aov::Depth => vect!(self.depth, self.depth, self.depth) / n,
+            Aov::Normal => self.normal / n,
+            Aov::Albedo => self.albedo / n,
+            Aov::Position => self.position / n,
+            Aov::Uv => self.uv / n,
+            Aov::FrontFace => vect!(self.front_face, self.front_face, self.front_face) / n,
+        }
+    }
+}
+
+// Writes one pass as a plain PPM like the beauty pass, but without the gamma,
+// since most of these aren't colors. What doesn't already fit in 0..1 gets
+// mapped into it: normals from -1..1, depth and position by how far they
+// reach across the image.
+pub fn write_aov(
+    stream: &mut impl Write,
+    aov: Aov,
+    width: i32,
+    pixels: &[AovPixel],
+) -> io::Result<()> {
+    let height = pixels.len() as i32 / width.max(1);
+    let hit = || pixels.iter().filter(|p| p.hits() > 0).map(|p| p.value(aov));
+    let lo = hit().fold(vect!(f64::MAX, f64::MAX, f64::MAX), |a, b| a.min(&b));
+    let hi = hit().fold(vect!(f64::MIN, f64::MIN, f64::MIN), |a, b| a.max(&b));
+    let scaled = |v: Vec3| match aov {
+        // near is dark, the farthest thing in the picture white
+        Aov::Depth => v / hi.x.max(f64::MIN_POSITIVE),
+        Aov::Normal => 0.5 * (v + vect!(1, 1, 1)),
+        // each axis across the box everything visible fits in
+        Aov::Position => {
+            let size = (hi - lo).max(&vect!(
+                f64::MIN_POSITIVE,
+                f64::MIN_POSITIVE,
+                f64::MIN_POSITIVE
+            ));
+            vect!(
+                (v.x - lo.x) / size.x,
+                (v.y - lo.y) / size.y,
+                (v.z - lo.z) / size.z
+            )
+        }
+        Aov::Albedo | Aov::Uv | Aov::FrontFace => v,
+    };
+    let to_byte = |c: f64| ((c * u8::MAX as f64) as i32).clamp(0, u8::MAX as i32);
+
+    writeln!(stream, "P3\n{} {}\n255", width, height)?;
+    for p in pixels {
+        let v = if p.hits() > 0 {
+            scaled(p.value(aov))
+        } else {
+            Vec3::default()
+        };
+        writeln!(stream, "{} {} {}", to_byte(v.x), to_byte(v.y), to_byte(v.z))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_aov, Aov, AovPixel};
+    use crate::hittable::{HitRecord, TextureCoord};
+    use crate::materials::{Lambertian, MaterialType};
+    use crate::ray::Ray;
+    use crate::vec3::Color;
+    use crate::{color_to_texture, vect};
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            Aov::parse_list("depth, normal,depth").unwrap(),
+            vec![Aov::Depth, Aov::Normal]
+        );
+        assert_eq!(Aov::parse_list("all").unwrap(), Aov::ALL.to_vec());
+        assert!(Aov::parse_list("depth,nope").is_err());
+        for aov in Aov::ALL {
+            assert_eq!(aov.to_string().parse::<Aov>().unwrap(), aov);
+        }
+    }
+
+    #[test]
+    fn test_aov_pixel() {
+        let material = MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&Color::new(
+            0.2, 0.4, 0.6
+        ))));
+        let ray = Ray::new(&vect!(0, 0, 0), &vect!(0, 0, -2), None);
+        let mut rec = HitRecord::new(vect!(0, 0, -3), 1.5, material);
+        rec.normal = vect!(0, 0, 1);
+        rec.texture_coord = Some(TextureCoord { u: 0.25, v: 0.75 });
+
+        let mut pixel = AovPixel::default();
+        pixel.add(&ray, Some(&rec));
+        // a miss doesn't drag the averages down
+        pixel.add(&ray, None);
+
+        assert_eq!(pixel.hits(), 1);
+        assert_eq!(pixel.value(Aov::Depth), vect!(3, 3, 3));
+        assert_eq!(pixel.value(Aov::Normal), vect!(0, 0, 1));
+        assert_eq!(pixel.value(Aov::Albedo), vect!(0.2, 0.4, 0.6));
+        assert_eq!(pixel.value(Aov::Position), vect!(0, 0, -3));
+        assert_eq!(pixel.value(Aov::Uv), vect!(0.25, 0.75, 0));
+        assert_eq!(pixel.value(Aov::FrontFace), vect!(1, 1, 1));
+        assert_eq!(AovPixel::default().value(Aov::Depth), vect!(0, 0, 0));
+
+        // from the inside the normal points away from the camera
+        let mut inside = AovPixel::default();
+        rec.normal = vect!(0, 0, -1);
+        inside.add(&ray, Some(&rec));
+        assert_eq!(inside.value(Aov::FrontFace), vect!(0, 0, 0));
+
+        let mut out = Vec::new();
+        write_aov(&mut out, Aov::Normal, 2, &[pixel, AovPixel::default()]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "P3\n2 1\n255\n127 127 255\n0 0 0\n"
+        );
+    }
+}
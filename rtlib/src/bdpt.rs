@@ -0,0 +1,541 @@
+use crate::{
+    environment::Background,
+    hitlist::HitList,
+    hittable::{HitRecord, Hittable},
+    materials::Material,
+    onb::Onb,
+    ray::Ray,
+    sampler::Sampler,
+    stats::{count, count_path, Counter},
+    util::{color, cosine_direction, russian_roulette},
+    vec3::{dot, unit_vector, Color, Point3, Vec3},
+};
+use std::f64::consts::PI;
+
+// Bidirectional path tracing. One path is traced from the camera and another
+// from a point on one of the lights, and every vertex of one gets joined up with
+// every vertex of the other by a shadow ray. Each joining is a different way of
+// having found the same kind of path, so they're all weighed against each other
+// with the balance heuristic. Paths that would join straight onto the camera
+// (light tracing) aren't used, they'd land on some other pixel and we've no way
+// of adding to that yet.
+//
+// The notation follows Veach: s is how many vertices come from the light path,
+// t how many come from the camera path, counting the camera itself.
+
+#[derive(Clone)]
+struct Vertex {
+    p: Point3,
+    normal: Vec3,
+    // None for the camera. For the start of the light path this is the point
+    // that was picked on the light.
+    rec: Option<HitRecord>,
+    // throughput of the path up to and including this vertex
+    beta: Color,
+    // pdf per unit area of getting here from the previous vertex, and of
+    // getting here going the other way
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // mirrors and glass, nothing can be joined onto these
+    delta: bool,
+}
+
+impl Vertex {
+    fn camera(p: Point3) -> Self {
+        Vertex {
+            p,
+            normal: Vec3::default(),
+            rec: None,
+            beta: Color::new(1.0, 1.0, 1.0),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn light(rec: HitRecord, beta: Color, pdf_pos: f64) -> Self {
+        Vertex {
+            p: rec.p,
+            normal: rec.normal,
+            rec: Some(rec),
+            beta,
+            pdf_fwd: pdf_pos,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn surface(rec: HitRecord, beta: Color) -> Self {
+        Vertex {
+            p: rec.p,
+            normal: rec.normal,
+            rec: Some(rec),
+            beta,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+        }
+    }
+
+    fn emitted(&self) -> Color {
+        match &self.rec {
+            Some(rec) => rec.material.emitted(
+                rec.texture_coord.unwrap_or_default().u,
+                rec.texture_coord.unwrap_or_default().v,
+                &rec.p,
+            ),
+            None => Color::default(),
+        }
+    }
+
+    // the bsdf for light coming in from prev and leaving towards next
+    fn bsdf(&self, prev: &Point3, next: &Point3) -> Color {
+        match &self.rec {
+            Some(rec) => rec.material.bsdf(
+                &Ray::new(prev, &(self.p - *prev), None),
+                rec,
+                &Ray::new(&self.p, &(*next - self.p), None),
+            ),
+            None => Color::default(),
+        }
+    }
+
+    // pdf per unit area of going from here to next. prev is where we came
+    // from, which the start of a light path doesn't have.
+    fn pdf(&self, prev: Option<&Point3>, next: &Vertex) -> f64 {
+        let rec = match &self.rec {
+            Some(rec) => rec,
+            None => return 0.0,
+        };
+        let to_next = next.p - self.p;
+        let pdf_dir = match prev {
+            Some(prev) => rec.material.scattering_pdf(
+                &Ray::new(prev, &(self.p - *prev), None),
+                rec,
+                &Ray::new(&self.p, &to_next, None),
+            ),
+            None => emission_pdf(&self.normal, &to_next),
+        };
+        to_area(pdf_dir, &self.p, next)
+    }
+}
+
+// lights give off the same from both sides, so we pick a side and then a
+// cosine weighted direction on it
+fn emission_pdf(normal: &Vec3, direction: &Vec3) -> f64 {
+    dot(normal, &unit_vector(direction)).abs() / (2.0 * PI)
+}
+
+// turns a pdf over directions from `from` into one over the area around `to`
+fn to_area(pdf_dir: f64, from: &Point3, to: &Vertex) -> f64 {
+    let w = to.p - *from;
+    let distance_squared = w.length_squared();
+    if distance_squared <= 0.0 {
+        return 0.0;
+    }
+    pdf_dir * dot(&to.normal, &w).abs() / distance_squared.sqrt() / distance_squared
+}
+
+// the geometry term between two vertices, zero if something's in the way
+fn geometry(
+    world: &dyn Hittable,
+    a: &Vertex,
+    b: &Vertex,
+    time: f64,
+    sampler: &mut dyn Sampler,
+) -> f64 {
+    let w = b.p - a.p;
+    let distance = w.length();
+    if distance <= 0.0 {
+        return 0.0;
+    }
+    let direction = w / distance;
+    let shadow_ray = Ray::new(&a.p, &direction, Some(time)).with_medium_sample(sampler.get_1d());
+    count(Counter::ShadowRays);
+    if world.hit(&shadow_ray, 0.001, distance - 0.001).is_some() {
+        return 0.0;
+    }
+    dot(&a.normal, &direction).abs() * dot(&b.normal, &direction).abs() / (distance * distance)
+}
+
+// Follows ray through the world adding a vertex for everything it hits, until
+// max_vertices have been added or the path dies. pdf_dir is the pdf of having
+// picked ray's direction. Gives back the throughput if the path ran out without
+// being killed off by roulette, which is what picks up the interior light.
+fn random_walk(
+    world: &dyn Hittable,
+    ray: &Ray,
+    beta: Color,
+    pdf_dir: f64,
+    max_vertices: usize,
+    path: &mut Vec<Vertex>,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let mut tmpray = *ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf_dir;
+    let mut bounces = 0;
+    let start = path.len();
+    if max_vertices == 0 {
+        return beta;
+    }
+    loop {
+        let rec = match world.hit(&tmpray, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return beta,
+        };
+        let prev = path.len() - 1;
+        let mut vertex = Vertex::surface(rec.clone(), beta);
+        vertex.pdf_fwd = to_area(pdf_fwd, &path[prev].p, &vertex);
+        path.push(vertex);
+        if path.len() - start >= max_vertices {
+            return beta;
+        }
+
+        let (attenuation, sray) = match rec.material.scatter(&tmpray, &rec, sampler) {
+            Some(scattered) => scattered,
+            None => return beta,
+        };
+        let mut pdf_dir = rec.material.scattering_pdf(&tmpray, &rec, &sray);
+        let mut pdf_rev = 0.0;
+        if rec.material.is_specular() || pdf_dir <= 0.0 {
+            path[prev + 1].delta = true;
+            pdf_dir = 0.0;
+        } else {
+            // the chance of having gone the other way, from the next vertex
+            // back to the previous one
+            pdf_rev = rec.material.scattering_pdf(
+                &Ray::new(
+                    &(rec.p + sray.direction()),
+                    &(-1.0 * sray.direction()),
+                    None,
+                ),
+                &rec,
+                &Ray::new(&rec.p, &(-1.0 * tmpray.direction()), None),
+            );
+        }
+        path[prev].pdf_rev = to_area(pdf_rev, &rec.p, &path[prev]);
+
+        beta *= attenuation;
+        if !russian_roulette(&mut beta, bounces, sampler) {
+            return Color::default();
+        }
+        pdf_fwd = pdf_dir;
+        count(Counter::BounceRays);
+        tmpray = sray;
+        bounces += 1;
+    }
+}
+
+// Picks a point on a light and a direction to leave it in, giving the start of
+// the light path, the ray leaving it and the throughput along that ray.
+fn start_light_path(
+    lights: &HitList,
+    time: f64,
+    sampler: &mut dyn Sampler,
+) -> Option<(Vertex, Ray, Color, f64)> {
+    let (rec, pdf_pos) = lights.sample_surface(sampler)?;
+    let side = if sampler.get_1d() < 0.5 {
+        rec.normal
+    } else {
+        -1.0 * rec.normal
+    };
+    let (r1, r2) = sampler.get_2d();
+    let direction = Onb::build_from_w(&side).local_vec(&cosine_direction(r1, r2));
+    let pdf_dir = emission_pdf(&rec.normal, &direction);
+    if pdf_pos <= 0.0 || pdf_dir <= 0.0 {
+        return None;
+    }
+    let vertex = Vertex::light(rec, Color::default(), pdf_pos);
+    let cosine = dot(&vertex.normal, &unit_vector(&direction)).abs();
+    let beta = vertex.emitted() * (cosine / (pdf_pos * pdf_dir));
+    let ray = Ray::new(&vertex.p, &direction, Some(time)).with_medium_sample(sampler.get_1d());
+    count(Counter::LightRays);
+    Some((vertex, ray, beta, pdf_dir))
+}
+
+// The balance heuristic weight for joining light_path[..s] with
+// camera_path[..t]. Rather than work out every other strategy's pdf from
+// scratch, we walk along the path and keep a running ratio of how likely the
+// neighbouring strategy was compared to this one. For s == 1, sampled is the
+// light point that was picked for the join.
+fn mis_weight(
+    lights: &HitList,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    sampled: Option<&Vertex>,
+    s: usize,
+    t: usize,
+) -> f64 {
+    let mut lp: Vec<(f64, f64, bool)> = match sampled {
+        Some(y) => vec![(y.pdf_fwd, y.pdf_rev, y.delta)],
+        None => light_path[..s]
+            .iter()
+            .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+            .collect(),
+    };
+    let mut cp: Vec<(f64, f64, bool)> = camera_path[..t]
+        .iter()
+        .map(|v| (v.pdf_fwd, v.pdf_rev, v.delta))
+        .collect();
+
+    // the pdfs at the ends of the join depend on what's on the other side
+    let z = &camera_path[t - 1];
+    let z_prev = &camera_path[t - 2];
+    if s == 0 {
+        let pdf_light = lights.surface_pdf(&z.p);
+        if pdf_light <= 0.0 {
+            // a light that isn't in the list, the camera path is the only
+            // way to find it
+            return 1.0;
+        }
+        cp[t - 1].1 = pdf_light;
+        if t > 2 {
+            cp[t - 2].1 = to_area(emission_pdf(&z.normal, &(z_prev.p - z.p)), &z.p, z_prev);
+        }
+    } else {
+        let y = match sampled {
+            Some(y) => y,
+            None => &light_path[s - 1],
+        };
+        let y_prev = s.checked_sub(2).map(|i| &light_path[i]);
+        cp[t - 1].1 = y.pdf(y_prev.map(|v| &v.p), z);
+        if t > 2 {
+            cp[t - 2].1 = z.pdf(Some(&y.p), z_prev);
+        }
+        lp[s - 1].1 = z.pdf(Some(&z_prev.p), y);
+        if let Some(y_prev) = y_prev {
+            lp[s - 2].1 = y.pdf(Some(&z.p), y_prev);
+        }
+    }
+
+    // a zero pdf comes from a delta vertex, those are skipped below so any
+    // value that keeps the ratios finite will do
+    let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    // moving camera vertices over to the light path. We stop before the
+    // camera path is down to just the camera, that strategy isn't used.
+    for i in (2..t).rev() {
+        ratio *= remap(cp[i].1) / remap(cp[i].0);
+        if !cp[i].2 && !cp[i - 1].2 {
+            sum += ratio;
+        }
+    }
+    ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(lp[i].1) / remap(lp[i].0);
+        let prev_delta = i > 0 && lp[i - 1].2;
+        if !lp[i].2 && !prev_delta {
+            sum += ratio;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+// the weighted contribution of joining light_path[..s] with camera_path[..t],
+// strategy is (s, t)
+fn connect(
+    world: &dyn Hittable,
+    lights: &HitList,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    strategy: (usize, usize),
+    time: f64,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let (s, t) = strategy;
+    let z = &camera_path[t - 1];
+    let z_prev = &camera_path[t - 2];
+    let mut sampled: Option<Vertex> = None;
+    let contribution = if s == 0 {
+        // the camera path ran into a light by itself
+        z.beta * z.emitted()
+    } else if s == 1 {
+        // pick a new point on a light rather than use the start of the light
+        // path, it's the same as the shadow rays in color_direct_lighting()
+        if z.delta {
+            return Color::default();
+        }
+        let (rec, pdf_pos) = match lights.sample_surface(sampler) {
+            Some(sample) => sample,
+            None => return Color::default(),
+        };
+        if pdf_pos <= 0.0 {
+            return Color::default();
+        }
+        let mut y = Vertex::light(rec, Color::default(), pdf_pos);
+        let bsdf = z.bsdf(&z_prev.p, &y.p);
+        if bsdf == Color::default() {
+            return bsdf;
+        }
+        let g = geometry(world, z, &y, time, sampler);
+        y.beta = y.emitted() / pdf_pos;
+        let contribution = z.beta * bsdf * y.beta * g;
+        sampled = Some(y);
+        contribution
+    } else {
+        let y = &light_path[s - 1];
+        if y.delta || z.delta {
+            return Color::default();
+        }
+        let y_prev = &light_path[s - 2];
+        let bsdf_y = y.bsdf(&y_prev.p, &z.p);
+        let bsdf_z = z.bsdf(&z_prev.p, &y.p);
+        if bsdf_y == Color::default() || bsdf_z == Color::default() {
+            return Color::default();
+        }
+        y.beta * bsdf_y * bsdf_z * z.beta * geometry(world, y, z, time, sampler)
+    };
+    if contribution == Color::default() {
+        return contribution;
+    }
+    contribution * mis_weight(lights, light_path, camera_path, sampled.as_ref(), s, t)
+}
+
+// lights should hold everything world.emitters() gives back. depth is the
+// number of bounces, same as color(), so the two give the same picture.
+#[allow(unused_imports, dead_code)]
+pub fn color_bdpt(
+    ray: &Ray,
+    world: &dyn Hittable,
+    lights: &HitList,
+    depth: i32,
+    background: &Background,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    if lights.list.is_empty() {
+        return color(ray, world, depth, background, sampler);
+    }
+    // Only the interior light, the walk doesn't say which way it left the
+    // scene, so an environment map can't be looked up. It's added whether
+    // the path got out or not, like color() does with it.
+    let ambient = background.ended();
+    let max_bounces = depth.max(0) as usize;
+    let time = ray.time();
+
+    let mut camera_path = vec![Vertex::camera(ray.origin())];
+    let escaped = random_walk(
+        world,
+        ray,
+        Color::new(1.0, 1.0, 1.0),
+        1.0,
+        max_bounces + 1,
+        &mut camera_path,
+        sampler,
+    );
+    // the camera and the first hit aren't bounces
+    count_path(camera_path.len().saturating_sub(2) as i32);
+    let mut radiance = escaped * ambient;
+
+    let mut light_path = Vec::new();
+    if let Some((start, light_ray, beta, pdf_dir)) = start_light_path(lights, time, sampler) {
+        light_path.push(start);
+        random_walk(
+            world,
+            &light_ray,
+            beta,
+            pdf_dir,
+            max_bounces,
+            &mut light_path,
+            sampler,
+        );
+    }
+
+    for t in 2..=camera_path.len() {
+        for s in 0..=light_path.len().max(1) {
+            // every vertex in between the two ends is a bounce
+            if s + t - 2 > max_bounces {
+                break;
+            }
+            radiance += connect(
+                world,
+                lights,
+                &light_path,
+                &camera_path,
+                (s, t),
+                time,
+                sampler,
+            );
+        }
+    }
+    radiance
+}
+
+#[cfg(test)]
+mod test {
+    use super::color_bdpt;
+    use crate::{
+        color_to_texture,
+        environment::Background,
+        hitlist::HitList,
+        hittable::{Hittable, Hitters},
+        materials::{DiffuseLight, Lambertian, MaterialType},
+        ray,
+        rectangle::{Axis, Rect},
+        sampler::IndependentSampler,
+        util::color_mis,
+        vec3::Color,
+        vect,
+    };
+
+    // a floor, a wall along one side of it and a light above, so there are
+    // paths with a few bounces in them
+    fn floor_wall_light() -> HitList {
+        let grey =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.5, 0.5, 0.5))));
+        let light =
+            MaterialType::DiffuseLight(DiffuseLight::new(color_to_texture!(&vect!(4, 4, 4))));
+        let mut world = HitList::new();
+        world.add(Hitters::Rect(Rect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            0.0,
+            &grey,
+            Axis::Y,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            0.0,
+            1.0,
+            -1.0,
+            1.0,
+            -1.0,
+            &grey,
+            Axis::X,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            -0.25,
+            0.25,
+            -0.25,
+            0.25,
+            1.0,
+            &light,
+            Axis::Y,
+        )));
+        world
+    }
+
+    #[test]
+    fn test_color_bdpt() {
+        let world = floor_wall_light();
+        let mut lights = HitList::new();
+        lights.list = world.emitters();
+
+        // looking down at the floor next to the wall, bdpt should come out
+        // the same as the unidirectional integrators
+        let r = ray!(&vect!(0.5, 0.5, 0), &vect!(-1.2, -0.5, 0));
+        let n = 40000;
+        let mut bdpt = Color::default();
+        let mut mis = Color::default();
+        let mut sampler = IndependentSampler::new(0);
+        for _ in 0..n {
+            bdpt += color_bdpt(&r, &world, &lights, 3, &Background::default(), &mut sampler);
+            mis += color_mis(&r, &world, &lights, 3, &Background::default(), &mut sampler);
+        }
+        let (bdpt, mis) = (bdpt.x / n as f64, mis.x / n as f64);
+        assert!(mis > 0.0);
+        assert!((bdpt - mis).abs() < 0.03 * mis, "bdpt {} mis {}", bdpt, mis);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::aabb::BoundingBox;
 use crate::prelude;
-use prelude::{HitList, HitRecord, Hittable, Ray};
+use crate::stats::{count, Counter};
+use prelude::{HitList, HitRecord, Hittable, Hitters, Ray};
 use rand::Rng;
 use std::sync::Arc;
 
@@ -55,10 +56,17 @@ impl<'a> BvhNode {
         self.clone()
     }
 
-    pub fn add_hitlist(&mut self, hl: &mut Arc<HitList>, t_min: f64, t_max: f64) -> &mut Self {
+    // rng picks the axes, so the same seed always builds the same tree
+    pub fn add_hitlist(
+        &mut self,
+        hl: &mut Arc<HitList>,
+        t_min: f64,
+        t_max: f64,
+        rng: &mut impl Rng,
+    ) -> &mut Self {
         // first we need to sort the list
         // choose a random axis to partition by
-        let axis = Self::rand_axis();
+        let axis = Self::rand_axis(rng);
         // make sure our bounding box contains everything we hold
         for item in &hl.list {
             self.bb = BoundingBox::expand_to_contain(
@@ -102,8 +110,8 @@ impl<'a> BvhNode {
             let left_hl = left_hl;
             let right_hl = right_hl;
 
-            left_node.add_hitlist(&mut Arc::new(left_hl), t_min, t_max);
-            right_node.add_hitlist(&mut Arc::new(right_hl), t_min, t_max);
+            left_node.add_hitlist(&mut Arc::new(left_hl), t_min, t_max, rng);
+            right_node.add_hitlist(&mut Arc::new(right_hl), t_min, t_max, rng);
             self.p_left = Arc::new(Box::from(left_node));
             self.p_right = Arc::new(Box::from(right_node));
         }
@@ -111,8 +119,7 @@ impl<'a> BvhNode {
         self
     }
 
-    pub fn rand_axis() -> u8 {
-        let mut rng = rand::thread_rng();
+    pub fn rand_axis(rng: &mut impl Rng) -> u8 {
         (rng.gen::<f64>() * 3.) as u8
     }
 
@@ -142,6 +149,7 @@ impl<'a> Hittable for Bvh {
     }
 
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count(Counter::BvhNodeVisits);
         // does it hit me?
         if self.bb.hit(r, t_min, t_max).is_some() {
             // does it hit the left branch?
@@ -168,6 +176,14 @@ impl<'a> Hittable for Bvh {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    // the tree is only for finding hits quickly, lights are sampled from a
+    // flat list built from this.
+    fn emitters(&self) -> Vec<Hitters> {
+        let mut lights = self.p_left.emitters();
+        lights.append(&mut self.p_right.emitters());
+        lights
+    }
 }
 
 mod test {
@@ -197,7 +213,7 @@ mod test {
         let mut world = std::sync::Arc::new(random_scene(&mut rng, false, false));
 
         let mut bvh = BvhNode::default();
-        bvh.add_hitlist(&mut world, 0., 0.);
+        bvh.add_hitlist(&mut world, 0., 0., &mut rng);
 
         assert_ne!(
             bvh.bb.bounding_box(0., 0.),
@@ -261,7 +277,12 @@ mod test {
         hl.add(Hitters::Sphere(s3));
 
         let mut bvh = Bvh::new();
-        bvh.add_hitlist(&mut std::sync::Arc::new(hl), 0., 0.);
+        bvh.add_hitlist(
+            &mut std::sync::Arc::new(hl),
+            0.,
+            0.,
+            &mut rand::thread_rng(),
+        );
         bvh.build();
         let hit_or_not = bvh.hit(&r, 0., 0.);
         // this should have 2 hits, but we'll return the closest one
@@ -275,4 +296,32 @@ mod test {
         }
         //println!("the result front_face: {}", result.material);
     }
+
+    #[test]
+    fn test_seeded_bvh() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        // the same seed has to give the same scene and the same tree
+        let build = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut world = std::sync::Arc::new(random_scene(&mut rng, true, true));
+            let mut bvh = Bvh::new();
+            bvh.add_hitlist(&mut world, 0., 1., &mut rng);
+            bvh.build()
+        };
+        let a = build(11);
+        let b = build(11);
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..200 {
+            let direction = vect!(
+                -1.0 - rng.gen::<f64>(),
+                -0.2 * rng.gen::<f64>(),
+                rng.gen::<f64>() - 0.5
+            );
+            let r = Ray::new(&vect!(13, 2, 3), &direction, Some(rng.gen::<f64>()));
+            let hit_a = a.hit(&r, 0.001, f64::MAX).map(|h| (h.t, h.p));
+            let hit_b = b.hit(&r, 0.001, f64::MAX).map(|h| (h.t, h.p));
+            assert_eq!(hit_a, hit_b);
+        }
+    }
 }
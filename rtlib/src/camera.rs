@@ -1,6 +1,9 @@
 use super::{ray, vect};
+use crate::animation::Keyframe;
 #[allow(unused_imports)]
 use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::stats::{count, Counter};
 #[allow(unused_imports)]
 use crate::vec3::{unit_vector, Vec3};
 use rand::Rng;
@@ -20,6 +23,13 @@ pub fn random_in_unit_disk() -> Vec3 {
     p
 }
 
+// the same, from two numbers in [0, 1) rather than until one lands inside
+pub fn sample_unit_disk(u: f64, v: f64) -> Vec3 {
+    let r = u.sqrt();
+    let theta = 2.0 * std::f64::consts::PI * v;
+    vect!(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
 #[derive(Clone, Copy, Debug)]
 #[allow(unused_imports, dead_code)]
 pub struct Camera {
@@ -34,6 +44,12 @@ pub struct Camera {
     // shutter open/close times
     time0: f64,
     time1: f64,
+    // what it was made from, so it can be moved to a keyframe
+    look_at: Vec3,
+    vup: Vec3,
+    vfov: f64,
+    aspect: f64,
+    focus_dist: f64,
 }
 
 impl Camera {
@@ -73,15 +89,60 @@ impl Camera {
             lens_radius,
             time0: shutter_open,
             time1: shutter_close,
+            look_at: lookat,
+            vup,
+            vfov,
+            aspect,
+            focus_dist,
         }
     }
 
+    pub fn vup(&self) -> Vec3 {
+        self.vup
+    }
+
+    // where it is and how it's set up, as a keyframe at `time`
+    pub fn keyframe(&self, time: f64) -> Keyframe {
+        Keyframe {
+            time,
+            look_from: self.origin,
+            look_at: self.look_at,
+            vfov: self.vfov,
+            focus_dist: self.focus_dist,
+            aperture: 2.0 * self.lens_radius,
+        }
+    }
+
+    // The same camera, pointed and focused the way the keyframe says, with
+    // the shutter open from shutter_open to shutter_close. Which way is up
+    // and the aspect ratio stay the same.
+    pub fn moved_to(&self, keyframe: &Keyframe, shutter_open: f64, shutter_close: f64) -> Camera {
+        Camera::new(
+            keyframe.look_from,
+            keyframe.look_at,
+            self.vup,
+            keyframe.vfov,
+            self.aspect,
+            keyframe.aperture,
+            keyframe.focus_dist,
+            shutter_open,
+            shutter_close,
+        )
+    }
+
+    // when the shutter opens and closes, anything that moves is somewhere
+    // in between for every ray
+    pub fn shutter(&self) -> (f64, f64) {
+        (self.time0, self.time1)
+    }
+
     #[allow(unused_imports, dead_code)]
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd: Vec3 = self.lens_radius * random_in_unit_disk();
+    pub fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Ray {
+        count(Counter::CameraRays);
+        let (lens_u, lens_v) = sampler.get_2d();
+        let rd: Vec3 = self.lens_radius * sample_unit_disk(lens_u, lens_v);
         let offset: Vec3 = self.u * rd.x + self.v * rd.y;
-        let mut rng = rand::thread_rng();
-        let time = self.time0 + rng.gen::<f64>() * (self.time1 - self.time0);
+        let time = self.time0 + sampler.get_1d() * (self.time1 - self.time0);
         ray!(
             &(self.origin + offset),
             &(self.lower_left_corner + self.horizontal * s + self.vertical * t
@@ -89,6 +150,7 @@ impl Camera {
                 - offset), // changed the mult order so it didn't try to dereference self
             time
         )
+        .with_medium_sample(sampler.get_1d())
     }
 }
 
@@ -155,7 +217,8 @@ mod test {
             0.0,
         );
 
-        let r = c.get_ray(4.0, 2.0);
+        let mut sampler = crate::sampler::IndependentSampler::new(0);
+        let r = c.get_ray(4.0, 2.0, &mut sampler);
         // this is now random, so it'll change every time!
         //let ans: raytracer::ray::Ray = raytracer::ray::Ray::new(&raytracer::vec3::Vec3::new(0.0, 0.0, 0.0), &raytracer::vec3::Vec3::new(14.0, 3.0, -1.0));
         let ans = ray!(
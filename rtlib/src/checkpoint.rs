@@ -0,0 +1,322 @@
+use crate::adaptive::PixelStats;
+use crate::film::Splat;
+use crate::vec3::Color;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Long renders are split into tiles, and the samples each pixel has so far
+// are saved to a checkpoint every so often. If the render gets killed it can
+// pick up from the last checkpoint, and a finished one can be given more
+// samples. Since a pixel's samples only depend on where it is and how many
+// it already has, a resumed render comes out the same as one that was never
+// stopped.
+
+const MAGIC: &[u8; 8] = b"RTCKPT01";
+// Checked before anything's allocated, so a broken file is an error rather
+// than running out of memory. A pixel's stats are a u32 and two colors.
+const MAX_SETTINGS: usize = 64 * 1024;
+const PIXEL_BYTES: u64 = 4 + 6 * 8;
+
+// a block of pixels, in the order they're written out, so row 0 is the top
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x0: i32,
+    pub y0: i32,
+    pub x1: i32,
+    pub y1: i32,
+}
+
+impl Tile {
+    // where the tile's pixels are in an image `width` pixels wide
+    pub fn indices(&self, width: i32) -> impl Iterator<Item = usize> + '_ {
+        (self.y0..self.y1)
+            .flat_map(move |y| (self.x0..self.x1).map(move |x| (y * width + x) as usize))
+    }
+
+    // x across and y down from the top left
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+
+    pub fn pixels(&self) -> usize {
+        ((self.x1 - self.x0).max(0) * (self.y1 - self.y0).max(0)) as usize
+    }
+
+    // the pixels that are in both, if there are any
+    pub fn intersection(&self, other: &Tile) -> Option<Tile> {
+        let tile = Tile {
+            x0: self.x0.max(other.x0),
+            y0: self.y0.max(other.y0),
+            x1: self.x1.min(other.x1),
+            y1: self.y1.min(other.y1),
+        };
+        (tile.x0 < tile.x1 && tile.y0 < tile.y1).then_some(tile)
+    }
+}
+
+// covers the image with tiles of size x size pixels, smaller at the right
+// and bottom edges if it doesn't divide evenly
+pub fn tiles(width: i32, height: i32, size: i32) -> Vec<Tile> {
+    let size = size.max(1);
+    (0..height)
+        .step_by(size as usize)
+        .flat_map(|y0| {
+            (0..width).step_by(size as usize).map(move |x0| Tile {
+                x0,
+                y0,
+                x1: (x0 + size).min(width),
+                y1: (y0 + size).min(height),
+            })
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    pub width: i32,
+    pub height: i32,
+    pub seed: u64,
+    // describes the scene and the settings that change what a sample is, so
+    // we don't carry on with a render that isn't the same one
+    pub settings: String,
+    pub pixels: Vec<PixelStats>,
+    // what's been splatted through the reconstruction filter, empty without one
+    pub film: Vec<Splat>,
+    // every pixel's median of means sums one after the other, empty without it
+    pub groups: Vec<Color>,
+}
+
+impl Checkpoint {
+    // Writes to a temporary file first and then moves it over the old one,
+    // so being killed part way through never leaves a broken checkpoint.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        {
+            let mut w = BufWriter::new(File::create(&temp)?);
+            w.write_all(MAGIC)?;
+            w.write_all(&self.width.to_le_bytes())?;
+            w.write_all(&self.height.to_le_bytes())?;
+            w.write_all(&self.seed.to_le_bytes())?;
+            w.write_all(&(self.settings.len() as u32).to_le_bytes())?;
+            w.write_all(self.settings.as_bytes())?;
+            for stats in &self.pixels {
+                stats.write_to(&mut w)?;
+            }
+            w.write_all(&(self.film.len() as u32).to_le_bytes())?;
+            for splat in &self.film {
+                splat.write_to(&mut w)?;
+            }
+            w.write_all(&(self.groups.len() as u32).to_le_bytes())?;
+            for sum in &self.groups {
+                for v in [sum.x, sum.y, sum.z] {
+                    w.write_all(&v.to_le_bytes())?;
+                }
+            }
+            w.flush()?;
+        }
+        fs::rename(&temp, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a checkpoint file",
+            ));
+        }
+        let mut word = [0u8; 4];
+        r.read_exact(&mut word)?;
+        let width = i32::from_le_bytes(word);
+        r.read_exact(&mut word)?;
+        let height = i32::from_le_bytes(word);
+        let mut seed = [0u8; 8];
+        r.read_exact(&mut seed)?;
+        r.read_exact(&mut word)?;
+        let settings_len = u32::from_le_bytes(word) as usize;
+        if settings_len > MAX_SETTINGS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint settings are too long",
+            ));
+        }
+        let mut settings = vec![0u8; settings_len];
+        r.read_exact(&mut settings)?;
+        let settings = String::from_utf8(settings)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if width <= 0 || height <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint has no pixels",
+            ));
+        }
+        if width as u64 * height as u64 * PIXEL_BYTES > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint is too short for its size",
+            ));
+        }
+        let pixels = (0..width as usize * height as usize)
+            .map(|_| PixelStats::read_from(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        r.read_exact(&mut word)?;
+        let splats = u32::from_le_bytes(word) as usize;
+        if splats != 0 && splats != pixels.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint film is the wrong size",
+            ));
+        }
+        let film = (0..splats)
+            .map(|_| Splat::read_from(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        r.read_exact(&mut word)?;
+        let sums = u32::from_le_bytes(word) as usize;
+        if !sums.is_multiple_of(pixels.len()) || sums as u64 * 24 > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint groups are the wrong size",
+            ));
+        }
+        let mut groups = Vec::new();
+        let mut v = [0u8; 8];
+        for _ in 0..sums {
+            let mut sum = [0.0; 3];
+            for c in &mut sum {
+                r.read_exact(&mut v)?;
+                *c = f64::from_le_bytes(v);
+            }
+            groups.push(Color::new(sum[0], sum[1], sum[2]));
+        }
+        Ok(Checkpoint {
+            width,
+            height,
+            seed: u64::from_le_bytes(seed),
+            settings,
+            pixels,
+            film,
+            groups,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tiles, Checkpoint, Tile, MAGIC};
+    use crate::adaptive::PixelStats;
+    use crate::film::Splat;
+    use crate::vect;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_tiles() {
+        // every pixel in exactly one tile, even when they don't fit evenly
+        let (width, height) = (70, 45);
+        let mut seen = vec![0; (width * height) as usize];
+        let tiles = tiles(width, height, 16);
+        assert_eq!(tiles.len(), 5 * 3);
+        for tile in &tiles {
+            tile.indices(width).for_each(|index| seen[index] += 1);
+        }
+        assert!(seen.iter().all(|&n| n == 1));
+
+        let a = Tile {
+            x0: 0,
+            y0: 0,
+            x1: 16,
+            y1: 16,
+        };
+        let b = Tile {
+            x0: 10,
+            y0: 12,
+            x1: 30,
+            y1: 20,
+        };
+        let both = a.intersection(&b).unwrap();
+        assert_eq!((both.x0, both.y0, both.x1, both.y1), (10, 12, 16, 16));
+        assert_eq!(both.pixels(), 24);
+        assert!(both.contains(15, 12) && !both.contains(16, 12));
+        assert_eq!(a.intersection(&tiles[2]), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut pixels = vec![PixelStats::default(); 6];
+        for (n, stats) in pixels.iter_mut().enumerate() {
+            for s in 0..n {
+                stats.add(&vect!(s as f64 * 0.1, 0.5, n as f64));
+            }
+        }
+        let checkpoint = Checkpoint {
+            width: 3,
+            height: 2,
+            seed: 0xdead_beef,
+            settings: "cornell_box mis".to_string(),
+            pixels,
+            film: Vec::new(),
+            groups: Vec::new(),
+        };
+        let path = std::env::temp_dir().join(format!("rt_test_{}.ckpt", std::process::id()));
+        checkpoint.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        std::fs::write(&path, b"not one").unwrap();
+        let broken = Checkpoint::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), checkpoint);
+        assert!(broken.is_err());
+
+        let filtered = Checkpoint {
+            film: (0..6)
+                .map(|n| Splat {
+                    sum: vect!(n as f64, -0.25, 1e10),
+                    weight: n as f64 * 0.5 - 1.0,
+                })
+                .collect(),
+            ..checkpoint
+        };
+        filtered.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), filtered);
+
+        let robust = Checkpoint {
+            groups: (0..18).map(|n| vect!(n as f64, 2.5, -1e-3)).collect(),
+            ..filtered
+        };
+        robust.save(&path).unwrap();
+        let loaded = Checkpoint::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), robust);
+    }
+
+    #[test]
+    fn test_load_broken() {
+        // sizes that would need gigabytes are turned down before they're used
+        let path = std::env::temp_dir().join(format!("rt_test_broken_{}.ckpt", std::process::id()));
+        let header = |width: i32, height: i32, settings: u32| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend(width.to_le_bytes());
+            bytes.extend(height.to_le_bytes());
+            bytes.extend(7u64.to_le_bytes());
+            bytes.extend(settings.to_le_bytes());
+            bytes
+        };
+        for bytes in [
+            header(4, 4, u32::MAX),
+            header(65535, 65535, 0),
+            header(-3, 2, 0),
+        ] {
+            std::fs::write(&path, bytes).unwrap();
+            let loaded = Checkpoint::load(&path);
+            assert_eq!(loaded.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
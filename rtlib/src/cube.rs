@@ -5,6 +5,7 @@ use crate::{
     materials::MaterialType,
     ray::Ray,
     rectangle::{Axis, Rect},
+    sampler::Sampler,
     vec3::Vec3,
 };
 
@@ -102,6 +103,27 @@ impl Hittable for Cube {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+        self.walls.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.walls.random(origin, sampler)
+    }
+
+    // a glowing cube is sampled one wall at a time
+    fn emitters(&self) -> Vec<Hitters> {
+        self.walls.emitters()
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        self.walls.sample_surface(sampler)
+    }
+
+    fn surface_pdf(&self, p: &Vec3) -> f64 {
+        self.walls.surface_pdf(p)
+    }
 }
 
 impl std::fmt::Display for Cube {
@@ -0,0 +1,289 @@
+// Just enough of zlib (RFC 1950) and deflate (RFC 1951) for PNG files, so we
+// don't need a crate for it. Repeats are found with LZ77 over a 32K window,
+// using hash chains, and everything goes out as one block with the fixed
+// Huffman codes. That doesn't squeeze as hard as zlib's dynamic codes, but
+// renders have plenty of flat areas and repeats for LZ77 to find.
+
+const WINDOW: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// how many earlier places with the same 3 bytes we look at for a match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// deflate packs bits starting from the low end of each byte
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter {
+            out: Vec::new(),
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first, the other way round
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+// the fixed Huffman code for a literal/length symbol
+fn write_literal(w: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => w.write_code(0x30 + symbol, 8),
+        144..=255 => w.write_code(0x190 + symbol - 144, 9),
+        256..=279 => w.write_code(symbol - 256, 7),
+        _ => w.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let l = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_literal(w, 257 + l as u16);
+    w.write(
+        (length - LENGTH_BASE[l] as usize) as u32,
+        LENGTH_EXTRA[l] as u32,
+    );
+    let d = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    w.write_code(d as u32, 5);
+    w.write(
+        (distance - DISTANCE_BASE[d] as usize) as u32,
+        DISTANCE_EXTRA[d] as u32,
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (v.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+// raw deflate data, without the zlib wrapping
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+    // the one and only block, with the fixed codes
+    w.write(1, 1);
+    w.write(1, 2);
+
+    // head[h] is the last place those 3 bytes were seen, prev[i % WINDOW]
+    // the place before that, and so on back through the window
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(&data[i..]);
+            prev[i % WINDOW] = head[h];
+            head[h] = i;
+        }
+    };
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let limit = (data.len() - i).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[i..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && i - candidate <= WINDOW && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[i..i + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, i - candidate);
+                    if length == limit {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW];
+                // the chain only goes back in time, anything else is a slot
+                // that's been reused for a later position
+                if next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+        if best.0 >= MIN_MATCH {
+            write_match(&mut w, best.0, best.1);
+            for j in i..i + best.0 {
+                insert(j, &mut head, &mut prev);
+            }
+            i += best.0;
+        } else {
+            write_literal(&mut w, data[i] as u16);
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    // end of block
+    write_literal(&mut w, 256);
+    w.finish()
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the most bytes before b could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+// deflate data wrapped the way PNG wants it
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32K window, and the check bits that make it a multiple of 31
+    let mut out = vec![0x78, 0x9c];
+    out.extend(deflate(data));
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{adler32, zlib_compress, DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Enough of an inflater to read back what we write, fixed Huffman
+    // blocks only, so the tests don't need anything else.
+    pub(crate) fn zlib_decompress(data: &[u8]) -> Vec<u8> {
+        assert_eq!((data[0] as u32 * 256 + data[1] as u32) % 31, 0);
+        let mut position = 16;
+        let mut bit = |n: u32| -> u32 {
+            let mut v = 0;
+            for k in 0..n {
+                let byte = data[position / 8];
+                v |= ((byte >> (position % 8)) as u32 & 1) << k;
+                position += 1;
+            }
+            v
+        };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = bit(1);
+            assert_eq!(bit(2), 1, "only fixed Huffman blocks");
+            loop {
+                // codes are read a bit at a time, most significant first
+                let mut code = 0;
+                for _ in 0..7 {
+                    code = code << 1 | bit(1);
+                }
+                let symbol = if code <= 0x17 {
+                    code + 256
+                } else {
+                    code = code << 1 | bit(1);
+                    if (0x30..=0xbf).contains(&code) {
+                        code - 0x30
+                    } else if (0xc0..=0xc7).contains(&code) {
+                        code - 0xc0 + 280
+                    } else {
+                        code = code << 1 | bit(1);
+                        code - 0x190 + 144
+                    }
+                };
+                match symbol {
+                    0..=255 => out.push(symbol as u8),
+                    256 => break,
+                    _ => {
+                        let l = (symbol - 257) as usize;
+                        let length = LENGTH_BASE[l] as usize + bit(LENGTH_EXTRA[l] as u32) as usize;
+                        let mut d = 0;
+                        for _ in 0..5 {
+                            d = d << 1 | bit(1);
+                        }
+                        let d = d as usize;
+                        let distance =
+                            DISTANCE_BASE[d] as usize + bit(DISTANCE_EXTRA[d] as u32) as usize;
+                        for _ in 0..length {
+                            out.push(out[out.len() - distance]);
+                        }
+                    }
+                }
+            }
+            if last == 1 {
+                break;
+            }
+        }
+        let end = position.div_ceil(8);
+        let check = u32::from_be_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]);
+        assert_eq!(check, adler32(&out));
+        out
+    }
+
+    #[test]
+    fn test_adler32() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let noise: Vec<u8> = (0..5000).map(|_| rng.gen()).collect();
+        let flat = vec![7u8; 100_000];
+        // repeats further back than the window, and of every length
+        let mut repeats = Vec::new();
+        for n in 0..400 {
+            repeats.extend((0..n % 300).map(|k| (k * 7 + n) as u8));
+            repeats.extend_from_slice(&noise[..n * 11 % 200]);
+        }
+        for data in [&b""[..], b"a", b"abcabcabcabc", &noise, &flat, &repeats] {
+            let compressed = zlib_compress(data);
+            assert_eq!(zlib_decompress(&compressed), data);
+        }
+        // long runs should come out tiny
+        assert!(zlib_compress(&flat).len() < 1000);
+    }
+}
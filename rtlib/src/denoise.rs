@@ -0,0 +1,283 @@
+use crate::adaptive::PixelStats;
+use crate::aov::{Aov, AovPixel};
+use crate::vec3::{Color, Vec3};
+
+// An edge-avoiding à-trous wavelet filter (Dammertz et al. 2010), with the
+// variance guided color weight from SVGF (Schied et al. 2017). Each pass
+// blurs with a 5x5 kernel whose taps are spread twice as far apart as the
+// last pass's, so a handful of passes covers a wide area cheaply. What stops
+// it from blurring everything is the weight each tap gets:
+//  - normal, depth and albedo from the first hit, so it doesn't smear across
+//    the edges of objects or of their textures,
+//  - color, but only for differences bigger than the pixel's noise, which we
+//    know from the samples it got.
+// That's good enough to judge a preview by, it isn't meant for final frames.
+
+const PASSES: usize = 4;
+// B3 spline, the usual à-trous kernel
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// how many standard deviations of noise a color difference can be
+const SIGMA_LUMINANCE: f64 = 2.0;
+// the power the cosine between normals is raised to
+const SIGMA_NORMAL: i32 = 64;
+// how many times the local depth slope a depth difference can be
+const SIGMA_DEPTH: f64 = 4.0;
+const SIGMA_ALBEDO: f64 = 0.3;
+// variance for pixels with too few samples to tell
+const UNKNOWN_VARIANCE: f64 = 1.0;
+
+fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// what the filter needs to know about each pixel
+#[derive(Clone, Copy, Debug, Default)]
+struct Guide {
+    hit: bool,
+    normal: Vec3,
+    depth: f64,
+    // how much the depth changes per pixel across and down
+    slope: (f64, f64),
+    albedo: Color,
+}
+
+fn guides(width: usize, aovs: &[AovPixel]) -> Vec<Guide> {
+    let height = aovs.len() / width;
+    let depth = |x: usize, y: usize| aovs[y * width + x].value(Aov::Depth).x;
+    (0..aovs.len())
+        .map(|index| {
+            let (x, y) = (index % width, index / width);
+            let aov = &aovs[index];
+            let normal = aov.value(Aov::Normal);
+            let length = normal.length();
+            let slope = |a: f64, b: f64, steps: usize| (b - a).abs() / steps.max(1) as f64;
+            Guide {
+                hit: aov.hits() > 0,
+                normal: if length > 0.0 {
+                    normal / length
+                } else {
+                    normal
+                },
+                depth: depth(x, y),
+                slope: (
+                    slope(
+                        depth(x.saturating_sub(1), y),
+                        depth((x + 1).min(width - 1), y),
+                        (x + 1).min(width - 1) - x.saturating_sub(1),
+                    ),
+                    slope(
+                        depth(x, y.saturating_sub(1)),
+                        depth(x, (y + 1).min(height - 1)),
+                        (y + 1).min(height - 1) - y.saturating_sub(1),
+                    ),
+                ),
+                albedo: aov.value(Aov::Albedo),
+            }
+        })
+        .collect()
+}
+
+// how much neighbour q at offset (dx, dy) pixels should count towards p,
+// leaving out the color
+fn edge_weight(p: &Guide, q: &Guide, dx: f64, dy: f64) -> f64 {
+    if p.hit != q.hit {
+        return 0.0;
+    }
+    if !p.hit {
+        // both looking at the background
+        return 1.0;
+    }
+    // samples that hit both sides of something thin can average out to no
+    // normal at all, then the normal can't tell us anything
+    let normal = if p.normal.length_squared() > 0.5 && q.normal.length_squared() > 0.5 {
+        p.normal.dot(&q.normal).max(0.0).powi(SIGMA_NORMAL)
+    } else {
+        1.0
+    };
+    let expected = SIGMA_DEPTH * (p.slope.0 * dx.abs() + p.slope.1 * dy.abs());
+    let depth = (-(p.depth - q.depth).abs() / (expected + 1e-6 * p.depth.max(1.0))).exp();
+    let albedo = (-(p.albedo - q.albedo).length_squared() / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+    normal * depth * albedo
+}
+
+// The denoised color of each pixel, from their samples and the aov pass.
+// Both are in the same order, width pixels to a row.
+pub fn denoise(width: i32, pixels: &[PixelStats], aovs: &[AovPixel]) -> Vec<Color> {
+    let width = width.max(1) as usize;
+    let height = pixels.len() / width;
+    let guides = guides(width, aovs);
+
+    // A NaN from one bad sample would spread to every pixel the filter
+    // touches. write_color() would have written them as black anyway.
+    let finite = |c: Color| c.x.is_finite() && c.y.is_finite() && c.z.is_finite();
+    let mut color: Vec<Color> = pixels
+        .iter()
+        .map(|stats| {
+            if finite(stats.mean()) {
+                stats.mean()
+            } else {
+                Color::default()
+            }
+        })
+        .collect();
+    // the variance of each pixel's mean, which is what the noise in it is
+    let mut variance: Vec<f64> = pixels
+        .iter()
+        .map(|stats| {
+            let v = luminance(&stats.variance()) / stats.count().max(1) as f64;
+            if v.is_finite() && finite(stats.mean()) {
+                v
+            } else {
+                UNKNOWN_VARIANCE
+            }
+        })
+        .collect();
+
+    for pass in 0..PASSES {
+        let step = 1usize << pass;
+        // the variance estimates are noisy themselves, so the color weight
+        // uses a blurred copy
+        let blurred: Vec<f64> = (0..pixels.len())
+            .map(|index| {
+                let (x, y) = ((index % width) as i64, (index / width) as i64);
+                let mut sum = 0.0;
+                let mut total = 0.0;
+                for dy in -1..=1i64 {
+                    for dx in -1..=1i64 {
+                        let (qx, qy) = (x + dx, y + dy);
+                        if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                            continue;
+                        }
+                        let w = KERNEL[(dx + 2) as usize] * KERNEL[(dy + 2) as usize];
+                        sum += w * variance[qy as usize * width + qx as usize];
+                        total += w;
+                    }
+                }
+                sum / total
+            })
+            .collect();
+
+        let (next_color, next_variance): (Vec<Color>, Vec<f64>) = (0..pixels.len())
+            .map(|index| {
+                let (x, y) = ((index % width) as i64, (index / width) as i64);
+                let p = &guides[index];
+                let lp = luminance(&color[index]);
+                let noise = SIGMA_LUMINANCE * blurred[index].sqrt() + 1e-10;
+                let mut sum = Color::default();
+                let mut sum_variance = 0.0;
+                let mut total = 0.0;
+                for (ky, dy) in (-2..=2i64).enumerate() {
+                    for (kx, dx) in (-2..=2i64).enumerate() {
+                        let (qx, qy) = (x + dx * step as i64, y + dy * step as i64);
+                        if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                            continue;
+                        }
+                        let q_index = qy as usize * width + qx as usize;
+                        let lq = luminance(&color[q_index]);
+                        let w = KERNEL[kx]
+                            * KERNEL[ky]
+                            * edge_weight(p, &guides[q_index], (qx - x) as f64, (qy - y) as f64)
+                            * (-(lp - lq).abs() / noise).exp();
+                        sum += w * color[q_index];
+                        sum_variance += w * w * variance[q_index];
+                        total += w;
+                    }
+                }
+                if total > 0.0 {
+                    (sum / total, sum_variance / (total * total))
+                } else {
+                    (color[index], variance[index])
+                }
+            })
+            .unzip();
+        color = next_color;
+        variance = next_variance;
+    }
+    color
+}
+
+#[cfg(test)]
+mod test {
+    use super::denoise;
+    use crate::adaptive::PixelStats;
+    use crate::aov::AovPixel;
+    use crate::hittable::HitRecord;
+    use crate::materials::{Lambertian, MaterialType};
+    use crate::ray::Ray;
+    use crate::vec3::Color;
+    use crate::{color_to_texture, vect};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // a 32x16 picture of a wall facing the camera, the left half grey and
+    // the right half white, rendered with 4 noisy samples a pixel
+    fn wall() -> (Vec<PixelStats>, Vec<AovPixel>, Vec<Color>) {
+        let mut rng = StdRng::seed_from_u64(5);
+        let (width, height) = (32, 16);
+        let mut pixels = Vec::new();
+        let mut aovs = Vec::new();
+        let mut truth = Vec::new();
+        for index in 0..width * height {
+            let albedo = if index % width < width / 2 { 0.5 } else { 1.0 };
+            let material = MaterialType::Lambertian(Lambertian::new(&color_to_texture!(
+                &Color::new(albedo, albedo, albedo)
+            )));
+            let ray = Ray::new(&vect!(0, 0, 0), &vect!(0, 0, -1), None);
+            let mut rec = HitRecord::new(vect!(0, 0, -2), 2.0, material);
+            rec.normal = vect!(0, 0, 1);
+            let mut aov = AovPixel::default();
+            aov.add(&ray, Some(&rec));
+            aovs.push(aov);
+
+            let mut stats = PixelStats::default();
+            for _ in 0..4 {
+                // mean of albedo, but very noisy
+                let s = albedo * 2.0 * rng.gen::<f64>();
+                stats.add(&vect!(s, s, s));
+            }
+            pixels.push(stats);
+            truth.push(vect!(albedo, albedo, albedo));
+        }
+        (pixels, aovs, truth)
+    }
+
+    fn error(colors: &[Color], truth: &[Color]) -> f64 {
+        colors
+            .iter()
+            .zip(truth)
+            .map(|(c, t)| (*c - *t).length_squared())
+            .sum::<f64>()
+            / colors.len() as f64
+    }
+
+    #[test]
+    fn test_denoise() {
+        let (pixels, aovs, truth) = wall();
+        let noisy: Vec<Color> = pixels.iter().map(|stats| stats.mean()).collect();
+        let denoised = denoise(32, &pixels, &aovs);
+
+        assert!(error(&denoised, &truth) < 0.2 * error(&noisy, &truth));
+        // the albedo edge keeps the two halves apart
+        let half = |right: bool| {
+            let sum: f64 = (0..denoised.len())
+                .filter(|index| (index % 32 >= 16) == right)
+                .map(|index| denoised[index].x)
+                .sum();
+            sum / (denoised.len() / 2) as f64
+        };
+        assert!((half(false) - 0.5).abs() < 0.05);
+        assert!((half(true) - 1.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_converged_pixels_kept() {
+        // without any noise there's nothing to take out
+        let (mut pixels, aovs, truth) = wall();
+        for (stats, t) in pixels.iter_mut().zip(&truth) {
+            *stats = PixelStats::default();
+            stats.add(t);
+            stats.add(t);
+        }
+        let denoised = denoise(32, &pixels, &aovs);
+        assert!(error(&denoised, &truth) < 1e-12);
+    }
+}
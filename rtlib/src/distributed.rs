@@ -0,0 +1,776 @@
+use crate::adaptive::PixelStats;
+use crate::checkpoint::{Checkpoint, Tile};
+use crate::film::FilmTile;
+use crate::progress::RenderControl;
+use crate::render::{Renderer, TileJob, TileResult};
+use crate::vec3::Color;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// Rendering one image with more than one process, possibly on more than one
+// machine. A coordinator listens for workers and hands them the render's
+// tiles a pass at a time, the same TileJobs a single process would do, and
+// merges what comes back in the order it was handed out. Every worker builds
+// the scene for itself from the seed the coordinator gives it, and a pixel's
+// samples only depend on the seed, where it is and how many it had before,
+// so the image comes out the same as it would from one process.
+//
+// Workers can come and go. One that goes away, or takes longer than the
+// timeout over its tiles, has them handed to someone else.
+//
+// Everything is little endian, like the checkpoints. The coordinator starts
+// with MAGIC and the seed. The worker answers with MAGIC, how many tiles it
+// wants at once, its image size and the settings string the checkpoint
+// would have, which have to be the coordinator's. Then it's jobs one way
+// and results the other until the coordinator says it's done, or just hangs
+// up.
+
+const MAGIC: &[u8; 8] = b"RTDIST01";
+// from the coordinator
+const ACCEPT: u8 = 1;
+const REJECT: u8 = 2;
+const JOB: u8 = 3;
+const DONE: u8 = 4;
+// from the workers
+const RESULT: u8 = 5;
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    r.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut word = [0u8; 4];
+    r.read_exact(&mut word)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    Ok(read_u32(r)? as i32)
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut word = [0u8; 8];
+    r.read_exact(&mut word)?;
+    Ok(u64::from_le_bytes(word))
+}
+
+fn write_string(w: &mut impl Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let len = read_u32(r)? as usize;
+    if len > 1 << 20 {
+        return Err(invalid("string is too long"));
+    }
+    let mut bytes = vec![0u8; len];
+    r.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|err| invalid(err.to_string()))
+}
+
+fn write_tile(w: &mut impl Write, tile: &Tile) -> io::Result<()> {
+    for v in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        w.write_all(&v.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_tile(r: &mut impl Read) -> io::Result<Tile> {
+    let tile = Tile {
+        x0: read_i32(r)?,
+        y0: read_i32(r)?,
+        x1: read_i32(r)?,
+        y1: read_i32(r)?,
+    };
+    // so working out how many pixels it has can't overflow
+    let size = |from: i32, to: i32| to.checked_sub(from).filter(|n| (0..=1 << 14).contains(n));
+    if size(tile.x0, tile.x1).is_none() || size(tile.y0, tile.y1).is_none() {
+        return Err(invalid("tile is the wrong size"));
+    }
+    Ok(tile)
+}
+
+fn write_pixels(w: &mut impl Write, pixels: &[PixelStats], groups: &[Color]) -> io::Result<()> {
+    for stats in pixels {
+        stats.write_to(w)?;
+    }
+    w.write_all(&(groups.len() as u32).to_le_bytes())?;
+    for sum in groups {
+        for v in [sum.x, sum.y, sum.z] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_pixels(r: &mut impl Read, tile: &Tile) -> io::Result<(Vec<PixelStats>, Vec<Color>)> {
+    if tile.pixels() > 1 << 24 {
+        return Err(invalid("tile is too big"));
+    }
+    let pixels = (0..tile.pixels())
+        .map(|_| PixelStats::read_from(r))
+        .collect::<io::Result<Vec<_>>>()?;
+    let sums = read_u32(r)? as usize;
+    if !sums.is_multiple_of(pixels.len().max(1)) || sums > 64 * pixels.len() {
+        return Err(invalid("tile's groups are the wrong size"));
+    }
+    let mut groups = Vec::with_capacity(sums);
+    let mut v = [0u8; 8];
+    for _ in 0..sums {
+        let mut sum = [0.0; 3];
+        for c in &mut sum {
+            r.read_exact(&mut v)?;
+            *c = f64::from_le_bytes(v);
+        }
+        groups.push(Color::new(sum[0], sum[1], sum[2]));
+    }
+    Ok((pixels, groups))
+}
+
+fn write_job(w: &mut impl Write, id: u64, job: &TileJob) -> io::Result<()> {
+    w.write_all(&[JOB])?;
+    w.write_all(&id.to_le_bytes())?;
+    write_tile(w, &job.tile)?;
+    // no batch is the first pass
+    w.write_all(&job.batch.unwrap_or(-1).to_le_bytes())?;
+    write_pixels(w, &job.pixels, &job.groups)
+}
+
+// after the JOB
+fn read_job(r: &mut impl Read) -> io::Result<(u64, TileJob)> {
+    let id = read_u64(r)?;
+    let tile = read_tile(r)?;
+    let batch = read_i32(r)?;
+    let (pixels, groups) = read_pixels(r, &tile)?;
+    Ok((
+        id,
+        TileJob {
+            tile,
+            batch: (batch >= 0).then_some(batch),
+            pixels,
+            groups,
+        },
+    ))
+}
+
+fn write_result(w: &mut impl Write, id: u64, result: &TileResult) -> io::Result<()> {
+    w.write_all(&[RESULT])?;
+    w.write_all(&id.to_le_bytes())?;
+    write_tile(w, &result.tile)?;
+    write_pixels(w, &result.pixels, &result.groups)?;
+    match &result.film {
+        Some(film) => {
+            w.write_all(&[1])?;
+            film.write_to(w)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+// after the RESULT, the film needs the renderer's filter
+fn read_result(r: &mut impl Read, renderer: &Renderer) -> io::Result<(u64, TileResult)> {
+    let id = read_u64(r)?;
+    let tile = read_tile(r)?;
+    let (pixels, groups) = read_pixels(r, &tile)?;
+    let film = match (read_u8(r)?, renderer.settings().filter) {
+        (0, None) => None,
+        (1, Some(filter)) => Some(FilmTile::read_from(r, filter)?),
+        _ => return Err(invalid("worker's film doesn't match")),
+    };
+    // Anything that doesn't fit the tile would be out of the image when it's
+    // merged, so it's the worker that goes rather than the render.
+    let (width, height) = (renderer.settings().width, renderer.settings().height);
+    if let Some(film) = &film {
+        let filter = renderer.settings().filter.expect("Film has a filter.");
+        if film.bounds() != FilmTile::new(&tile, filter, width, height).bounds() {
+            return Err(invalid("worker's film is in the wrong place"));
+        }
+    }
+    if groups.len() != tile.pixels() * renderer.groups() {
+        return Err(invalid("worker's groups don't match"));
+    }
+    Ok((
+        id,
+        TileResult {
+            tile,
+            pixels,
+            groups,
+            film,
+        },
+    ))
+}
+
+type Log = Box<dyn Fn(&str) + Send + Sync>;
+
+// jobs waiting for a worker, shared by everyone talking to one
+struct Queue {
+    jobs: VecDeque<(u64, TileJob)>,
+    finished: bool,
+    // to hang up on everyone once the render's done
+    streams: Vec<(SocketAddr, TcpStream)>,
+}
+
+pub struct Coordinator {
+    listener: TcpListener,
+    timeout: Option<Duration>,
+    log: Option<Log>,
+}
+
+impl Coordinator {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Coordinator {
+            listener: TcpListener::bind(address)?,
+            timeout: None,
+            log: None,
+        })
+    }
+
+    // workers that take longer than this to send back a tile are given up
+    // on, and their tiles handed out again
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Coordinator {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    // workers joining and leaving, and why
+    pub fn with_log(self, log: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        Coordinator {
+            log: Some(Box::new(log)),
+            ..self
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    fn log(&self, message: &str) {
+        if let Some(log) = &self.log {
+            log(message);
+        }
+    }
+
+    // Like Renderer::render_into, but the tiles are rendered by whichever
+    // workers connect. renderer has to be the same render the workers have,
+    // it's only used to hand out and merge the tiles. With no workers it
+    // waits for some, until control says to stop.
+    pub fn render_into(
+        &self,
+        renderer: &Renderer,
+        state: &mut Checkpoint,
+        control: &RenderControl,
+        saved: &mut dyn FnMut(&Checkpoint),
+    ) -> io::Result<()> {
+        self.listener.set_nonblocking(true)?;
+        let queue = Mutex::new(Queue {
+            jobs: VecDeque::new(),
+            finished: false,
+            streams: Vec::new(),
+        });
+        let ready = Condvar::new();
+        let (results, done) = mpsc::channel();
+        let handshake = (
+            state.seed,
+            state.width,
+            state.height,
+            state.settings.clone(),
+        );
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                while !queue.lock().unwrap().finished {
+                    match self.listener.accept() {
+                        Ok((stream, address)) => {
+                            let (queue, ready, handshake) = (&queue, &ready, &handshake);
+                            let results = results.clone();
+                            s.spawn(move || {
+                                let served =
+                                    self.serve(renderer, stream, handshake, queue, ready, results);
+                                // once it's finished everyone's hung up on anyway
+                                match served {
+                                    _ if queue.lock().unwrap().finished => {}
+                                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                                        self.log(&format!("Worker {} hung up", address))
+                                    }
+                                    Err(err) => {
+                                        self.log(&format!("Worker {} left: {}", address, err))
+                                    }
+                                    Ok(()) => {}
+                                }
+                            });
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(50));
+                        }
+                        Err(err) => self.log(&format!("Couldn't accept a worker: {}", err)),
+                    }
+                }
+            });
+
+            let mut progress = renderer.progress(state, control);
+            let mut jobs = renderer.first_pass(state);
+            let mut next_id = 0;
+            while !jobs.is_empty() {
+                // Results are merged in the order the jobs were handed out,
+                // the ones that come back early wait their turn.
+                let (first, last) = (next_id, next_id + jobs.len() as u64);
+                next_id = last;
+                queue.lock().unwrap().jobs.extend((first..last).zip(jobs));
+                ready.notify_all();
+                let mut waiting = BTreeMap::new();
+                let mut next = first;
+                while next < last {
+                    match done.recv_timeout(Duration::from_millis(100)) {
+                        Ok((id, result)) => {
+                            if (next..last).contains(&id) {
+                                waiting.insert(id, result);
+                            }
+                            while let Some(result) = waiting.remove(&next) {
+                                let (samples, finished) = renderer.merge(state, result);
+                                progress.samples_done += samples;
+                                progress.pixels_done += finished;
+                                next += 1;
+                            }
+                            progress.elapsed = control.progress_elapsed();
+                            control.report(&progress);
+                            saved(state);
+                        }
+                        Err(RecvTimeoutError::Timeout) if !control.should_stop() => {}
+                        Err(_) => break,
+                    }
+                }
+                if next < last {
+                    break;
+                }
+                jobs = renderer.adaptive_pass(state);
+            }
+
+            // Everyone waiting for a job is told it's done, and anyone still
+            // working on one is hung up on.
+            let mut queue = queue.lock().unwrap();
+            queue.finished = true;
+            queue.jobs.clear();
+            for (_, stream) in &queue.streams {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            ready.notify_all();
+        });
+        Ok(())
+    }
+
+    // Talks to one worker, handing it jobs until the render's done. Whatever
+    // it had when it went away goes back in the queue for someone else.
+    fn serve(
+        &self,
+        renderer: &Renderer,
+        stream: TcpStream,
+        (seed, width, height, settings): &(u64, i32, i32, String),
+        queue: &Mutex<Queue>,
+        ready: &Condvar,
+        results: mpsc::Sender<(u64, TileResult)>,
+    ) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        let address = stream.peer_addr()?;
+        {
+            let mut queue = queue.lock().unwrap();
+            if queue.finished {
+                return Ok(());
+            }
+            queue.streams.push((address, stream.try_clone()?));
+        }
+
+        let mut in_flight: Vec<(u64, TileJob)> = Vec::new();
+        let served = (|| -> io::Result<()> {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = BufWriter::new(stream.try_clone()?);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&seed.to_le_bytes())?;
+            writer.flush()?;
+
+            // the worker builds its scene before it answers, which can take a while
+            let mut magic = [0u8; 8];
+            reader.read_exact(&mut magic)?;
+            if &magic != MAGIC {
+                return Err(invalid("not a worker"));
+            }
+            let wanted = read_u32(&mut reader)?.clamp(1, 1024) as usize;
+            let (their_width, their_height) = (read_i32(&mut reader)?, read_i32(&mut reader)?);
+            let theirs = read_string(&mut reader)?;
+            if (their_width, their_height, &theirs) != (*width, *height, settings) {
+                let why = format!(
+                    "it's rendering something else.\n  It has: {}x{} {}\n  This is: {}x{} {}",
+                    their_width, their_height, theirs, width, height, settings
+                );
+                writer.write_all(&[REJECT])?;
+                write_string(&mut writer, &why)?;
+                writer.flush()?;
+                return Err(invalid(why));
+            }
+            writer.write_all(&[ACCEPT])?;
+            writer.flush()?;
+            stream.set_read_timeout(self.timeout)?;
+            self.log(&format!(
+                "Worker {} joined, {} tiles at a time",
+                address, wanted
+            ));
+
+            loop {
+                // Keeps the worker topped up with as many jobs as it wants,
+                // then waits for one of them to come back.
+                let mut new = Vec::new();
+                {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        if queue.finished {
+                            drop(queue);
+                            writer.write_all(&[DONE])?;
+                            return writer.flush();
+                        }
+                        while in_flight.len() + new.len() < wanted {
+                            match queue.jobs.pop_front() {
+                                Some(job) => new.push(job),
+                                None => break,
+                            }
+                        }
+                        if !in_flight.is_empty() || !new.is_empty() {
+                            break;
+                        }
+                        queue = ready.wait(queue).unwrap();
+                    }
+                }
+                let sent = in_flight.len();
+                in_flight.extend(new);
+                for (id, job) in &in_flight[sent..] {
+                    write_job(&mut writer, *id, job)?;
+                }
+                writer.flush()?;
+
+                if read_u8(&mut reader)? != RESULT {
+                    return Err(invalid("expected a result"));
+                }
+                let (id, result) = read_result(&mut reader, renderer)?;
+                let n = in_flight
+                    .iter()
+                    .position(|(job_id, job)| *job_id == id && job.tile == result.tile)
+                    .ok_or_else(|| invalid("sent back a tile it wasn't given"))?;
+                let (_, job) = &in_flight[n];
+                let mut counts = job.pixels.iter().zip(&result.pixels);
+                if counts.any(|(before, after)| after.count() < before.count()) {
+                    return Err(invalid("sent back fewer samples than it was given"));
+                }
+                in_flight.remove(n);
+                // the render might have stopped already
+                let _ = results.send((id, result));
+            }
+        })();
+        // A worker that's given up on is hung up on too, so one that was
+        // only slow doesn't carry on with tiles someone else now has.
+        if served.is_err() {
+            let _ = stream.shutdown(Shutdown::Both);
+            let mut queue = queue.lock().unwrap();
+            queue.streams.retain(|(other, _)| *other != address);
+            for job in in_flight.into_iter().rev() {
+                queue.jobs.push_front(job);
+            }
+            ready.notify_all();
+        }
+        served
+    }
+}
+
+// The other end, rendering tiles for a coordinator.
+pub struct Worker {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    seed: u64,
+}
+
+impl Worker {
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a coordinator"));
+        }
+        let seed = read_u64(&mut reader)?;
+        Ok(Worker {
+            stream,
+            reader,
+            seed,
+        })
+    }
+
+    // the scene has to be built from this, so it's the same as everyone else's
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Renders tiles, as many at a time as there are threads, until the
+    // coordinator says it's done or hangs up. settings is the settings
+    // string a checkpoint of this render would have, the coordinator turns
+    // away workers that aren't doing the same render as it. Gives back how
+    // many tiles it did.
+    pub fn run(mut self, renderer: &Renderer, settings: &str) -> io::Result<usize> {
+        let mut writer = BufWriter::new(self.stream.try_clone()?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(rayon::current_num_threads() as u32).to_le_bytes())?;
+        writer.write_all(&renderer.settings().width.to_le_bytes())?;
+        writer.write_all(&renderer.settings().height.to_le_bytes())?;
+        write_string(&mut writer, settings)?;
+        writer.flush()?;
+        match read_u8(&mut self.reader)? {
+            ACCEPT => {}
+            REJECT => {
+                return Err(invalid(format!(
+                    "The coordinator turned us away, {}",
+                    read_string(&mut self.reader)?
+                )))
+            }
+            _ => return Err(invalid("not a coordinator")),
+        }
+
+        let control = RenderControl::new();
+        let (finished, tiles_done) = mpsc::channel::<(u64, TileResult)>();
+        std::thread::scope(|s| {
+            let sender = s.spawn(move || -> io::Result<()> {
+                for (id, result) in tiles_done {
+                    write_result(&mut writer, id, &result)?;
+                    writer.flush()?;
+                }
+                Ok(())
+            });
+            let mut tiles = 0;
+            let received = rayon::in_place_scope(|pool| loop {
+                let kind = match read_u8(&mut self.reader) {
+                    Ok(kind) => kind,
+                    // hanging up is as good as saying it's done
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    Err(err) => return Err(err),
+                };
+                match kind {
+                    JOB => {
+                        let (id, job) = read_job(&mut self.reader)?;
+                        let (finished, control) = (finished.clone(), &control);
+                        pool.spawn(move |_| {
+                            let _ = finished.send((id, renderer.render_tile(job, control)));
+                        });
+                        tiles += 1;
+                    }
+                    DONE => return Ok(()),
+                    _ => return Err(invalid("unexpected message")),
+                }
+            });
+            drop(finished);
+            let sent = sender.join().unwrap();
+            received.and(sent).map(|_| tiles)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        read_job, read_u8, write_pixels, write_tile, Coordinator, Worker, ACCEPT, JOB, MAGIC,
+        RESULT,
+    };
+    use crate::camera::Camera;
+    use crate::film::Splat;
+    use crate::film::{Filter, FilterKind};
+    use crate::progress::RenderControl;
+    use crate::render::{RenderSettings, Renderer};
+    use crate::util::cornell_box;
+    use crate::vect;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    fn cornell(seed: u64) -> Renderer {
+        let camera = Camera::new(
+            vect!(278, 273, -800),
+            vect!(278, 273, 0),
+            vect!(0, 1, 0),
+            40.0,
+            1.5,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+        let settings = RenderSettings {
+            samples: 6,
+            max_depth: 6,
+            seed,
+            noise_threshold: 0.2,
+            min_samples: 2,
+            max_samples: 16,
+            tile_size: 5,
+            filter: Some(Filter::new(FilterKind::Gaussian, 1.5)),
+            ..RenderSettings::new(24, 16)
+        };
+        Renderer::new(cornell_box(), camera, settings)
+    }
+
+    #[test]
+    fn test_distributed() {
+        let coordinator = Coordinator::bind("127.0.0.1:0").unwrap();
+        let address = coordinator.local_addr().unwrap();
+        let renderer = cornell(7);
+        let mut state = renderer.checkpoint(7, "test".to_string());
+        let control = RenderControl::new().with_time_budget(Duration::from_secs(60));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                coordinator
+                    .render_into(&renderer, &mut state, &control, &mut |_| {})
+                    .unwrap()
+            });
+
+            // turned away for doing something else
+            let worker = Worker::connect(address).unwrap();
+            let other = cornell(worker.seed());
+            assert!(worker.run(&other, "something else").is_err());
+
+            // takes two tiles and disappears with them
+            let mut flaky = std::net::TcpStream::connect(address).unwrap();
+            let mut hello = [0u8; 16];
+            flaky.read_exact(&mut hello).unwrap();
+            assert_eq!(&hello[..8], MAGIC);
+            flaky.write_all(MAGIC).unwrap();
+            for v in [2, 24, 16] {
+                flaky.write_all(&(v as u32).to_le_bytes()).unwrap();
+            }
+            flaky.write_all(&4u32.to_le_bytes()).unwrap();
+            flaky.write_all(b"test").unwrap();
+            assert_eq!(read_u8(&mut flaky).unwrap(), ACCEPT);
+            assert_eq!(read_u8(&mut flaky).unwrap(), JOB);
+            drop(flaky);
+
+            let workers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(move || {
+                        let worker = Worker::connect(address).unwrap();
+                        let renderer = cornell(worker.seed());
+                        worker.run(&renderer, "test").unwrap()
+                    })
+                })
+                .collect();
+            let tiles: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+            assert!(tiles >= 24);
+        });
+        assert!(!control.should_stop());
+        // the same as doing it all here
+        let here = renderer.render(&RenderControl::new());
+        assert_eq!(renderer.image(&state), here);
+    }
+
+    #[test]
+    fn test_timed_out_worker_hung_up_on() {
+        let coordinator = Coordinator::bind("127.0.0.1:0")
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        let address = coordinator.local_addr().unwrap();
+        let renderer = cornell(3);
+        let mut state = renderer.checkpoint(3, "test".to_string());
+        let control = RenderControl::new().with_time_budget(Duration::from_secs(60));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                coordinator
+                    .render_into(&renderer, &mut state, &control, &mut |_| {})
+                    .unwrap()
+            });
+
+            // takes two tiles and never sends them back
+            let mut slow = std::net::TcpStream::connect(address).unwrap();
+            let mut hello = [0u8; 16];
+            slow.read_exact(&mut hello).unwrap();
+            slow.write_all(MAGIC).unwrap();
+            for v in [2, 24, 16] {
+                slow.write_all(&(v as u32).to_le_bytes()).unwrap();
+            }
+            slow.write_all(&4u32.to_le_bytes()).unwrap();
+            slow.write_all(b"test").unwrap();
+            assert_eq!(read_u8(&mut slow).unwrap(), ACCEPT);
+            // nobody else is working, so only the timeout can hang up on it
+            slow.set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut jobs = Vec::new();
+            slow.read_to_end(&mut jobs).unwrap();
+            assert_eq!(jobs[0], JOB);
+
+            let worker = Worker::connect(address).unwrap();
+            let renderer = cornell(worker.seed());
+            worker.run(&renderer, "test").unwrap();
+        });
+        assert!(!control.should_stop());
+        let here = renderer.render(&RenderControl::new());
+        assert_eq!(renderer.image(&state), here);
+    }
+
+    #[test]
+    fn test_bad_result_drops_worker() {
+        let coordinator = Coordinator::bind("127.0.0.1:0").unwrap();
+        let address = coordinator.local_addr().unwrap();
+        let renderer = cornell(5);
+        let mut state = renderer.checkpoint(5, "test".to_string());
+        let control = RenderControl::new().with_time_budget(Duration::from_secs(60));
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                coordinator
+                    .render_into(&renderer, &mut state, &control, &mut |_| {})
+                    .unwrap()
+            });
+
+            // sends back a film that's off the side of the image
+            let mut bad = std::net::TcpStream::connect(address).unwrap();
+            let mut hello = [0u8; 16];
+            bad.read_exact(&mut hello).unwrap();
+            bad.write_all(MAGIC).unwrap();
+            for v in [1, 24, 16] {
+                bad.write_all(&(v as u32).to_le_bytes()).unwrap();
+            }
+            bad.write_all(&4u32.to_le_bytes()).unwrap();
+            bad.write_all(b"test").unwrap();
+            assert_eq!(read_u8(&mut bad).unwrap(), ACCEPT);
+            assert_eq!(read_u8(&mut bad).unwrap(), JOB);
+            let (id, job) = read_job(&mut bad).unwrap();
+            bad.write_all(&[RESULT]).unwrap();
+            bad.write_all(&id.to_le_bytes()).unwrap();
+            write_tile(&mut bad, &job.tile).unwrap();
+            write_pixels(&mut bad, &job.pixels, &job.groups).unwrap();
+            bad.write_all(&[1]).unwrap();
+            let (w, h) = (job.tile.x1 - job.tile.x0, job.tile.y1 - job.tile.y0);
+            for v in [24, 0, 24 + w, h] {
+                bad.write_all(&v.to_le_bytes()).unwrap();
+            }
+            for _ in 0..w * h {
+                Splat::default().write_to(&mut bad).unwrap();
+            }
+            // hung up on rather than taking the coordinator down with it
+            bad.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            let mut rest = Vec::new();
+            bad.read_to_end(&mut rest).unwrap();
+
+            let worker = Worker::connect(address).unwrap();
+            let renderer = cornell(worker.seed());
+            worker.run(&renderer, "test").unwrap();
+        });
+        assert!(!control.should_stop());
+        let here = renderer.render(&RenderControl::new());
+        assert_eq!(renderer.image(&state), here);
+    }
+}
@@ -0,0 +1,373 @@
+use crate::framebuffer::Framebuffer;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::sky::Sky;
+use crate::util::{uv_for_sphere, Image};
+use crate::vec3::{unit_vector, Color, Vec3};
+use std::f64::consts::PI;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// Light from far away, all around the scene, from an equirectangular image
+// (longitude across, latitude down, the way sky panoramas are stored). A
+// direction gets looked up with uv_for_sphere(), the same mapping the earth
+// texture uses, so an image that looks right on a sphere looks right here.
+//
+// A small bright sun in a big sky is hard to find by bouncing around, so the
+// integrators that sample lights can also pick directions from the image, the
+// brighter a pixel the more likely. See sample() and pdf().
+#[derive(Clone, Debug)]
+pub struct Environment {
+    width: usize,
+    height: usize,
+    // rows go top to bottom, like Framebuffer
+    pixels: Vec<Color>,
+    // radians, around the y axis
+    rotation: f64,
+    intensity: f64,
+    // for picking a pixel: which row, and then which pixel in that row. Both
+    // are running totals that end at 1.
+    rows: Vec<f64>,
+    columns: Vec<f64>,
+    // the weight of each pixel divided by the weight of them all
+    weights: Vec<f64>,
+}
+
+fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// turns weights into a running total that ends at 1, and hands back the total
+fn running_total(weights: &[f64]) -> (Vec<f64>, f64) {
+    let total: f64 = weights.iter().sum();
+    let mut sum = 0.0;
+    let cdf = weights
+        .iter()
+        .map(|w| {
+            sum += w;
+            sum / total
+        })
+        .collect();
+    (cdf, total)
+}
+
+// which entry of a running total u lands on, and how far into it
+fn pick(cdf: &[f64], u: f64) -> (usize, f64) {
+    let i = cdf.partition_point(|&c| c <= u).min(cdf.len() - 1);
+    let below = if i == 0 { 0.0 } else { cdf[i - 1] };
+    let width = cdf[i] - below;
+    let within = if width > 0.0 {
+        (u - below) / width
+    } else {
+        0.5
+    };
+    (i, within.clamp(0.0, 1.0 - f64::EPSILON))
+}
+
+fn rotate_y(v: &Vec3, angle: f64) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    Vec3::new(cos * v.x + sin * v.z, v.y, -sin * v.x + cos * v.z)
+}
+
+impl Environment {
+    // width x height pixels, rows top to bottom
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        assert!(width > 0 && height > 0, "an environment needs pixels");
+        assert_eq!(pixels.len(), width * height);
+        // rows near the poles are squashed into less of the sphere, so they
+        // get picked less
+        let latitude = |row: usize| (0.5 - (row as f64 + 0.5) / height as f64) * PI;
+        let mut weights: Vec<f64> = pixels
+            .iter()
+            .enumerate()
+            .map(|(i, c)| luminance(c).max(0.0) * latitude(i / width).cos())
+            .collect();
+        if weights.iter().sum::<f64>() <= 0.0 {
+            // all black, spread evenly over the sphere
+            for (i, w) in weights.iter_mut().enumerate() {
+                *w = latitude(i / width).cos();
+            }
+        }
+        let row_totals: Vec<f64> = weights.chunks(width).map(|row| row.iter().sum()).collect();
+        let (rows, total) = running_total(&row_totals);
+        let mut columns = Vec::with_capacity(weights.len());
+        for row in weights.chunks(width) {
+            if row.iter().sum::<f64>() > 0.0 {
+                columns.extend(running_total(row).0);
+            } else {
+                // never picked, but keep the rows lined up
+                columns.extend((1..=width).map(|x| x as f64 / width as f64));
+            }
+        }
+        for w in weights.iter_mut() {
+            *w /= total;
+        }
+        Environment {
+            width,
+            height,
+            pixels,
+            rotation: 0.0,
+            intensity: 1.0,
+            rows,
+            columns,
+            weights,
+        }
+    }
+
+    pub fn from_framebuffer(fb: &Framebuffer) -> Self {
+        Environment::new(
+            fb.width() as usize,
+            fb.height() as usize,
+            fb.pixels().to_vec(),
+        )
+    }
+
+    // an ordinary 8 bit image, taken as linear like the image textures are
+    pub fn from_image(image: &Image) -> Self {
+        let mut pixels = Vec::with_capacity((image.nx * image.ny) as usize);
+        for row in 0..image.ny {
+            for x in 0..image.nx {
+                // get() counts rows up from the bottom
+                pixels.push(image.get(x, image.ny - 1 - row).unwrap_or_default());
+            }
+        }
+        Environment::new(image.nx as usize, image.ny as usize, pixels)
+    }
+
+    // .hdr and .pfm keep everything brighter than white, anything else is
+    // loaded as an Image
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hdr") | Some("rgbe") | Some("pfm") => {
+                Ok(Environment::from_framebuffer(&Framebuffer::load(path)?))
+            }
+            _ => {
+                // Image::new() panics on a missing file
+                std::fs::metadata(path)?;
+                Ok(Environment::from_image(&Image::new(&path.display())))
+            }
+        }
+    }
+
+    // turns the image around the vertical axis, in degrees
+    pub fn with_rotation(mut self, degrees: f64) -> Self {
+        self.rotation = degrees.to_radians();
+        self
+    }
+
+    // multiplies all of the light
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    fn pixel(&self, direction: &Vec3) -> usize {
+        let uv = uv_for_sphere(&rotate_y(&unit_vector(direction), -self.rotation));
+        let x = ((uv.u * self.width as f64) as usize).min(self.width - 1);
+        let y = (((1.0 - uv.v) * self.height as f64) as usize).min(self.height - 1);
+        y * self.width + x
+    }
+
+    // the light coming from direction
+    pub fn value(&self, direction: &Vec3) -> Color {
+        self.pixels[self.pixel(direction)] * self.intensity
+    }
+
+    // A direction picked by how bright the pixels are, and the pdf of having
+    // picked it (per solid angle). None for the odd direction right at a pole.
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, f64)> {
+        let (u1, u2) = sampler.get_2d();
+        let (row, v) = pick(&self.rows, u1);
+        let start = row * self.width;
+        let (column, u) = pick(&self.columns[start..start + self.width], u2);
+        // back from where in the image to a direction, uv_for_sphere() in reverse
+        let u = (column as f64 + u) / self.width as f64;
+        let v = 1.0 - (row as f64 + v) / self.height as f64;
+        let phi = PI - 2.0 * PI * u;
+        let theta = (v - 0.5) * PI;
+        let direction = Vec3::new(
+            theta.cos() * phi.cos(),
+            theta.sin(),
+            theta.cos() * phi.sin(),
+        );
+        let pdf = self.pdf_pixel(start + column, theta.cos());
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((rotate_y(&direction, self.rotation), pdf))
+    }
+
+    // the chance of sample() picking direction
+    pub fn pdf(&self, direction: &Vec3) -> f64 {
+        let cos_latitude = (1.0 - unit_vector(direction).y.powi(2)).max(0.0).sqrt();
+        self.pdf_pixel(self.pixel(direction), cos_latitude)
+    }
+
+    fn pdf_pixel(&self, index: usize, cos_latitude: f64) -> f64 {
+        if cos_latitude <= 0.0 {
+            return 0.0;
+        }
+        // a pixel is 2pi/width by pi/height of longitude and latitude, and
+        // the solid angle of that shrinks with the cosine of the latitude
+        self.weights[index] * (self.width * self.height) as f64 / (2.0 * PI * PI * cos_latitude)
+    }
+}
+
+// What a ray that leaves the scene sees.
+#[derive(Clone, Debug)]
+pub enum Background {
+    // The interior light, the same everywhere. It's squared (see color()),
+    // and it's also added when a path stops without getting out, which is
+    // how the scenes have always been lit.
+    Constant(Color),
+    Environment(Arc<Environment>),
+    // a daylight sky and sun, see sky.rs
+    Sky(Arc<Sky>),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Constant(Color::default())
+    }
+}
+
+impl Background {
+    // the light a ray that didn't hit anything brings back
+    pub fn escaped(&self, ray: &Ray) -> Color {
+        match self {
+            Background::Constant(c) => *c * *c,
+            Background::Environment(env) => env.value(&ray.direction()),
+            Background::Sky(sky) => sky.value(&ray.direction()),
+        }
+    }
+
+    // what gets added when a path ends on something, without escaping
+    pub fn ended(&self) -> Color {
+        match self {
+            Background::Constant(c) => *c * *c,
+            Background::Environment(_) | Background::Sky(_) => Color::default(),
+        }
+    }
+
+    // whether the integrators can sample it like a light
+    pub fn is_sampled(&self) -> bool {
+        !matches!(self, Background::Constant(_))
+    }
+
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, f64)> {
+        match self {
+            Background::Constant(_) => None,
+            Background::Environment(env) => env.sample(sampler),
+            Background::Sky(sky) => sky.sample(sampler),
+        }
+    }
+
+    pub fn pdf(&self, direction: &Vec3) -> f64 {
+        match self {
+            Background::Constant(_) => 0.0,
+            Background::Environment(env) => env.pdf(direction),
+            Background::Sky(sky) => sky.pdf(direction),
+        }
+    }
+}
+
+impl From<Color> for Background {
+    fn from(c: Color) -> Self {
+        Background::Constant(c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Environment;
+    use crate::sampler::{IndependentSampler, Sampler};
+    use crate::vec3::Color;
+    use crate::vect;
+    use std::f64::consts::PI;
+
+    // dark, with one bright pixel for a sun
+    fn sunny() -> Environment {
+        let (width, height) = (64, 32);
+        let mut pixels = vec![vect!(0.1, 0.2, 0.4); width * height];
+        pixels[10 * width + 40] = vect!(5000, 5000, 4000);
+        Environment::new(width, height, pixels)
+    }
+
+    // how much of the sphere a pixel in the row covers
+    fn solid_angle(env: &Environment, row: usize) -> f64 {
+        let latitude = (0.5 - (row as f64 + 0.5) / env.height as f64) * PI;
+        2.0 * PI * PI / (env.width * env.height) as f64 * latitude.cos()
+    }
+
+    #[test]
+    fn test_lookup() {
+        // the first pixel of the top row, and the one under it
+        let mut pixels = vec![Color::default(); 4 * 2];
+        pixels[0] = vect!(1, 0, 0);
+        pixels[4] = vect!(0, 1, 0);
+        let env = Environment::new(4, 2, pixels);
+        assert_eq!(env.value(&vect!(-1, 0.1, 1)), vect!(1, 0, 0));
+        assert_eq!(env.value(&vect!(-1, -0.1, 1)), vect!(0, 1, 0));
+        assert_eq!(env.value(&vect!(1, 0.1, -1)), vect!(0, 0, 0));
+        // turned half way around, it's on the other side
+        let turned = env.with_rotation(180.0).with_intensity(2.0);
+        assert_eq!(turned.value(&vect!(1, 0.1, -1)), vect!(2, 0, 0));
+    }
+
+    #[test]
+    fn test_sampling() {
+        for env in [sunny(), sunny().with_rotation(70.0)] {
+            // the pdf adds up to 1 over the sphere
+            let total: f64 = (0..env.height)
+                .map(|row| {
+                    (0..env.width)
+                        .map(|x| env.weights[row * env.width + x])
+                        .sum::<f64>()
+                })
+                .sum();
+            assert!((total - 1.0).abs() < 1e-9);
+
+            let mut sampler = IndependentSampler::new(3);
+            sampler.start_pixel_sample(0, 0, 0);
+            let m = 20_000;
+            let mut sun = 0;
+            let mut estimate = Color::default();
+            for _ in 0..m {
+                // directions land where pdf() says they came from
+                let (d, pdf) = env.sample(&mut sampler).unwrap();
+                assert!((d.length() - 1.0).abs() < 1e-9);
+                assert!(
+                    (env.pdf(&d) - pdf).abs() < 1e-6 * pdf,
+                    "{} {}",
+                    env.pdf(&d),
+                    pdf
+                );
+                if env.value(&d).x > 100.0 {
+                    sun += 1;
+                }
+                estimate += env.value(&d) / pdf;
+            }
+            // one pixel of sun has most of the light, so most samples go there
+            assert!(sun > m / 2, "{}", sun);
+            // and all the light together is what the pixels add up to
+            let mut exact = Color::default();
+            for row in 0..env.height {
+                for x in 0..env.width {
+                    exact += env.pixels[row * env.width + x] * solid_angle(&env, row);
+                }
+            }
+            let estimate = estimate / m as f64;
+            assert!(
+                (estimate - exact).length() < 0.02 * exact.length(),
+                "{} {}",
+                estimate,
+                exact
+            );
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use crate::checkpoint::Tile;
+use crate::vec3::Color;
+use std::f64::consts::PI;
+use std::io::{self, Read, Write};
+
+// Averaging the samples that land in a pixel is a box filter the size of the
+// pixel, which lets thin things alias. Here each sample is instead added to
+// every pixel within the filter's radius of it, weighted by how far it is
+// from the pixel's center, and a pixel ends up as its weighted sum over the
+// sum of the weights.
+//
+// Samples spill over into the pixels around them, so tiles rendered in
+// parallel would step on each other. Each tile splats into a FilmTile of its
+// own, big enough for the spill, and those get added to the film one at a
+// time in the same order every run, so the sums come out the same no matter
+// which thread finished first.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FilterKind {
+    #[default]
+    Box,
+    // falls off in a straight line, also called triangle or bilinear
+    Tent,
+    Gaussian,
+    // Mitchell-Netravali with B = C = 1/3, slightly sharpening
+    Mitchell,
+    // windowed sinc, the sharpest, but it rings around hard edges
+    Lanczos,
+}
+
+impl FilterKind {
+    // in pixels, what each one is usually used with
+    pub fn default_radius(&self) -> f64 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+impl std::str::FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" | "triangle" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(format!(
+                "unknown filter '{}', expected one of: box, tent, gaussian, mitchell, lanczos",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterKind::Box => write!(f, "box"),
+            FilterKind::Tent => write!(f, "tent"),
+            FilterKind::Gaussian => write!(f, "gaussian"),
+            FilterKind::Mitchell => write!(f, "mitchell"),
+            FilterKind::Lanczos => write!(f, "lanczos"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    // how far a sample reaches, in pixels
+    pub radius: f64,
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn mitchell(x: f64) -> f64 {
+    const B: f64 = 1.0 / 3.0;
+    const C: f64 = 1.0 / 3.0;
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * B - 6.0 * C) * x * x * x
+            + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+            + (6.0 - 2.0 * B))
+            / 6.0
+    } else if x < 2.0 {
+        ((-B - 6.0 * C) * x * x * x
+            + (6.0 * B + 30.0 * C) * x * x
+            + (-12.0 * B - 48.0 * C) * x
+            + (8.0 * B + 24.0 * C))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: f64) -> Self {
+        Filter { kind, radius }
+    }
+
+    // across one axis, x pixels from the center
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let r = self.radius;
+        match self.kind {
+            // half open, so a sample on the line between two pixels only
+            // goes to one of them
+            FilterKind::Box => {
+                if (-r..r).contains(&x) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            FilterKind::Tent => (1.0 - x.abs() / r).max(0.0),
+            FilterKind::Gaussian => {
+                // a standard deviation of half a pixel, shifted down so it
+                // reaches 0 at the radius instead of stopping with a step
+                const ALPHA: f64 = 2.0;
+                ((-ALPHA * x * x).exp() - (-ALPHA * r * r).exp()).max(0.0)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * x / r),
+            FilterKind::Lanczos => {
+                if x.abs() < r {
+                    sinc(x) * sinc(x / r)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    // the weight of a sample (dx, dy) pixels from a pixel's center. Mitchell
+    // and Lanczos go negative a little way out.
+    pub fn evaluate(&self, dx: f64, dy: f64) -> f64 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.kind, self.radius)
+    }
+}
+
+// the weighted samples a pixel has gathered
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Splat {
+    pub sum: Color,
+    pub weight: f64,
+}
+
+impl Splat {
+    pub fn add(&mut self, other: &Splat) {
+        self.sum += other.sum;
+        self.weight += other.weight;
+    }
+
+    // The negative lobes of Mitchell and Lanczos can leave the weights summing
+    // to less than 0 where samples are sparse, which still divides out fine.
+    // Black if nothing reached it at all.
+    pub fn color(&self) -> Color {
+        if self.weight != 0.0 {
+            self.sum / self.weight
+        } else {
+            Color::default()
+        }
+    }
+
+    // for checkpoints, little endian like PixelStats
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        for v in [self.sum.x, self.sum.y, self.sum.z, self.weight] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut v = [0.0; 4];
+        for x in v.iter_mut() {
+            let mut bytes = [0u8; 8];
+            r.read_exact(&mut bytes)?;
+            *x = f64::from_le_bytes(bytes);
+        }
+        Ok(Splat {
+            sum: Color::new(v[0], v[1], v[2]),
+            weight: v[3],
+        })
+    }
+}
+
+// What the samples from one tile add to the film: the tile and as much
+// around it as the filter reaches, cut off at the edges of the image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilmTile {
+    filter: Filter,
+    bounds: Tile,
+    splats: Vec<Splat>,
+}
+
+impl FilmTile {
+    pub fn new(tile: &Tile, filter: Filter, width: i32, height: i32) -> Self {
+        let reach = filter.radius.ceil() as i32;
+        let bounds = Tile {
+            x0: (tile.x0 - reach).max(0),
+            y0: (tile.y0 - reach).max(0),
+            x1: (tile.x1 + reach).min(width),
+            y1: (tile.y1 + reach).min(height),
+        };
+        let size = (bounds.x1 - bounds.x0).max(0) * (bounds.y1 - bounds.y0).max(0);
+        FilmTile {
+            filter,
+            bounds,
+            splats: vec![Splat::default(); size as usize],
+        }
+    }
+
+    // x across from the left and y down from the top, in pixels, so pixel
+    // (0, 0) is the square from (0, 0) to (1, 1)
+    pub fn add_sample(&mut self, x: f64, y: f64, color: &Color) {
+        // one bad sample would spread to all its neighbours, write_color()
+        // only ever blacked out the pixel it was in
+        if !(color.x.is_finite() && color.y.is_finite() && color.z.is_finite()) {
+            return;
+        }
+        let r = self.filter.radius;
+        let b = self.bounds;
+        let px0 = ((x - 0.5 - r).ceil() as i32).max(b.x0);
+        let px1 = ((x - 0.5 + r).floor() as i32).min(b.x1 - 1);
+        let py0 = ((y - 0.5 - r).ceil() as i32).max(b.y0);
+        let py1 = ((y - 0.5 + r).floor() as i32).min(b.y1 - 1);
+        for py in py0..=py1 {
+            for px in px0..=px1 {
+                let w = self
+                    .filter
+                    .evaluate(x - (px as f64 + 0.5), y - (py as f64 + 0.5));
+                if w != 0.0 {
+                    let splat =
+                        &mut self.splats[((py - b.y0) * (b.x1 - b.x0) + px - b.x0) as usize];
+                    splat.sum += w * *color;
+                    splat.weight += w;
+                }
+            }
+        }
+    }
+
+    // the pixels it splats into, the tile and as far as the filter reaches
+    pub fn bounds(&self) -> Tile {
+        self.bounds
+    }
+
+    // adds it to the whole image's splats, `width` pixels to a row
+    pub fn merge_into(&self, film: &mut [Splat], width: i32) {
+        for (index, splat) in self.bounds.indices(width).zip(&self.splats) {
+            film[index].add(splat);
+        }
+    }
+
+    // For sending a tile back from another process. The filter isn't sent,
+    // both ends already know it.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        let b = self.bounds;
+        for v in [b.x0, b.y0, b.x1, b.y1] {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        for splat in &self.splats {
+            splat.write_to(w)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read, filter: Filter) -> io::Result<Self> {
+        let mut v = [0; 4];
+        for x in v.iter_mut() {
+            let mut bytes = [0u8; 4];
+            r.read_exact(&mut bytes)?;
+            *x = i32::from_le_bytes(bytes);
+        }
+        let bounds = Tile {
+            x0: v[0],
+            y0: v[1],
+            x1: v[2],
+            y1: v[3],
+        };
+        // a tile is never more than a few hundred pixels across
+        let size = |from: i32, to: i32| to.checked_sub(from).filter(|n| (0..=1 << 14).contains(n));
+        if size(bounds.x0, bounds.x1).is_none() || size(bounds.y0, bounds.y1).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "film tile is too big",
+            ));
+        }
+        let splats = (0..bounds.pixels())
+            .map(|_| Splat::read_from(r))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(FilmTile {
+            filter,
+            bounds,
+            splats,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FilmTile, Filter, FilterKind, Splat};
+    use crate::checkpoint::{tiles, Tile};
+    use crate::vect;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const KINDS: [FilterKind; 5] = [
+        FilterKind::Box,
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+        FilterKind::Mitchell,
+        FilterKind::Lanczos,
+    ];
+
+    #[test]
+    fn test_filters() {
+        for kind in KINDS {
+            assert_eq!(kind.to_string().parse::<FilterKind>().unwrap(), kind);
+            let filter = Filter::new(kind, kind.default_radius());
+            assert!(filter.evaluate(0.0, 0.0) > 0.0, "{}", kind);
+            // symmetric, and nothing past the radius
+            for x in [0.1, 0.3, 0.7, 1.2, 2.5] {
+                assert_eq!(
+                    filter.evaluate(x, 0.2),
+                    filter.evaluate(-x, 0.2),
+                    "{}",
+                    kind
+                );
+            }
+            let r = filter.radius;
+            assert_eq!(filter.evaluate(r + 0.01, 0.0), 0.0, "{}", kind);
+            assert_eq!(filter.evaluate(0.0, -r - 0.01), 0.0, "{}", kind);
+        }
+        // Mitchell's negative lobe
+        assert!(Filter::new(FilterKind::Mitchell, 2.0).evaluate(1.5, 0.0) < 0.0);
+        assert_eq!(Filter::new(FilterKind::Tent, 1.0).evaluate(0.5, 0.5), 0.25);
+    }
+
+    #[test]
+    fn test_box_is_the_plain_average() {
+        // the default box only ever reaches the pixel the sample is in
+        let mut rng = StdRng::seed_from_u64(4);
+        let tile = Tile {
+            x0: 0,
+            y0: 0,
+            x1: 4,
+            y1: 3,
+        };
+        let mut film_tile = FilmTile::new(&tile, Filter::new(FilterKind::Box, 0.5), 4, 3);
+        for py in 0..3 {
+            for px in 0..4 {
+                for _ in 0..5 {
+                    let c = (py * 4 + px) as f64;
+                    film_tile.add_sample(
+                        px as f64 + rng.gen::<f64>(),
+                        py as f64 + rng.gen::<f64>(),
+                        &vect!(c, c, c),
+                    );
+                }
+            }
+        }
+        // on the line between two pixels
+        film_tile.add_sample(2.0, 1.5, &vect!(100, 100, 100));
+        let mut film = vec![Splat::default(); 12];
+        film_tile.merge_into(&mut film, 4);
+        for (index, splat) in film.iter().enumerate() {
+            if index == 6 {
+                assert_eq!(splat.weight, 6.0);
+            } else {
+                assert_eq!(splat.weight, 5.0);
+                assert!((splat.color().x - index as f64).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tiles_add_up() {
+        // splatting tile by tile has to give what one big tile gives
+        let (width, height) = (23, 17);
+        let whole = Tile {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        };
+        for kind in KINDS {
+            let filter = Filter::new(kind, kind.default_radius());
+            let mut rng = StdRng::seed_from_u64(9);
+            let mut one = FilmTile::new(&whole, filter, width, height);
+            let mut film = vec![Splat::default(); (width * height) as usize];
+            for tile in tiles(width, height, 8) {
+                let mut film_tile = FilmTile::new(&tile, filter, width, height);
+                for py in tile.y0..tile.y1 {
+                    for px in tile.x0..tile.x1 {
+                        let (x, y) = (px as f64 + rng.gen::<f64>(), py as f64 + rng.gen::<f64>());
+                        let c = vect!(rng.gen::<f64>(), 0.5, 1.0);
+                        film_tile.add_sample(x, y, &c);
+                        one.add_sample(x, y, &c);
+                    }
+                }
+                film_tile.merge_into(&mut film, width);
+            }
+            let mut expected = vec![Splat::default(); film.len()];
+            one.merge_into(&mut expected, width);
+            for (a, b) in film.iter().zip(&expected) {
+                assert!((a.weight - b.weight).abs() < 1e-9, "{}", kind);
+                assert!((a.sum - b.sum).length() < 1e-9, "{}", kind);
+                // a flat channel stays flat, whatever the filter
+                assert!((a.color().y - 0.5).abs() < 1e-9, "{}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bad_samples_skipped() {
+        let tile = Tile {
+            x0: 0,
+            y0: 0,
+            x1: 2,
+            y1: 2,
+        };
+        let mut film_tile = FilmTile::new(&tile, Filter::new(FilterKind::Gaussian, 1.5), 2, 2);
+        film_tile.add_sample(0.5, 0.5, &vect!(f64::NAN, 0, 0));
+        film_tile.add_sample(1.5, 0.5, &vect!(1, f64::INFINITY, 0));
+        let mut film = vec![Splat::default(); 4];
+        film_tile.merge_into(&mut film, 2);
+        assert!(film.iter().all(|splat| *splat == Splat::default()));
+        assert_eq!(Splat::default().color(), vect!(0, 0, 0));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::adaptive::PixelStats;
+use crate::vec3::Color;
+
+// Fireflies are the odd sample that's hundreds of times brighter than the
+// rest of its pixel, like a diffuse bounce that happens to go off a mirror
+// straight into a small light. They're correct, just rare, and it takes
+// thousands of samples before the pixel's average stops showing them. Two
+// ways to get rid of them sooner, both of which give up a little of the
+// energy in the picture (they're biased) for a lot less noise.
+
+fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+// Scales a sample down so its luminance is no more than max, keeping its
+// color. Anything dimmer is left alone.
+pub fn clamp_luminance(sample: &Color, max: f64) -> Color {
+    let l = luminance(sample);
+    if l > max {
+        *sample * (max / l)
+    } else {
+        *sample
+    }
+}
+
+// Median of means. A pixel's samples are dealt out in turn into a few groups
+// and each group is averaged. The median of those averages barely moves when
+// one of them has a firefly in it, where the plain mean would.
+//
+// The groups' sums are kept outside of PixelStats, `groups` of them for
+// every pixel, since most renders don't need them. Which group a sample goes
+// in only depends on how many the pixel had before it, so how many each
+// group has can be worked out from the count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MedianOfMeans {
+    pub groups: usize,
+}
+
+impl MedianOfMeans {
+    pub fn new(groups: usize) -> Self {
+        MedianOfMeans {
+            groups: groups.max(1),
+        }
+    }
+
+    // sums holds the pixel's groups, index is how many samples it had before
+    pub fn add(&self, sums: &mut [Color], index: i32, sample: &Color) {
+        sums[index as usize % self.groups] += *sample;
+    }
+
+    pub fn estimate(&self, sums: &[Color], stats: &PixelStats) -> Color {
+        let count = stats.count() as usize;
+        // not enough to fill every group yet
+        if count < self.groups {
+            return stats.mean();
+        }
+        let mut means: Vec<Color> = sums
+            .iter()
+            .enumerate()
+            .map(|(group, sum)| {
+                let n = count / self.groups + usize::from(group < count % self.groups);
+                *sum / n as f64
+            })
+            .collect();
+        // the median by brightness, so the color stays together
+        means.sort_by(|a, b| luminance(a).total_cmp(&luminance(b)));
+        let middle = means.len() / 2;
+        if means.len() % 2 == 1 {
+            means[middle]
+        } else {
+            (means[middle - 1] + means[middle]) / 2.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clamp_luminance, luminance, MedianOfMeans};
+    use crate::adaptive::PixelStats;
+    use crate::vec3::Color;
+    use crate::vect;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    fn test_clamp() {
+        assert_eq!(
+            clamp_luminance(&vect!(0.5, 0.2, 0.1), 1.0),
+            vect!(0.5, 0.2, 0.1)
+        );
+        let clamped = clamp_luminance(&vect!(400, 100, 50), 10.0);
+        assert!((luminance(&clamped) - 10.0).abs() < 1e-12);
+        // same hue
+        assert!((clamped.x / clamped.y - 4.0).abs() < 1e-12);
+        assert!((clamped.z / clamped.y - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_median_of_means() {
+        let mut rng = StdRng::seed_from_u64(19);
+        for groups in [5, 8] {
+            let mom = MedianOfMeans::new(groups);
+            let mut sums = vec![Color::default(); groups];
+            let mut stats = PixelStats::default();
+            // too few samples to have every group, it's just the mean
+            for v in [0.2, 0.4] {
+                mom.add(&mut sums, stats.count(), &vect!(v, v, v));
+                stats.add(&vect!(v, v, v));
+            }
+            assert_eq!(mom.estimate(&sums, &stats), stats.mean());
+
+            // around 0.5, and one firefly. 203 samples doesn't divide evenly.
+            for n in 0..201 {
+                let v = rng.gen::<f64>();
+                let sample = if n == 77 {
+                    vect!(5000, 5000, 5000)
+                } else {
+                    vect!(v, v, v)
+                };
+                mom.add(&mut sums, stats.count(), &sample);
+                stats.add(&sample);
+            }
+            assert!(stats.mean().x > 20.0);
+            let robust = mom.estimate(&sums, &stats);
+            assert!(
+                (robust.x - 0.5).abs() < 0.1,
+                "{} groups: {}",
+                groups,
+                robust
+            );
+        }
+
+        // with nothing unusual going on it's the mean, give or take
+        let mom = MedianOfMeans::new(5);
+        let mut sums = vec![Color::default(); 5];
+        let mut stats = PixelStats::default();
+        for _ in 0..5000 {
+            let sample = vect!(rng.gen::<f64>(), 2.0 * rng.gen::<f64>(), 0.25);
+            mom.add(&mut sums, stats.count(), &sample);
+            stats.add(&sample);
+        }
+        assert!((mom.estimate(&sums, &stats) - stats.mean()).length() < 0.02);
+    }
+}
@@ -0,0 +1,425 @@
+use crate::checkpoint::Tile;
+use crate::vec3::Color;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+
+// The finished image as floating point linear light, before anything is
+// clamped or gamma corrected, so highlights like the 15x lights in
+// cornell_box survive. Saved as a Portable FloatMap or a Radiance HDR file
+// it can be graded and tone mapped later, and the same files can be read
+// back, for environment maps. Rows go top to bottom, like the PPM we print.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Framebuffer {
+    width: i32,
+    height: i32,
+    pixels: Vec<Color>,
+}
+
+impl Framebuffer {
+    pub fn new(width: i32, height: i32) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Color::default(); (width.max(0) * height.max(0)) as usize],
+        }
+    }
+
+    pub fn from_pixels(width: i32, height: i32, pixels: Vec<Color>) -> Self {
+        assert_eq!(
+            pixels.len(),
+            (width.max(0) * height.max(0)) as usize,
+            "a {}x{} framebuffer needs {} pixels",
+            width,
+            height,
+            width * height
+        );
+        Framebuffer {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    // x across from the left, y down from the top
+    pub fn get(&self, x: i32, y: i32) -> Color {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, color: Color) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    // just the pixels in the tile, as an image of their own
+    pub fn crop(&self, tile: &Tile) -> Self {
+        Framebuffer::from_pixels(
+            tile.x1 - tile.x0,
+            tile.y1 - tile.y0,
+            tile.indices(self.width)
+                .map(|index| self.pixels[index])
+                .collect(),
+        )
+    }
+
+    // Portable FloatMap: three 32 bit floats a pixel, little endian (that's
+    // what the negative scale says), with the bottom row first.
+    pub fn write_pfm(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(w, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width.max(1) as usize).rev() {
+            for c in row {
+                for v in [c.x, c.y, c.z] {
+                    w.write_all(&(v as f32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Radiance RGBE: a byte of mantissa for each channel and one exponent
+    // they share. The scanlines are written flat, without run length
+    // encoding, which every reader accepts.
+    pub fn write_rgbe(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(
+            w,
+            "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+            self.height, self.width
+        )?;
+        for c in &self.pixels {
+            w.write_all(&to_rgbe(c))?;
+        }
+        Ok(())
+    }
+
+    pub fn read_pfm(r: &mut impl Read) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let mut at = 0;
+        let mut token = || {
+            while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+                at += 1;
+            }
+            let start = at;
+            while at < bytes.len() && !bytes[at].is_ascii_whitespace() {
+                at += 1;
+            }
+            String::from_utf8_lossy(&bytes[start..at]).to_string()
+        };
+        if token() != "PF" {
+            return Err(bad_data("not a color PFM file"));
+        }
+        let width: i32 = token().parse().map_err(|_| bad_data("bad PFM width"))?;
+        let height: i32 = token().parse().map_err(|_| bad_data("bad PFM height"))?;
+        let scale: f64 = token().parse().map_err(|_| bad_data("bad PFM scale"))?;
+        // a single whitespace character between the header and the floats
+        let data = &bytes[(at + 1).min(bytes.len())..];
+        if width <= 0 || height <= 0 {
+            return Err(bad_data("PFM file has no pixels"));
+        }
+        // checked against what's there before anything's allocated for it
+        let size = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|n| n.checked_mul(12))
+            .filter(|&size| size <= data.len())
+            .ok_or_else(|| bad_data("PFM file is too short"))?;
+        let float = |b: &[u8]| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if scale < 0.0 {
+                f32::from_le_bytes(b) as f64
+            } else {
+                f32::from_be_bytes(b) as f64
+            }
+        };
+        let mut pixels = Vec::with_capacity(size / 12);
+        for row in data[..size].chunks(width as usize * 12).rev() {
+            for c in row.chunks(12) {
+                pixels.push(Color::new(
+                    float(&c[0..4]),
+                    float(&c[4..8]),
+                    float(&c[8..12]),
+                ));
+            }
+        }
+        Ok(Framebuffer::from_pixels(width, height, pixels))
+    }
+
+    // Reads flat scanlines like write_rgbe() makes, and the run length
+    // encoded ones most other programs write. Only the usual -Y h +X w
+    // orientation.
+    pub fn read_rgbe(r: &mut impl Read) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let mut at = 0;
+        let mut line = || {
+            if at >= bytes.len() {
+                return None;
+            }
+            let start = at;
+            while at < bytes.len() && bytes[at] != b'\n' {
+                at += 1;
+            }
+            at += 1;
+            Some(String::from_utf8_lossy(&bytes[start..at - 1]).to_string())
+        };
+        let no_image = || bad_data("Radiance HDR file has no image");
+        if !line().ok_or_else(no_image)?.starts_with("#?") {
+            return Err(bad_data("not a Radiance HDR file"));
+        }
+        // the header ends with a blank line
+        while !line().ok_or_else(no_image)?.trim().is_empty() {}
+        let size = line().ok_or_else(no_image)?;
+        let (height, width) = match size.split_whitespace().collect::<Vec<_>>()[..] {
+            ["-Y", h, "+X", w] => (
+                h.parse::<i32>().map_err(|_| bad_data("bad HDR height"))?,
+                w.parse::<i32>().map_err(|_| bad_data("bad HDR width"))?,
+            ),
+            _ => return Err(bad_data(&format!("unsupported HDR resolution '{}'", size))),
+        };
+        if width <= 0 || height <= 0 {
+            return Err(bad_data("HDR file has no pixels"));
+        }
+        let data = &bytes[at.min(bytes.len())..];
+        // A run length encoded scanline is at least its 4 byte start and a
+        // run of up to 127 for each channel, flat ones are 4 bytes a pixel.
+        // That's checked against what's there before anything's allocated.
+        let w = width as usize;
+        let shortest = if (8..0x8000).contains(&w) {
+            4 + 8 * w.div_ceil(127)
+        } else {
+            4 * w
+        };
+        let pixel_count = w
+            .checked_mul(height as usize)
+            .filter(|_| shortest.saturating_mul(height as usize) <= data.len())
+            .ok_or_else(|| bad_data("HDR file is too short"))?;
+        let mut at = 0;
+        let mut next = || {
+            at += 1;
+            data.get(at - 1)
+                .copied()
+                .ok_or_else(|| bad_data("HDR file is too short"))
+        };
+        let mut pixels = Vec::with_capacity(pixel_count);
+        let mut scanline = vec![[0u8; 4]; w];
+        for _ in 0..height {
+            let start = [next()?, next()?, next()?, next()?];
+            if (8..0x8000).contains(&w)
+                && start[0] == 2
+                && start[1] == 2
+                && ((start[2] as usize) << 8 | start[3] as usize) == w
+            {
+                // each channel on its own, as runs and literals
+                for channel in 0..4 {
+                    let mut x = 0;
+                    while x < w {
+                        let count = next()? as usize;
+                        let (run, count) = if count > 128 {
+                            (true, count - 128)
+                        } else {
+                            (false, count)
+                        };
+                        if count == 0 || x + count > w {
+                            return Err(bad_data("bad run in HDR scanline"));
+                        }
+                        let value = if run { next()? } else { 0 };
+                        for pixel in &mut scanline[x..x + count] {
+                            pixel[channel] = if run { value } else { next()? };
+                        }
+                        x += count;
+                    }
+                }
+            } else {
+                scanline[0] = start;
+                for pixel in scanline.iter_mut().skip(1) {
+                    *pixel = [next()?, next()?, next()?, next()?];
+                }
+            }
+            pixels.extend(scanline.iter().map(from_rgbe));
+        }
+        Ok(Framebuffer::from_pixels(width, height, pixels))
+    }
+
+    // picks the format from the extension, .pfm or .hdr
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let mut r = File::open(path)?;
+        match extension.as_deref() {
+            Some("pfm") => Framebuffer::read_pfm(&mut r),
+            Some("hdr") | Some("rgbe") => Framebuffer::read_rgbe(&mut r),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "don't know how to load {}, use .pfm or .hdr",
+                    path.display()
+                ),
+            )),
+        }
+    }
+
+    // picks the format from the extension, .pfm or .hdr
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let mut w = BufWriter::new(File::create(path)?);
+        match extension.as_deref() {
+            Some("pfm") => self.write_pfm(&mut w)?,
+            Some("hdr") | Some("rgbe") => self.write_rgbe(&mut w)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "don't know how to save {}, use .pfm or .hdr",
+                        path.display()
+                    ),
+                ))
+            }
+        }
+        w.flush()
+    }
+}
+
+// negative and NaN channels can't be stored, so they come out black
+fn to_rgbe(c: &Color) -> [u8; 4] {
+    let channel = |v: f64| if v > 0.0 { v } else { 0.0 };
+    let (r, g, b) = (channel(c.x), channel(c.y), channel(c.z));
+    let brightest = r.max(g).max(b);
+    if brightest < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    let (mantissa, exponent) = libm::frexp(brightest);
+    let scale = mantissa * 256.0 / brightest;
+    [
+        (r * scale) as u8,
+        (g * scale) as u8,
+        (b * scale) as u8,
+        (exponent + 128) as u8,
+    ]
+}
+
+fn from_rgbe(rgbe: &[u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::default();
+    }
+    // the middle of the step the mantissa stands for
+    let scale = 2f64.powi(rgbe[3] as i32 - 136);
+    Color::new(
+        (rgbe[0] as f64 + 0.5) * scale,
+        (rgbe[1] as f64 + 0.5) * scale,
+        (rgbe[2] as f64 + 0.5) * scale,
+    )
+}
+
+fn bad_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_rgbe, to_rgbe, Framebuffer};
+    use crate::vect;
+
+    #[test]
+    fn test_pfm() {
+        let mut fb = Framebuffer::new(2, 2);
+        fb.set(0, 0, vect!(15.0, 0.5, -1.0));
+        fb.set(1, 1, vect!(0.25, 2, 3));
+        let mut out = Vec::new();
+        fb.write_pfm(&mut out).unwrap();
+
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&out[..header.len()], header);
+        let floats: Vec<f32> = out[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(floats.len(), 12);
+        // the bottom row comes first, so (1, 1) is the second pixel
+        assert_eq!(&floats[3..6], &[0.25, 2.0, 3.0]);
+        // and the top left one is the third, highlights and all
+        assert_eq!(&floats[6..9], &[15.0, 0.5, -1.0]);
+    }
+
+    #[test]
+    fn test_rgbe() {
+        assert_eq!(to_rgbe(&vect!(1, 1, 1)), [128, 128, 128, 129]);
+        assert_eq!(to_rgbe(&vect!(0, 0, 0)), [0, 0, 0, 0]);
+        assert_eq!(to_rgbe(&vect!(f64::NAN, -1, 0)), [0, 0, 0, 0]);
+        // decoding gives back the brightest channel to within a step
+        let [r, g, b, e] = to_rgbe(&vect!(15.0, 3.0, 0.1));
+        let decode = |m: u8| (m as f64 + 0.5) * 2f64.powi(e as i32 - 136);
+        assert!((decode(r) - 15.0).abs() < 15.0 / 128.0);
+        assert!((decode(g) - 3.0).abs() < 15.0 / 128.0);
+        assert!(decode(b) < 15.0 / 128.0);
+
+        let fb = Framebuffer::from_pixels(3, 1, vec![vect!(1, 1, 1); 3]);
+        let mut out = Vec::new();
+        fb.write_rgbe(&mut out).unwrap();
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 3\n";
+        assert_eq!(&out[..header.len()], header);
+        assert_eq!(&out[header.len()..], &[128, 128, 128, 129].repeat(3)[..]);
+    }
+
+    #[test]
+    fn test_read_back() {
+        let mut fb = Framebuffer::new(9, 2);
+        fb.set(0, 0, vect!(15.0, 0.5, 0.25));
+        fb.set(8, 1, vect!(0.125, 2, 3));
+        let mut out = Vec::new();
+        fb.write_pfm(&mut out).unwrap();
+        assert_eq!(Framebuffer::read_pfm(&mut &out[..]).unwrap(), fb);
+
+        let mut out = Vec::new();
+        fb.write_rgbe(&mut out).unwrap();
+        let back = Framebuffer::read_rgbe(&mut &out[..]).unwrap();
+        assert_eq!((back.width(), back.height()), (9, 2));
+        for (a, b) in back.pixels().iter().zip(fb.pixels()) {
+            assert!(
+                (*a - *b).length() < 0.01 * b.length().max(1.0),
+                "{} {}",
+                a,
+                b
+            );
+        }
+        assert_eq!(from_rgbe(&to_rgbe(&vect!(1, 0.5, 0))).x, 1.0 + 0.5 / 128.0);
+
+        // the same 9 pixels run length encoded, one channel at a time: red
+        // as a run, green as literals and then a run, blue and the exponent
+        // as runs
+        let mut rle = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 9\n".to_vec();
+        rle.extend_from_slice(&[2, 2, 0, 9]);
+        rle.extend_from_slice(&[128 + 9, 128]);
+        rle.extend_from_slice(&[2, 64, 64, 128 + 7, 0]);
+        rle.extend_from_slice(&[128 + 9, 0]);
+        rle.extend_from_slice(&[128 + 9, 129]);
+        let back = Framebuffer::read_rgbe(&mut &rle[..]).unwrap();
+        assert_eq!(back.width(), 9);
+        assert_eq!(back.get(0, 0), from_rgbe(&[128, 64, 0, 129]));
+        assert_eq!(back.get(4, 0), from_rgbe(&[128, 0, 0, 129]));
+
+        assert!(Framebuffer::read_rgbe(&mut &b"P3\n"[..]).is_err());
+        assert!(Framebuffer::read_rgbe(&mut &rle[..rle.len() - 1]).is_err());
+
+        // sizes far bigger than the file are errors, not overflows or
+        // running out of memory
+        assert!(Framebuffer::read_pfm(&mut &b"PF\n100000 100000\n-1\n"[..]).is_err());
+        assert!(Framebuffer::read_pfm(&mut &b"PF\n2147483647 2147483647\n-1\n"[..]).is_err());
+        let huge = b"#?RADIANCE\n\n-Y 2000000000 +X 2000000000\n\x02\x02\x00\x09";
+        assert!(Framebuffer::read_rgbe(&mut &huge[..]).is_err());
+    }
+}
@@ -1,6 +1,8 @@
 #[allow(unused_imports)]
 use super::hittable::{HitRecord, Hittable, Hitters, TextureCoord};
 use super::ray::Ray;
+use super::sampler::{sample_index, Sampler};
+use super::vec3::{Point3, Vec3};
 use crate::prelude;
 use prelude::BoundingBox;
 
@@ -76,6 +78,49 @@ impl Hittable for HitList {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    // random() picks one of the objects with equal probability, so the pdf of
+    // the whole list is just the average of everyone's pdf.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        if self.list.is_empty() {
+            return 0.0;
+        }
+        let weight = 1.0 / self.list.len() as f64;
+        self.list
+            .iter()
+            .map(|obj| weight * obj.pdf_value(origin, direction))
+            .sum()
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let which = sample_index(sampler.get_1d(), self.list.len());
+        self.list[which].random(origin, sampler)
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        self.list.iter().flat_map(|obj| obj.emitters()).collect()
+    }
+
+    // same idea as random(), one object picked with equal probability
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        if self.list.is_empty() {
+            return None;
+        }
+        let which = sample_index(sampler.get_1d(), self.list.len());
+        let (rec, pdf) = self.list[which].sample_surface(sampler)?;
+        Some((rec, pdf / self.list.len() as f64))
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        if self.list.is_empty() {
+            return 0.0;
+        }
+        let weight = 1.0 / self.list.len() as f64;
+        self.list
+            .iter()
+            .map(|obj| weight * obj.surface_pdf(p))
+            .sum()
+    }
 }
 
 //#[allow(unused_macros)]
@@ -6,8 +6,10 @@ use super::hitlist::HitList;
 use super::materials::{Material, MaterialType};
 use super::ray::Ray;
 use super::rectangle::Rect;
+use super::sampler::Sampler;
 use super::sphere::{MovingSphere, Sphere};
 use super::vec3::{Point3, Vec3};
+use super::vect;
 use std::{cmp::PartialEq, fmt};
 
 #[allow(unused_imports, dead_code)]
@@ -19,6 +21,35 @@ pub trait Hittable {
     fn bounding_box(&self, t0: f64, t1: f64) -> Option<BoundingBox>;
 
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
+
+    // These are used to sample lights directly. Only shapes that can carry a
+    // DiffuseLight need to implement them, anything else is never asked.
+    // pdf_value is the solid angle pdf of sending a ray from origin along direction
+    // and hitting us, random gives a direction from origin towards a point on us.
+    fn pdf_value(&self, _origin: &Point3, _direction: &Vec3) -> f64 {
+        0.0
+    }
+
+    fn random(&self, _origin: &Point3, _sampler: &mut dyn Sampler) -> Vec3 {
+        vect!(1, 0, 0)
+    }
+
+    // everything that gives off light, so the integrator can aim rays at them
+    fn emitters(&self) -> Vec<Hitters> {
+        Vec::new()
+    }
+
+    // Paths that start on a light need a point on it rather than a direction
+    // towards it. sample_surface picks one and hands back a record for it (for
+    // the normal, material and uv) along with the pdf per unit area. surface_pdf
+    // is that same pdf for a point p that is already known to be on us.
+    fn sample_surface(&self, _sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        None
+    }
+
+    fn surface_pdf(&self, _p: &Point3) -> f64 {
+        0.0
+    }
 }
 
 impl Hittable for Hitters {
@@ -70,6 +101,81 @@ impl Hittable for Hitters {
             Hitters::Nothing(_x) => write!(f, "Hitter::Nothing"),
         }
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        match self {
+            Hitters::HitList(x) => x.pdf_value(origin, direction),
+            Hitters::Sphere(x) => x.pdf_value(origin, direction),
+            Hitters::MovingSphere(x) => x.pdf_value(origin, direction),
+            Hitters::BoundingBox(x) => x.pdf_value(origin, direction),
+            Hitters::Cube(x) => x.pdf_value(origin, direction),
+            Hitters::BVolumeHierarchy(x) | Hitters::BvhNode(x) => x.pdf_value(origin, direction),
+            Hitters::FlipNormal(x) => x.pdf_value(origin, direction),
+            Hitters::Rect(x) => x.pdf_value(origin, direction),
+            Hitters::Custom(x) => x.pdf_value(origin, direction),
+            Hitters::Nothing(_x) => 0.0,
+        }
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        match self {
+            Hitters::HitList(x) => x.random(origin, sampler),
+            Hitters::Sphere(x) => x.random(origin, sampler),
+            Hitters::MovingSphere(x) => x.random(origin, sampler),
+            Hitters::BoundingBox(x) => x.random(origin, sampler),
+            Hitters::Cube(x) => x.random(origin, sampler),
+            Hitters::BVolumeHierarchy(x) | Hitters::BvhNode(x) => x.random(origin, sampler),
+            Hitters::FlipNormal(x) => x.random(origin, sampler),
+            Hitters::Rect(x) => x.random(origin, sampler),
+            Hitters::Custom(x) => x.random(origin, sampler),
+            Hitters::Nothing(_x) => vect!(1, 0, 0),
+        }
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        match self {
+            Hitters::HitList(x) => x.emitters(),
+            Hitters::Sphere(x) => x.emitters(),
+            Hitters::MovingSphere(x) => x.emitters(),
+            Hitters::BoundingBox(x) => x.emitters(),
+            Hitters::Cube(x) => x.emitters(),
+            Hitters::BVolumeHierarchy(x) | Hitters::BvhNode(x) => x.emitters(),
+            Hitters::FlipNormal(x) => x.emitters(),
+            Hitters::Rect(x) => x.emitters(),
+            Hitters::Custom(x) => x.emitters(),
+            Hitters::Nothing(_x) => Vec::new(),
+        }
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        match self {
+            Hitters::HitList(x) => x.sample_surface(sampler),
+            Hitters::Sphere(x) => x.sample_surface(sampler),
+            Hitters::MovingSphere(x) => x.sample_surface(sampler),
+            Hitters::BoundingBox(x) => x.sample_surface(sampler),
+            Hitters::Cube(x) => x.sample_surface(sampler),
+            Hitters::BVolumeHierarchy(x) | Hitters::BvhNode(x) => x.sample_surface(sampler),
+            Hitters::FlipNormal(x) => x.sample_surface(sampler),
+            Hitters::Rect(x) => x.sample_surface(sampler),
+            Hitters::Custom(x) => x.sample_surface(sampler),
+            Hitters::Nothing(_x) => None,
+        }
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        match self {
+            Hitters::HitList(x) => x.surface_pdf(p),
+            Hitters::Sphere(x) => x.surface_pdf(p),
+            Hitters::MovingSphere(x) => x.surface_pdf(p),
+            Hitters::BoundingBox(x) => x.surface_pdf(p),
+            Hitters::Cube(x) => x.surface_pdf(p),
+            Hitters::BVolumeHierarchy(x) | Hitters::BvhNode(x) => x.surface_pdf(p),
+            Hitters::FlipNormal(x) => x.surface_pdf(p),
+            Hitters::Rect(x) => x.surface_pdf(p),
+            Hitters::Custom(x) => x.surface_pdf(p),
+            Hitters::Nothing(_x) => 0.0,
+        }
+    }
 }
 
 impl std::fmt::Display for Hitters {
@@ -116,6 +222,29 @@ impl Hittable for FlipNormal {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.hitter_fmt(f)
     }
+
+    // flipping the normal doesn't move anything, so sampling is the same
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.0.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.0.random(origin, sampler)
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        self.0.emitters()
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (mut rec, pdf) = self.0.sample_surface(sampler)?;
+        rec.normal *= -1.0;
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        self.0.surface_pdf(p)
+    }
 }
 
 #[derive(Clone)]
@@ -148,6 +277,26 @@ impl Hittable for Custom {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.0.hitter_fmt(f)
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.0.pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.0.random(origin, sampler)
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        self.0.emitters()
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        self.0.sample_surface(sampler)
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        self.0.surface_pdf(p)
+    }
 }
 
 #[derive(Clone, Copy, Default)]
@@ -319,6 +468,26 @@ impl Hittable for std::boxed::Box<dyn Hittable> {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.as_ref().hitter_fmt(f)
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.as_ref().pdf_value(origin, direction)
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.as_ref().random(origin, sampler)
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        self.as_ref().emitters()
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        self.as_ref().sample_surface(sampler)
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        self.as_ref().surface_pdf(p)
+    }
 }
 
 #[cfg(test)]
@@ -1,10 +1,11 @@
 use crate::{
     aabb::{AabbF, BoundingBox, AABB},
-    hittable::{HitRecord, Hittable},
+    hittable::{Custom, HitRecord, Hittable, Hitters},
     ray::Ray,
     rectangle::Axis,
+    sampler::Sampler,
     util::{self},
-    vec3::Vec3,
+    vec3::{Point3, Vec3},
     vect,
 };
 use std::marker::PhantomData;
@@ -31,7 +32,7 @@ impl TranslateHittable {
 
 impl Hittable for TranslateHittable {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
-        let moved_ray = Ray::new(&(r.origin() - self.offset), &r.direction(), Some(r.time()));
+        let moved_ray = r.moved(&(r.origin() - self.offset), &r.direction());
         match self.instance.hit(&moved_ray, t_min, t_max) {
             Some(mut gothit) => {
                 gothit.p += self.offset;
@@ -55,6 +56,35 @@ impl Hittable for TranslateHittable {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    // same as hit, we move the origin into the instance's space, directions
+    // don't change with a translation.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.instance.pdf_value(&(*origin - self.offset), direction)
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.instance.random(&(*origin - self.offset), sampler)
+    }
+
+    // the lights inside us have to be moved along with us
+    fn emitters(&self) -> Vec<Hitters> {
+        self.instance
+            .emitters()
+            .iter()
+            .map(|light| Hitters::Custom(Custom::new(&TranslateHittable::new(light, &self.offset))))
+            .collect()
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (mut rec, pdf) = self.instance.sample_surface(sampler)?;
+        rec.p += self.offset;
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        self.instance.surface_pdf(&(*p - self.offset))
+    }
 }
 
 #[derive(Clone)]
@@ -322,7 +352,7 @@ impl Hittable for RotateHittable {
         //eprintln!("r.direction: {:?} unrotated to direction: {:?}", &r.direction(), &direction);
         let origin: Vec3 = self.unrotate(&r.origin());
         //eprintln!("r.origin: {:?} unrotated to origin: {:?}", &r.origin(), &origin);
-        let rotated_r = Ray::new(&origin, &direction, Some(r.time()));
+        let rotated_r = r.moved(&origin, &direction);
         //eprintln!("Ray: {:?} unrotated_Ray: {:?}", r, rotated_r);
 
         match self.instance.hit(&rotated_r, t_min, t_max) {
@@ -354,6 +384,43 @@ impl Hittable for RotateHittable {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        self.instance
+            .pdf_value(&self.unrotate(origin), &self.unrotate(direction))
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        self.rotate(&self.instance.random(&self.unrotate(origin), sampler))
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        self.instance
+            .emitters()
+            .iter()
+            .map(|light| {
+                Hitters::Custom(Custom::new(&RotateHittable {
+                    instance: light.box_clone(),
+                    sin_theta: self.sin_theta,
+                    cos_theta: self.cos_theta,
+                    rotate_around: self.rotate_around,
+                    bbox: self.bbox,
+                }))
+            })
+            .collect()
+    }
+
+    // rotations don't stretch anything, so the area pdf carries over as is
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (mut rec, pdf) = self.instance.sample_surface(sampler)?;
+        rec.p = self.rotate(&rec.p);
+        rec.normal = self.rotate(&rec.normal);
+        Some((rec, pdf))
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        self.instance.surface_pdf(&self.unrotate(p))
+    }
 }
 
 #[allow(dead_code)]
@@ -478,6 +545,7 @@ mod test {
         hittable::{HitRecord, Hittable, TextureCoord},
         materials::{DiffuseLight, MaterialType},
         ray::Ray,
+        sampler::IndependentSampler,
         textures::{ConstantTexture, TextureType},
         vec3::Vec3,
         vect,
@@ -916,4 +984,33 @@ mod test {
             assert_eq!(hr, Some(hr_ans));
         }
     }
+
+    #[test]
+    fn test_instanced_light_sampling() {
+        let light = MaterialType::DiffuseLight(DiffuseLight::new(TextureType::ConstantTexture(
+            ConstantTexture::new(&vect!(4, 4, 4)),
+        )));
+        let cube = Cube::new(&vect!(-1, -1, -1), &vect!(1, 1, 1), &light);
+        let rotated = RotateHittable::new(&cube)
+            .with_rotate_around_y(30.0)
+            .build();
+        let moved = TranslateHittable::new(&rotated, &vect!(0, 10, 0));
+
+        // all six walls glow
+        let lights = moved.emitters();
+        assert_eq!(lights.len(), 6);
+
+        // whatever wall we pick, the direction should land on the moved cube
+        let origin = vect!(0, 0, 0);
+        let mut sampler = IndependentSampler::new(0);
+        for light in &lights {
+            for _ in 0..20 {
+                let to_light = light.random(&origin, &mut sampler);
+                let rec = moved.hit(&Ray::new(&origin, &to_light, None), 0.001, f64::INFINITY);
+                assert!(rec.is_some());
+                assert!(light.pdf_value(&origin, &to_light) > 0.0);
+            }
+        }
+        assert!(moved.pdf_value(&origin, &vect!(0, 1, 0)) > 0.0);
+    }
 }
@@ -6,36 +6,80 @@
 #![feature(let_chains)]
 #![feature(const_fn_trait_bound)]
 pub mod aabb;
+pub mod adaptive;
+pub mod animation;
+pub mod aov;
+pub mod bdpt;
 pub mod bvh;
 pub mod camera;
+pub mod checkpoint;
 pub mod cube;
+pub mod deflate;
+pub mod denoise;
+pub mod distributed;
+pub mod environment;
+pub mod film;
+pub mod firefly;
+pub mod framebuffer;
 pub mod hitlist;
 pub mod hittable;
 pub mod instances;
 pub mod materials;
+pub mod onb;
+pub mod output;
 pub mod perlin;
+pub mod photon;
+pub mod progress;
 pub mod ray;
 pub mod rectangle;
+pub mod render;
+pub mod sampler;
+pub mod sky;
+pub mod spectrum;
 pub mod sphere;
+pub mod stats;
 pub mod textures;
+pub mod tonemap;
 pub mod util;
 pub mod vec3;
 pub mod volumes;
 
 pub mod prelude {
     pub use super::aabb::*;
+    pub use super::adaptive::*;
+    pub use super::animation::*;
+    pub use super::aov::*;
+    pub use super::bdpt::*;
     pub use super::bvh::*;
     pub use super::camera::*;
+    pub use super::checkpoint::*;
     pub use super::cube::*;
+    pub use super::deflate::*;
+    pub use super::denoise::*;
+    pub use super::distributed::*;
+    pub use super::environment::*;
+    pub use super::film::*;
+    pub use super::firefly::*;
+    pub use super::framebuffer::*;
     pub use super::hitlist::*;
     pub use super::hittable::*;
     pub use super::instances::*;
     pub use super::materials::*;
+    pub use super::onb::*;
+    pub use super::output::*;
     pub use super::perlin::*;
+    pub use super::photon::*;
+    pub use super::progress::*;
     pub use super::ray::*;
     pub use super::rectangle::*;
+    pub use super::render::*;
+    pub use super::sampler::*;
+    pub use super::sky::*;
+    pub use super::spectrum::*;
     pub use super::sphere::*;
+    pub use super::stats::*;
     pub use super::textures::*;
+    pub use super::tonemap::*;
     pub use super::util::*;
     pub use super::vec3::*;
     pub use super::volumes::*;
@@ -1,19 +1,54 @@
 use super::hittable::{HitRecord};
+use super::onb::Onb;
 use super::ray::Ray;
+use super::sampler::Sampler;
+use super::spectrum::at_wavelength;
 use super::textures::{ConstantTexture, NoneTexture, Texture, TextureType};
-use super::util::{random_in_unit_sphere, reflect, refract};
+use super::util::{cosine_direction, reflect, refract, sample_in_unit_sphere};
 use super::vec3::{dot, unit_vector, Color, Vec3};
 use super::vect;
-use rand::Rng;
+use std::f64::consts::PI;
 
 pub trait Material {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)>;
+    // The random numbers come from sampler. The scattered ray also gets the
+    // one it needs if it goes into a ConstantMedium, see Ray::with_medium_sample().
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)>;
     fn albedo(&self) -> TextureType;
     fn inner_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result;
     fn box_clone(&self) -> Box<MaterialType>;
     fn emitted(&self, _u: f64, _v: f64, _p: &Vec3) -> Color {
         vect!(0, 0, 0)
     } // return black as default
+
+    // lights get sampled directly, so shapes need to know if they are one
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    // The pdf (over solid angle) of scatter() picking the direction of
+    // scattered. Together with bsdf() this is what lets an integrator weigh a
+    // direction that came from somewhere else, like a shadow ray to a light.
+    // For a direction scatter() hands out, bsdf * cosine / pdf is the attenuation.
+    fn scattering_pdf(&self, _ray_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        0.0
+    }
+
+    // how much light coming in along scattered is sent back along ray_in,
+    // without the cosine term
+    fn bsdf(&self, _ray_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> Color {
+        vect!(0, 0, 0)
+    }
+
+    // mirrors and glass only send light in one direction, so there is no
+    // pdf to speak of and no point aiming shadow rays from them
+    fn is_specular(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Clone)]
@@ -41,7 +76,12 @@ impl std::fmt::Display for MaterialType {
 pub struct NoneMaterial;
 
 impl Material for NoneMaterial {
-    fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        _rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
         None
     }
     fn albedo(&self) -> TextureType {
@@ -56,23 +96,39 @@ impl Material for NoneMaterial {
 }
 
 impl Material for MaterialType {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        match self {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let scattered = match self {
             MaterialType::Lambertian(innertype) => {
-                innertype.scatter(ray_in, rec)
+                innertype.scatter(ray_in, rec, sampler)
             }
             MaterialType::Dielectric(innertype) => {
-                innertype.scatter(ray_in, rec)
+                innertype.scatter(ray_in, rec, sampler)
             }
             MaterialType::Metal(innertype) => {
-                innertype.scatter(ray_in, rec)
+                innertype.scatter(ray_in, rec, sampler)
             }
             MaterialType::DiffuseLight(innertype) => {
-                innertype.scatter(ray_in, rec)
+                innertype.scatter(ray_in, rec, sampler)
             }
             MaterialType::Nothing(_innertype) => {
                 None
             }
+        };
+        // a spectral path keeps to its wavelength, and only sees the
+        // attenuation at it, see spectrum.rs
+        match ray_in.wavelength() {
+            Some(lambda) => scattered.map(|(attenuation, scattered)| {
+                (
+                    at_wavelength(&attenuation, lambda),
+                    scattered.with_wavelength(Some(lambda)),
+                )
+            }),
+            None => scattered,
         }
     }
     fn albedo(&self) -> TextureType {
@@ -120,6 +176,52 @@ impl Material for MaterialType {
             MaterialType::Nothing(innertype) => innertype.emitted(u, v, p),
         }
     }
+
+    fn is_emissive(&self) -> bool {
+        match self {
+            MaterialType::Lambertian(innertype) => innertype.is_emissive(),
+            MaterialType::Dielectric(innertype) => innertype.is_emissive(),
+            MaterialType::Metal(innertype) => innertype.is_emissive(),
+            MaterialType::DiffuseLight(innertype) => innertype.is_emissive(),
+            MaterialType::Nothing(innertype) => innertype.is_emissive(),
+        }
+    }
+
+    fn scattering_pdf(&self, ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        match self {
+            MaterialType::Lambertian(innertype) => innertype.scattering_pdf(ray_in, rec, scattered),
+            MaterialType::Dielectric(innertype) => innertype.scattering_pdf(ray_in, rec, scattered),
+            MaterialType::Metal(innertype) => innertype.scattering_pdf(ray_in, rec, scattered),
+            MaterialType::DiffuseLight(innertype) => {
+                innertype.scattering_pdf(ray_in, rec, scattered)
+            }
+            MaterialType::Nothing(innertype) => innertype.scattering_pdf(ray_in, rec, scattered),
+        }
+    }
+
+    fn bsdf(&self, ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        let bsdf = match self {
+            MaterialType::Lambertian(innertype) => innertype.bsdf(ray_in, rec, scattered),
+            MaterialType::Dielectric(innertype) => innertype.bsdf(ray_in, rec, scattered),
+            MaterialType::Metal(innertype) => innertype.bsdf(ray_in, rec, scattered),
+            MaterialType::DiffuseLight(innertype) => innertype.bsdf(ray_in, rec, scattered),
+            MaterialType::Nothing(innertype) => innertype.bsdf(ray_in, rec, scattered),
+        };
+        match ray_in.wavelength() {
+            Some(lambda) => at_wavelength(&bsdf, lambda),
+            None => bsdf,
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        match self {
+            MaterialType::Lambertian(innertype) => innertype.is_specular(),
+            MaterialType::Dielectric(innertype) => innertype.is_specular(),
+            MaterialType::Metal(innertype) => innertype.is_specular(),
+            MaterialType::DiffuseLight(innertype) => innertype.is_specular(),
+            MaterialType::Nothing(innertype) => innertype.is_specular(),
+        }
+    }
 }
 
 impl Clone for Box<dyn Material> {
@@ -169,9 +271,18 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        let target = rec.p + rec.normal + random_in_unit_sphere();
-        let scattered = Ray::new(&rec.p, &(target - rec.p), None);
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        // this used to be normal + random_in_unit_sphere(), which is close to a
+        // cosine distribution but not close enough to write down a pdf for it.
+        let uvw = Onb::build_from_w(&rec.normal);
+        let (r1, r2) = sampler.get_2d();
+        let scattered = Ray::new(&rec.p, &uvw.local_vec(&cosine_direction(r1, r2)), None)
+            .with_medium_sample(sampler.get_1d());
         //let attenuation = self.albedo.value(0.0, 0.0, &rec.p);
         let u: f64 = rec.texture_coord.unwrap_or_default().u;
         let v: f64 = rec.texture_coord.unwrap_or_default().v;
@@ -179,6 +290,24 @@ impl Material for Lambertian {
         Some((attenuation, scattered))
     }
 
+    fn scattering_pdf(&self, _ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let cosine = dot(&rec.normal, &unit_vector(&scattered.direction()));
+        if cosine <= 0.0 {
+            0.0
+        } else {
+            cosine / PI
+        }
+    }
+
+    fn bsdf(&self, _ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        if dot(&rec.normal, &scattered.direction()) <= 0.0 {
+            return vect!(0, 0, 0);
+        }
+        let u: f64 = rec.texture_coord.unwrap_or_default().u;
+        let v: f64 = rec.texture_coord.unwrap_or_default().v;
+        self.albedo.value(u, v, &rec.p) / PI
+    }
+
     fn albedo(&self) -> TextureType {
         self.albedo.clone()
     }
@@ -216,13 +345,17 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
         let reflected = &reflect(&unit_vector(&ray_in.direction()), &rec.normal);
-        let scattered = Ray::new(
-            &rec.p,
-            &(*reflected + self.fuzz * random_in_unit_sphere()),
-            None,
-        );
+        let (u, v) = sampler.get_2d();
+        let in_sphere = sample_in_unit_sphere(u, v, sampler.get_1d());
+        let scattered = Ray::new(&rec.p, &(*reflected + self.fuzz * in_sphere), None)
+            .with_medium_sample(sampler.get_1d());
         let attenuation = self.albedo().value(0.0, 0.0, reflected);
 
         if dot(&scattered.direction(), &rec.normal) > 0.0 {
@@ -231,6 +364,46 @@ impl Material for Metal {
         None
     }
 
+    // scatter() picks a point in a ball of radius fuzz around the tip of the
+    // (unit) reflected vector. The pdf of a direction w is how much of that
+    // ball the line t*w passes through, weighted by t^2 (the volume of the cone
+    // around it), which works out to (t1^3 - t0^3) / (4 pi fuzz^3) where
+    // t0 and t1 are where the line goes in and out of the ball.
+    fn scattering_pdf(&self, ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        if self.is_specular() {
+            return 0.0;
+        }
+        let reflected = reflect(&unit_vector(&ray_in.direction()), &rec.normal);
+        let w = unit_vector(&scattered.direction());
+        let b = dot(&w, &reflected);
+        let discriminant = b * b - reflected.length_squared() + self.fuzz * self.fuzz;
+        if discriminant <= 0.0 {
+            return 0.0;
+        }
+        let t1 = b + discriminant.sqrt();
+        if t1 <= 0.0 {
+            return 0.0;
+        }
+        let t0 = (b - discriminant.sqrt()).max(0.0);
+        (t1.powi(3) - t0.powi(3)) / (4.0 * PI * self.fuzz.powi(3))
+    }
+
+    // there's no physical model behind the fuzz, so the bsdf is whatever makes
+    // bsdf * cosine / pdf come out to the attenuation scatter() gives.
+    fn bsdf(&self, ray_in: &Ray, rec: &HitRecord, scattered: &Ray) -> Color {
+        let cosine = dot(&rec.normal, &unit_vector(&scattered.direction()));
+        if cosine <= 0.0 {
+            return vect!(0, 0, 0);
+        }
+        let reflected = reflect(&unit_vector(&ray_in.direction()), &rec.normal);
+        self.albedo().value(0.0, 0.0, &reflected)
+            * (self.scattering_pdf(ray_in, rec, scattered) / cosine)
+    }
+
+    fn is_specular(&self) -> bool {
+        self.fuzz <= 0.0
+    }
+
     fn albedo(&self) -> TextureType {
         self.albedo.clone()
     }
@@ -246,11 +419,58 @@ impl Material for Metal {
 }
 mat_display!(Metal);
 
+// How the index of refraction changes with the wavelength, which is what
+// splits white light into a rainbow. Wavelengths are in nanometres, the
+// coefficients are for micrometres, like they're usually published.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dispersion {
+    // n = a + b / λ²
+    Cauchy { a: f64, b: f64 },
+    // n² = 1 + Σ b λ² / (λ² - c), for each of the three terms
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+// the sodium D line, where an index of refraction is usually given for
+pub const SODIUM_D: f64 = 589.3;
+
+impl Dispersion {
+    // a Cauchy curve through ref_idx at SODIUM_D, about as steep as ordinary
+    // crown glass
+    pub fn through(ref_idx: f64) -> Self {
+        const CROWN_GLASS_B: f64 = 0.00420;
+        let lambda = SODIUM_D / 1000.0;
+        Dispersion::Cauchy {
+            a: ref_idx - CROWN_GLASS_B / (lambda * lambda),
+            b: CROWN_GLASS_B,
+        }
+    }
+
+    // Schott N-BK7, the common optical glass
+    pub fn bk7() -> Self {
+        Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    pub fn ior(&self, lambda: f64) -> f64 {
+        let l2 = (lambda / 1000.0) * (lambda / 1000.0);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Dielectric {
     #[allow(dead_code)]
     albedo: TextureType,
     ref_idx: f64,
+    // only used by spectral renders, RGB ones stick to ref_idx
+    dispersion: Dispersion,
 }
 
 impl Dielectric {
@@ -258,15 +478,35 @@ impl Dielectric {
         Dielectric {
             albedo: TextureType::ConstantTexture(ConstantTexture::new(albedo)),
             ref_idx: refractive_index,
+            dispersion: Dispersion::through(refractive_index),
+        }
+    }
+
+    pub fn with_dispersion(mut self, dispersion: Dispersion) -> Self {
+        self.dispersion = dispersion;
+        self
+    }
+
+    // the index of refraction a ray sees
+    pub fn ior(&self, ray: &Ray) -> f64 {
+        match ray.wavelength() {
+            Some(lambda) => self.dispersion.ior(lambda),
+            None => self.ref_idx,
         }
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let ref_idx = self.ior(ray_in);
         let mut outward_normal = rec.normal;
         let reflected = reflect(&ray_in.direction(), &rec.normal);
-        let mut ni_over_nt: f64 = self.ref_idx;
+        let mut ni_over_nt: f64 = ref_idx;
         let attenuation = Color::new(1.0, 1.0, 1.0);
         let reflect_prob: f64;
         let mut refracted = Color::new(0.0, 0.0, 0.0);
@@ -274,27 +514,33 @@ impl Material for Dielectric {
         let cosine: f64 = if dot(&ray_in.direction(), &outward_normal) > 0.0 {
             // otherwise it goes into the object
             outward_normal *= -1.0;
-            self.ref_idx * dot(&ray_in.direction(), &rec.normal) / ray_in.direction().length()
+            ref_idx * dot(&ray_in.direction(), &rec.normal) / ray_in.direction().length()
         } else {
             // otherwise, it's the inverse refractive index
-            ni_over_nt = 1.0 / self.ref_idx;
+            ni_over_nt = 1.0 / ref_idx;
             -dot(&ray_in.direction(), &rec.normal) / ray_in.direction().length()
         };
         if let Some(tmp_refracted) = refract(&ray_in.direction(), outward_normal, ni_over_nt) {
             refracted = tmp_refracted;
-            reflect_prob = schlick(cosine, self.ref_idx);
+            reflect_prob = schlick(cosine, ref_idx);
         } else {
             reflect_prob = 1.0;
         }
-        let mut rng = rand::thread_rng();
-        let scattered: Ray = if rng.gen::<f64>() < reflect_prob {
+        let scattered: Ray = if sampler.get_1d() < reflect_prob {
             Ray::new(&rec.p, &reflected, None)
         } else {
             Ray::new(&rec.p, &refracted, None)
         };
+        let scattered = scattered.with_medium_sample(sampler.get_1d());
         Some((attenuation, scattered))
     }
 
+    // the defaults for the pdf and bsdf are right, glass only ever goes one of
+    // two ways
+    fn is_specular(&self) -> bool {
+        true
+    }
+
     fn albedo(&self) -> TextureType {
         self.albedo.clone()
     }
@@ -324,7 +570,14 @@ impl DiffuseLight {
 }
 
 impl Material for DiffuseLight {
-    fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord) -> Option<(Color, Ray)> {
+    // lights don't reflect anything, so the default pdf and bsdf of zero are
+    // what we want.
+    fn scatter(
+        &self,
+        _ray_in: &Ray,
+        _rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
         None
     }
 
@@ -344,6 +597,10 @@ impl Material for DiffuseLight {
     fn emitted(&self, u: f64, v: f64, p: &Vec3) -> Color {
         self.albedo.value(u, v, p)
     }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
 }
 mat_display!(DiffuseLight);
 
@@ -357,8 +614,16 @@ pub fn schlick(cosine: f64, refractive_index: f64) -> f64 {
 mod test {
     use super::super::color_to_texture;
     #[allow(unused_imports)]
-    use super::{Color, DiffuseLight, Material, MaterialType, NoneMaterial};
+    use super::{
+        Color, Dielectric, DiffuseLight, Lambertian, Material, MaterialType, Metal, NoneMaterial,
+    };
+    use crate::hittable::HitRecord;
+    use crate::ray::Ray;
+    use crate::sampler::IndependentSampler;
+    use crate::util::random_in_unit_sphere;
+    use crate::vec3::{dot, unit_vector};
     use crate::vect;
+    use std::f64::consts::PI;
 
     #[test]
     fn test_base_material() {
@@ -375,5 +640,129 @@ mod test {
         let result = light.emitted(0.0, 0.0, &light_color);
 
         assert_eq!(result, light_color);
+        assert!(light.is_emissive());
+        assert!(!MaterialType::Nothing(NoneMaterial).is_emissive());
+    }
+
+    fn hit_floor() -> (Ray, HitRecord) {
+        let ray_in = Ray::new(&vect!(-1, 1, 0), &vect!(1, -1, 0), None);
+        let mut rec = HitRecord::new(vect!(0, 0, 0), 1.0, MaterialType::default());
+        rec.normal = vect!(0, 1, 0);
+        (ray_in, rec)
+    }
+
+    // whatever direction scatter hands out, bsdf * cos / pdf has to be the
+    // attenuation it gave with it, or mixing in light samples is biased
+    fn check_consistent(mat: &MaterialType) {
+        let (ray_in, rec) = hit_floor();
+        let mut sampler = IndependentSampler::new(7);
+        for _ in 0..100 {
+            if let Some((attenuation, scattered)) = mat.scatter(&ray_in, &rec, &mut sampler) {
+                let pdf = mat.scattering_pdf(&ray_in, &rec, &scattered);
+                assert!(pdf > 0.0);
+                let cosine = dot(&rec.normal, &unit_vector(&scattered.direction()));
+                let weight = mat.bsdf(&ray_in, &rec, &scattered) * cosine / pdf;
+                assert!((weight - attenuation).length() < 1e-9);
+            }
+        }
+    }
+
+    // the pdf should integrate to 1 over the sphere, estimate that with
+    // uniformly picked directions
+    fn integrate_pdf(mat: &MaterialType) -> f64 {
+        let (ray_in, rec) = hit_floor();
+        let n = 200_000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let mut d = random_in_unit_sphere();
+            while d.length_squared() < 1e-6 {
+                d = random_in_unit_sphere();
+            }
+            let scattered = Ray::new(&rec.p, &d, None);
+            sum += mat.scattering_pdf(&ray_in, &rec, &scattered);
+        }
+        sum * 4.0 * PI / n as f64
+    }
+
+    #[test]
+    fn test_lambertian_pdf() {
+        let mat =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.2, 0.4, 0.6))));
+        assert!(!mat.is_specular());
+        check_consistent(&mat);
+        assert!((integrate_pdf(&mat) - 1.0).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_metal_pdf() {
+        let fuzzy = MaterialType::Metal(Metal::new(color_to_texture!(&vect!(0.8, 0.8, 0.8)), 0.5));
+        assert!(!fuzzy.is_specular());
+        check_consistent(&fuzzy);
+        assert!((integrate_pdf(&fuzzy) - 1.0).abs() < 0.03);
+
+        let mirror = MaterialType::Metal(Metal::new(color_to_texture!(&vect!(0.8, 0.8, 0.8)), 0.0));
+        assert!(mirror.is_specular());
+        let (ray_in, rec) = hit_floor();
+        let mut sampler = IndependentSampler::new(7);
+        let (_, scattered) = mirror.scatter(&ray_in, &rec, &mut sampler).unwrap();
+        assert_eq!(mirror.scattering_pdf(&ray_in, &rec, &scattered), 0.0);
+    }
+
+    #[test]
+    fn test_specular_and_lights() {
+        let glass = MaterialType::Dielectric(Dielectric::new(&vect!(1, 1, 1), 1.5));
+        assert!(glass.is_specular());
+        let light =
+            MaterialType::DiffuseLight(DiffuseLight::new(color_to_texture!(&vect!(4, 4, 4))));
+        let (ray_in, rec) = hit_floor();
+        let up = Ray::new(&rec.p, &vect!(0, 1, 0), None);
+        let mut sampler = IndependentSampler::new(7);
+        assert!(light.scatter(&ray_in, &rec, &mut sampler).is_none());
+        assert_eq!(light.scattering_pdf(&ray_in, &rec, &up), 0.0);
+        assert_eq!(light.bsdf(&ray_in, &rec, &up), vect!(0, 0, 0));
+        assert_eq!(glass.scattering_pdf(&ray_in, &rec, &up), 0.0);
+    }
+
+    #[test]
+    fn test_dispersion() {
+        use super::{Dispersion, SODIUM_D};
+        assert!((Dispersion::bk7().ior(587.6) - 1.5168).abs() < 1e-4);
+        assert!((Dispersion::through(1.5).ior(SODIUM_D) - 1.5).abs() < 1e-12);
+        for d in [Dispersion::bk7(), Dispersion::through(1.5)] {
+            // blue bends more than red
+            assert!(d.ior(450.0) > d.ior(650.0));
+        }
+
+        // a ray into glass at an angle, refracted whenever the sampler
+        // doesn't pick the reflection
+        let glass = MaterialType::Dielectric(Dielectric::new(&vect!(1, 1, 1), 1.5));
+        let (ray_in, rec) = hit_floor();
+        let bend = |lambda: Option<f64>| {
+            let ray_in = ray_in.with_wavelength(lambda);
+            let mut sampler = IndependentSampler::new(3);
+            loop {
+                let (attenuation, scattered) = glass.scatter(&ray_in, &rec, &mut sampler).unwrap();
+                assert_eq!(attenuation, vect!(1, 1, 1));
+                assert_eq!(scattered.wavelength(), lambda);
+                if scattered.direction().y < 0.0 {
+                    return unit_vector(&scattered.direction()).x;
+                }
+            }
+        };
+        assert!(bend(Some(450.0)) < bend(Some(650.0)));
+        assert!((bend(None) - bend(Some(SODIUM_D))).abs() < 1e-12);
+
+        // colors are looked up at the wavelength
+        let red =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.9, 0.1, 0.1))));
+        let mut sampler = IndependentSampler::new(3);
+        let (attenuation, _) = red
+            .scatter(&ray_in.with_wavelength(Some(650.0)), &rec, &mut sampler)
+            .unwrap();
+        assert!((attenuation - vect!(0.9, 0.9, 0.9)).length() < 1e-12);
+        let (attenuation, _) = red
+            .scatter(&ray_in.with_wavelength(Some(450.0)), &rec, &mut sampler)
+            .unwrap();
+        assert!((attenuation - vect!(0.1, 0.1, 0.1)).length() < 1e-12);
     }
 }
@@ -0,0 +1,68 @@
+use super::vec3::{unit_vector, Vec3};
+use super::vect;
+
+// An orthonormal basis, w is the "up" we build around (usually a surface
+// normal, or the direction to a light) and u and v fill in the rest.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    pub fn build_from_w(n: &Vec3) -> Onb {
+        let w = unit_vector(n);
+        // any vector that isn't parallel to w will do to start the cross products
+        let a = if w.x.abs() > 0.9 {
+            vect!(0, 1, 0)
+        } else {
+            vect!(1, 0, 0)
+        };
+        let v = unit_vector(&w.cross(&a));
+        let u = w.cross(&v);
+        Onb { u, v, w }
+    }
+
+    // take a vector given in this basis back out to world space
+    pub fn local(&self, a: f64, b: f64, c: f64) -> Vec3 {
+        a * self.u + b * self.v + c * self.w
+    }
+
+    pub fn local_vec(&self, a: &Vec3) -> Vec3 {
+        self.local(a.x, a.y, a.z)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Onb;
+    use crate::vec3::dot;
+    use crate::vect;
+
+    #[test]
+    fn test_onb_is_orthonormal() {
+        for n in [
+            vect!(0, 0, 1),
+            vect!(1, 0, 0),
+            vect!(-3, 2, 7),
+            vect!(0.95, 0.1, 0),
+        ] {
+            let uvw = Onb::build_from_w(&n);
+            assert!((uvw.u.length() - 1.0).abs() < 1e-10);
+            assert!((uvw.v.length() - 1.0).abs() < 1e-10);
+            assert!((uvw.w.length() - 1.0).abs() < 1e-10);
+            assert!(dot(&uvw.u, &uvw.v).abs() < 1e-10);
+            assert!(dot(&uvw.v, &uvw.w).abs() < 1e-10);
+            assert!(dot(&uvw.u, &uvw.w).abs() < 1e-10);
+        }
+    }
+
+    #[test]
+    fn test_onb_local() {
+        let uvw = Onb::build_from_w(&vect!(0, 2, 0));
+        let up = uvw.local(0.0, 0.0, 1.0);
+        assert!((up - vect!(0, 1, 0)).length() < 1e-10);
+        assert_eq!(uvw.local_vec(&vect!(1, 2, 3)), uvw.local(1.0, 2.0, 3.0));
+    }
+}
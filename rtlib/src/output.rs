@@ -0,0 +1,305 @@
+use crate::deflate::zlib_compress;
+use crate::framebuffer::Framebuffer;
+use crate::tonemap::DisplayTransform;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Writing the finished image to a file ourselves, instead of printing P3 and
+// piping it through ppmtobmp. The 8 bit formats go through the display
+// transform, exposure, tone mapping and all, the float ones get the
+// framebuffer as it is.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    // binary P6, what P3 is but a third of the size
+    Ppm,
+    Bmp,
+    Png,
+    // linear floats, see framebuffer.rs
+    Pfm,
+    Hdr,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ppm") => Ok(ImageFormat::Ppm),
+            Some("bmp") => Ok(ImageFormat::Bmp),
+            Some("png") => Ok(ImageFormat::Png),
+            Some("pfm") => Ok(ImageFormat::Pfm),
+            Some("hdr") | Some("rgbe") => Ok(ImageFormat::Hdr),
+            _ => Err(format!(
+                "can't tell what kind of image {} should be, expected .ppm, .bmp, .png, .pfm or .hdr",
+                path.display()
+            )),
+        }
+    }
+}
+
+// the display colors, 3 bytes a pixel, rows from the top
+pub fn to_rgb8(film: &Framebuffer, display: &DisplayTransform) -> Vec<u8> {
+    film.pixels()
+        .iter()
+        .flat_map(|c| display.to_rgb8(c))
+        .collect()
+}
+
+pub fn write_p6(w: &mut impl Write, width: i32, height: i32, rgb: &[u8]) -> io::Result<()> {
+    write!(w, "P6\n{} {}\n255\n", width, height)?;
+    w.write_all(rgb)
+}
+
+// 24 bit BMP. Rows go bottom to top, blue first, each padded to 4 bytes.
+pub fn write_bmp(w: &mut impl Write, width: i32, height: i32, rgb: &[u8]) -> io::Result<()> {
+    let row = width as u32 * 3;
+    let padding = (4 - row % 4) % 4;
+    let image_size = (row + padding) * height as u32;
+    let offset = 14 + 40;
+
+    // BITMAPFILEHEADER
+    w.write_all(b"BM")?;
+    w.write_all(&(offset + image_size).to_le_bytes())?;
+    w.write_all(&[0; 4])?;
+    w.write_all(&offset.to_le_bytes())?;
+    // BITMAPINFOHEADER
+    w.write_all(&40u32.to_le_bytes())?;
+    w.write_all(&width.to_le_bytes())?;
+    w.write_all(&height.to_le_bytes())?;
+    // one plane, 24 bits a pixel, not compressed
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&24u16.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&image_size.to_le_bytes())?;
+    // 72 dpi, and no palette
+    w.write_all(&2835i32.to_le_bytes())?;
+    w.write_all(&2835i32.to_le_bytes())?;
+    w.write_all(&[0; 8])?;
+
+    for line in rgb.chunks(row.max(1) as usize).rev() {
+        for pixel in line.chunks(3) {
+            w.write_all(&[pixel[2], pixel[1], pixel[0]])?;
+        }
+        w.write_all(&[0; 3][..padding as usize])?;
+    }
+    Ok(())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn write_chunk(w: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let mut crc_data = kind.to_vec();
+    crc_data.extend_from_slice(data);
+    w.write_all(&crc32(&crc_data).to_be_bytes())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// Each row gets whichever of the five PNG filters leaves the smallest
+// numbers behind, the usual guess at what deflate will like best.
+fn filter_rows(width: i32, rgb: &[u8]) -> Vec<u8> {
+    let row = width as usize * 3;
+    let mut out = Vec::with_capacity(rgb.len() + rgb.len() / row.max(1));
+    let zeros = vec![0u8; row];
+    let mut above: &[u8] = &zeros;
+    for line in rgb.chunks(row.max(1)) {
+        let left = |i: usize| if i >= 3 { line[i - 3] } else { 0 };
+        let upper_left = |i: usize| if i >= 3 { above[i - 3] } else { 0 };
+        let filtered: Vec<Vec<u8>> = (0..5u8)
+            .map(|filter| {
+                let mut f = vec![filter];
+                f.extend((0..line.len()).map(|i| {
+                    let predicted = match filter {
+                        0 => 0,
+                        1 => left(i),
+                        2 => above[i],
+                        3 => ((left(i) as u16 + above[i] as u16) / 2) as u8,
+                        _ => paeth(left(i), above[i], upper_left(i)),
+                    };
+                    line[i].wrapping_sub(predicted)
+                }));
+                f
+            })
+            .collect();
+        let cost = |f: &Vec<u8>| -> u64 {
+            f[1..]
+                .iter()
+                .map(|&b| (b as i8).unsigned_abs() as u64)
+                .sum()
+        };
+        out.extend(filtered.iter().min_by_key(|f| cost(f)).unwrap());
+        above = line;
+    }
+    out
+}
+
+pub fn write_png(w: &mut impl Write, width: i32, height: i32, rgb: &[u8]) -> io::Result<()> {
+    w.write_all(b"\x89PNG\r\n\x1a\n")?;
+    let mut header = Vec::new();
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // 8 bit RGB, deflate, adaptive filtering, not interlaced
+    header.extend([8, 2, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header)?;
+    write_chunk(w, b"IDAT", &zlib_compress(&filter_rows(width, rgb)))?;
+    write_chunk(w, b"IEND", &[])
+}
+
+pub fn write_image(
+    w: &mut impl Write,
+    format: ImageFormat,
+    film: &Framebuffer,
+    display: &DisplayTransform,
+) -> io::Result<()> {
+    let (width, height) = (film.width(), film.height());
+    match format {
+        ImageFormat::Ppm => write_p6(w, width, height, &to_rgb8(film, display)),
+        ImageFormat::Bmp => write_bmp(w, width, height, &to_rgb8(film, display)),
+        ImageFormat::Png => write_png(w, width, height, &to_rgb8(film, display)),
+        ImageFormat::Pfm => film.write_pfm(w),
+        ImageFormat::Hdr => film.write_rgbe(w),
+    }
+}
+
+// in whatever format the extension says
+pub fn save_image(path: &Path, film: &Framebuffer, display: &DisplayTransform) -> io::Result<()> {
+    let format = ImageFormat::from_path(path)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut w = BufWriter::new(File::create(path)?);
+    write_image(&mut w, format, film, display)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, filter_rows, write_bmp, write_p6, write_png, ImageFormat};
+    use crate::deflate::test::zlib_decompress;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::path::Path;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            ImageFormat::from_path(Path::new("a/b.PNG")),
+            Ok(ImageFormat::Png)
+        );
+        assert_eq!(
+            ImageFormat::from_path(Path::new("x.bmp")),
+            Ok(ImageFormat::Bmp)
+        );
+        assert_eq!(
+            ImageFormat::from_path(Path::new("x.hdr")),
+            Ok(ImageFormat::Hdr)
+        );
+        assert!(ImageFormat::from_path(Path::new("x.jpg")).is_err());
+        assert!(ImageFormat::from_path(Path::new("x")).is_err());
+    }
+
+    #[test]
+    fn test_p6_and_bmp() {
+        let rgb = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let mut p6 = Vec::new();
+        write_p6(&mut p6, 2, 2, &rgb).unwrap();
+        assert_eq!(&p6[..11], b"P6\n2 2\n255\n");
+        assert_eq!(&p6[11..], &rgb);
+
+        let mut bmp = Vec::new();
+        write_bmp(&mut bmp, 2, 2, &rgb).unwrap();
+        // 6 bytes a row padded to 8, two rows
+        assert_eq!(bmp.len(), 54 + 16);
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(u32::from_le_bytes([bmp[2], bmp[3], bmp[4], bmp[5]]), 70);
+        // bottom row first, BGR
+        assert_eq!(&bmp[54..62], &[9, 8, 7, 12, 11, 10, 0, 0]);
+        assert_eq!(&bmp[62..70], &[3, 2, 1, 6, 5, 4, 0, 0]);
+    }
+
+    #[test]
+    fn test_png() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+
+        // a gradient with some noise on it, so the filters have work to do
+        let mut rng = StdRng::seed_from_u64(2);
+        let (width, height) = (37, 21);
+        let rgb: Vec<u8> = (0..width * height * 3)
+            .map(|i| (i / 3 % width * 5 + i / 3 / width * 3 + rng.gen_range(0..4)) as u8)
+            .collect();
+        let mut png = Vec::new();
+        write_png(&mut png, width as i32, height as i32, &rgb).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+
+        // walk the chunks, checking their crcs, and undo the filters
+        let mut chunks = Vec::new();
+        let mut at = 8;
+        while at < png.len() {
+            let length =
+                u32::from_be_bytes([png[at], png[at + 1], png[at + 2], png[at + 3]]) as usize;
+            let body = &png[at + 4..at + 8 + length];
+            let crc = &png[at + 8 + length..at + 12 + length];
+            assert_eq!(crc32(body).to_be_bytes(), crc);
+            chunks.push((body[..4].to_vec(), body[4..].to_vec()));
+            at += 12 + length;
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], b"IDAT", b"IEND"]);
+        let filtered = zlib_decompress(&chunks[1].1);
+        assert_eq!(filtered, filter_rows(width as i32, &rgb));
+
+        let row = width * 3;
+        let mut image: Vec<u8> = Vec::new();
+        for (y, line) in filtered.chunks(row + 1).enumerate() {
+            for i in 0..row {
+                let left = if i >= 3 { image[y * row + i - 3] } else { 0 };
+                let above = if y > 0 { image[(y - 1) * row + i] } else { 0 };
+                let upper_left = if y > 0 && i >= 3 {
+                    image[(y - 1) * row + i - 3]
+                } else {
+                    0
+                };
+                let predicted = match line[0] {
+                    0 => 0,
+                    1 => left,
+                    2 => above,
+                    3 => ((left as u16 + above as u16) / 2) as u8,
+                    _ => super::paeth(left, above, upper_left),
+                };
+                image.push(line[i + 1].wrapping_add(predicted));
+            }
+        }
+        assert_eq!(image, rgb);
+    }
+}
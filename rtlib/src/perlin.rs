@@ -19,11 +19,16 @@ unsafe impl Sync for Perlin {}
 
 impl Perlin {
     pub fn new() -> Self {
+        Perlin::with_rng(&mut rand::thread_rng())
+    }
+
+    // the same rng seeded the same way always gives the same noise
+    pub fn with_rng(rng: &mut impl Rng) -> Self {
         Perlin {
-            ranvec: Perlin::perlin_generate(),
-            perm_x: Perlin::perlin_generate_perm(),
-            perm_y: Perlin::perlin_generate_perm(),
-            perm_z: Perlin::perlin_generate_perm(),
+            ranvec: Perlin::perlin_generate(rng),
+            perm_x: Perlin::perlin_generate_perm(rng),
+            perm_y: Perlin::perlin_generate_perm(rng),
+            perm_z: Perlin::perlin_generate_perm(rng),
         }
     }
     pub fn noise(&self, p: &Vec3) -> f64 {
@@ -51,9 +56,8 @@ impl Perlin {
         trilinear_interp(&c, u, v, w)
     }
 
-    pub fn perlin_generate() -> Box<[Vec3]> {
+    pub fn perlin_generate(rng: &mut impl Rng) -> Box<[Vec3]> {
         let mut p: Vec<Vec3> = Vec::with_capacity(MAX_PERLIN_PERM);
-        let mut rng = rand::thread_rng();
 
         for _i in 0..MAX_PERLIN_PERM {
             p.push(vec3::unit_vector(&vect!(
@@ -66,21 +70,20 @@ impl Perlin {
         p.into_boxed_slice()
     }
 
-    pub fn permute(p: &mut [i64]) {
-        let mut rng = rand::thread_rng();
+    pub fn permute(p: &mut [i64], rng: &mut impl Rng) {
         for i in (0..(p.len() - 1)).rev() {
             let target: usize = (rng.gen::<f64>() * ((i + 1) as f64)) as usize;
             p.swap(i, target);
         }
     }
 
-    pub fn perlin_generate_perm() -> Box<[i64]> {
+    pub fn perlin_generate_perm(rng: &mut impl Rng) -> Box<[i64]> {
         let mut p: Vec<i64> = Vec::with_capacity(MAX_PERLIN_PERM);
 
         for i in 0..MAX_PERLIN_PERM {
             p.push(i as i64);
         }
-        Self::permute(&mut p);
+        Self::permute(&mut p, rng);
         p.into_boxed_slice()
     }
 
@@ -140,8 +143,23 @@ mod test {
 
         let mut perm_bxslice = bxslice.clone();
 
-        Perlin::permute(&mut perm_bxslice);
+        Perlin::permute(&mut perm_bxslice, &mut rand::thread_rng());
 
         assert_ne!(bxslice, perm_bxslice);
     }
+
+    #[test]
+    fn test_seeded() {
+        use crate::vect;
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let a = Perlin::with_rng(&mut StdRng::seed_from_u64(7));
+        let b = Perlin::with_rng(&mut StdRng::seed_from_u64(7));
+        let c = Perlin::with_rng(&mut StdRng::seed_from_u64(8));
+        let points = [vect!(0.5, 1.25, -3.5), vect!(10.1, 0.3, 7.7)];
+        for p in &points {
+            assert_eq!(a.noise(p), b.noise(p));
+        }
+        assert!(points.iter().any(|p| a.noise(p) != c.noise(p)));
+    }
 }
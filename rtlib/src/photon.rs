@@ -0,0 +1,454 @@
+use crate::{
+    environment::Background,
+    hitlist::HitList,
+    hittable::{HitRecord, Hittable},
+    materials::Material,
+    onb::Onb,
+    ray::Ray,
+    rectangle::Axis,
+    sampler::Sampler,
+    stats::{self, count_path, Counter},
+    util::{color, cosine_direction, russian_roulette, sample_one_light},
+    vec3::{dot, Color, Point3, Vec3},
+};
+use std::f64::consts::PI;
+
+// Photon mapping for caustics. Light that goes through glass or off a mirror
+// before landing on something diffuse can't be found by a shadow ray, and a
+// bounce from the diffuse surface only finds it by luck (it has to go through
+// the glass and then also hit the light). So instead we send photons out from
+// the lights, and wherever one lands on a diffuse surface after going through
+// glass or mirrors we keep it. The camera pass then adds up the photons around
+// every diffuse hit to get that light.
+//
+// Only these caustic paths go in the map. Everything else is left to the
+// shadow rays and bounces of the camera pass, which handle it just fine.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Photon {
+    pub p: Point3,
+    // the way it was travelling when it landed
+    pub direction: Vec3,
+    pub power: Color,
+}
+
+#[derive(Clone, Default)]
+pub struct PhotonMap {
+    // kept as a kd-tree, every slice of this is split at its middle photon
+    // along axes[middle], with the smaller ones to the left
+    photons: Vec<Photon>,
+    axes: Vec<Axis>,
+    radius: f64,
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>, radius: f64) -> Self {
+        let mut photons = photons;
+        let mut axes = vec![Axis::default(); photons.len()];
+        build_tree(&mut photons, &mut axes);
+        PhotonMap {
+            photons,
+            axes,
+            radius,
+        }
+    }
+
+    // Sends count photons out from the lights and keeps the ones that make it
+    // to a diffuse surface through glass or off a mirror. Photons are followed
+    // for up to max_depth bounces. The power of all of them adds up to what the
+    // lights give off. Photons are all sent at time 0. Photon number n is
+    // sample n of pixel (-1, -1) as far as the sampler is concerned, so the
+    // samplers that spread their samples out spread the photons out too.
+    pub fn emit(
+        world: &dyn Hittable,
+        lights: &HitList,
+        count: usize,
+        max_depth: i32,
+        radius: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Self {
+        let mut photons = Vec::new();
+        if lights.list.is_empty() {
+            return PhotonMap::new(photons, radius);
+        }
+        for index in 0..count {
+            sampler.start_pixel_sample(-1, -1, index as u32);
+            let (rec, pdf_pos) = match lights.sample_surface(sampler) {
+                Some(sample) if sample.1 > 0.0 => sample,
+                _ => continue,
+            };
+            // lights shine from both sides, pick one and a cosine weighted
+            // direction on it. The cosine cancels out, leaving 2pi.
+            let side = if sampler.get_1d() < 0.5 {
+                rec.normal
+            } else {
+                -1.0 * rec.normal
+            };
+            let (r1, r2) = sampler.get_2d();
+            let direction = Onb::build_from_w(&side).local_vec(&cosine_direction(r1, r2));
+            let mut power = rec.material.emitted(
+                rec.texture_coord.unwrap_or_default().u,
+                rec.texture_coord.unwrap_or_default().v,
+                &rec.p,
+            ) * (2.0 * PI / (pdf_pos * count as f64));
+            let mut ray = Ray::new(&rec.p, &direction, None).with_medium_sample(sampler.get_1d());
+            stats::count(Counter::LightRays);
+            for bounces in 0..max_depth.max(0) {
+                let hr = match world.hit(&ray, 0.001, f64::INFINITY) {
+                    Some(hr) => hr,
+                    None => break,
+                };
+                if !hr.material.is_specular() {
+                    // straight from the light is direct lighting, the shadow
+                    // rays already have that
+                    if bounces > 0 && !hr.material.is_emissive() {
+                        photons.push(Photon {
+                            p: hr.p,
+                            direction: ray.direction(),
+                            power,
+                        });
+                    }
+                    break;
+                }
+                let (attenuation, sray) = match hr.material.scatter(&ray, &hr, sampler) {
+                    Some(scattered) => scattered,
+                    None => break,
+                };
+                power *= attenuation;
+                if !russian_roulette(&mut power, bounces, sampler) {
+                    break;
+                }
+                stats::count(Counter::BounceRays);
+                ray = sray;
+            }
+        }
+        PhotonMap::new(photons, radius)
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    pub fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    // calls found with every photon within radius of p
+    pub fn gather(&self, p: &Point3, radius: f64, found: &mut impl FnMut(&Photon)) {
+        self.gather_range(0, self.photons.len(), p, radius, found);
+    }
+
+    fn gather_range(
+        &self,
+        lo: usize,
+        hi: usize,
+        p: &Point3,
+        radius: f64,
+        found: &mut impl FnMut(&Photon),
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        if (photon.p - *p).length_squared() <= radius * radius {
+            found(photon);
+        }
+        let axis = self.axes[mid];
+        let offset = coordinate(p, axis) - coordinate(&photon.p, axis);
+        // the side we're on first, the other only if the sphere reaches over
+        let (near, far) = if offset < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.gather_range(near.0, near.1, p, radius, found);
+        if offset.abs() <= radius {
+            self.gather_range(far.0, far.1, p, radius, found);
+        }
+    }
+
+    // The caustic light leaving rec back along ray_in. Each photon nearby is
+    // lit through the bsdf, and spread over the disc we gathered from.
+    pub fn radiance(&self, ray_in: &Ray, rec: &HitRecord) -> Color {
+        if self.photons.is_empty() || self.radius <= 0.0 {
+            return Color::default();
+        }
+        let mut total = Color::default();
+        self.gather(&rec.p, self.radius, &mut |photon| {
+            let towards_light = Ray::new(&rec.p, &(-1.0 * photon.direction), None);
+            // photons that landed on the other side of the surface don't count
+            let same_side =
+                dot(&photon.direction, &rec.normal) * dot(&ray_in.direction(), &rec.normal) > 0.0;
+            if !same_side {
+                return;
+            }
+            total += rec.material.bsdf(ray_in, rec, &towards_light) * photon.power;
+        });
+        total / (PI * self.radius * self.radius)
+    }
+}
+
+fn coordinate(p: &Point3, axis: Axis) -> f64 {
+    match axis {
+        Axis::X => p.x,
+        Axis::Y => p.y,
+        Axis::Z => p.z,
+    }
+}
+
+// splits at the median along whichever axis the photons are most spread out on
+fn build_tree(photons: &mut [Photon], axes: &mut [Axis]) {
+    if photons.is_empty() {
+        return;
+    }
+    let mut min = photons[0].p;
+    let mut max = photons[0].p;
+    for photon in photons.iter() {
+        min = Vec3::new(
+            min.x.min(photon.p.x),
+            min.y.min(photon.p.y),
+            min.z.min(photon.p.z),
+        );
+        max = Vec3::new(
+            max.x.max(photon.p.x),
+            max.y.max(photon.p.y),
+            max.z.max(photon.p.z),
+        );
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {
+        Axis::X
+    } else if extent.y >= extent.z {
+        Axis::Y
+    } else {
+        Axis::Z
+    };
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| {
+        coordinate(&a.p, axis).total_cmp(&coordinate(&b.p, axis))
+    });
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build_tree(left, left_axes);
+    build_tree(&mut right[1..], &mut right_axes[1..]);
+}
+
+// Same as color_direct_lighting(), except that light reaching a diffuse surface
+// through glass or off a mirror comes from the photon map. So when a bounce
+// from a diffuse hit goes through glass and runs into a light, it's already
+// been counted by the photons there and we skip it.
+#[allow(unused_imports, dead_code)]
+pub fn color_photon_map(
+    ray: &Ray,
+    world: &dyn Hittable,
+    lights: &HitList,
+    photons: &PhotonMap,
+    depth: i32,
+    background: &Background,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    if lights.list.is_empty() && !background.is_sampled() {
+        return color(ray, world, depth, background, sampler);
+    }
+    let mut radiance = Color::default();
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    // whether a shadow ray was sent from where the bounce started
+    let mut sampled = false;
+    // whether there's been a diffuse hit, and if since then it's been nothing
+    // but glass and mirrors
+    let mut seen_diffuse = false;
+    let mut caustic = false;
+    let mut tmpray = *ray;
+    let mut bounces = 0;
+    loop {
+        let hr = match world.hit(&tmpray, 0.001, f64::INFINITY) {
+            Some(hr) => hr,
+            None => {
+                if !sampled || !background.is_sampled() {
+                    radiance += throughput * background.escaped(&tmpray);
+                }
+                break;
+            }
+        };
+        let emitted = hr.material.emitted(
+            hr.texture_coord.unwrap_or_default().u,
+            hr.texture_coord.unwrap_or_default().v,
+            &hr.p,
+        );
+        // the shadow rays and the photons only count the lights in the list
+        let already_counted = (sampled || caustic) && lights.surface_pdf(&hr.p) > 0.0;
+        if !already_counted {
+            radiance += throughput * emitted;
+        }
+
+        let scattered = if bounces < depth {
+            hr.material.scatter(&tmpray, &hr, sampler)
+        } else {
+            None
+        };
+        let (attenuation, sray) = match scattered {
+            Some(scattered) => scattered,
+            None => {
+                radiance += throughput * background.ended();
+                break;
+            }
+        };
+        if hr.material.is_specular() {
+            sampled = false;
+            caustic = seen_diffuse;
+        } else if hr.material.scattering_pdf(&tmpray, &hr, &sray) > 0.0 {
+            radiance += throughput
+                * (sample_one_light(world, lights, background, &tmpray, &hr, false, sampler)
+                    + photons.radiance(&tmpray, &hr));
+            sampled = true;
+            seen_diffuse = true;
+            caustic = false;
+        } else {
+            // smoke and the like, photons aren't gathered here so whatever
+            // light comes after it is ours to count
+            sampled = false;
+            seen_diffuse = false;
+            caustic = false;
+        }
+        throughput *= attenuation;
+        if !russian_roulette(&mut throughput, bounces, sampler) {
+            break;
+        }
+        stats::count(Counter::BounceRays);
+        tmpray = sray;
+        bounces += 1;
+    }
+    count_path(bounces);
+    radiance
+}
+
+#[cfg(test)]
+mod test {
+    use super::{color_photon_map, Photon, PhotonMap};
+    use crate::{
+        color_to_texture,
+        environment::Background,
+        hitlist::HitList,
+        hittable::{Hittable, Hitters},
+        materials::{DiffuseLight, Lambertian, MaterialType, Metal},
+        ray,
+        rectangle::{Axis, Rect},
+        sampler::IndependentSampler,
+        util::color_mis,
+        vec3::{Color, Vec3},
+        vect,
+    };
+    use rand::Rng;
+
+    #[test]
+    fn test_gather() {
+        // the tree has to find exactly what looking through them all does
+        let mut rng = rand::thread_rng();
+        let photons: Vec<Photon> = (0..2000)
+            .map(|_| Photon {
+                p: vect!(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>() * 0.1),
+                direction: vect!(0, -1, 0),
+                power: vect!(1, 1, 1),
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone(), 0.1);
+        assert_eq!(map.len(), 2000);
+        for _ in 0..50 {
+            let p = vect!(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>() * 0.1);
+            let radius = rng.gen::<f64>() * 0.2;
+            let mut found: Vec<Vec3> = Vec::new();
+            map.gather(&p, radius, &mut |photon| found.push(photon.p));
+            let expected = photons
+                .iter()
+                .filter(|photon| (photon.p - p).length_squared() <= radius * radius)
+                .count();
+            assert_eq!(found.len(), expected);
+            assert!(found
+                .iter()
+                .all(|q| (*q - p).length_squared() <= radius * radius));
+        }
+    }
+
+    #[test]
+    fn test_color_photon_map() {
+        // a light over a floor, with a mirror standing at one end. The floor
+        // gets the light straight from above and also off the mirror, the
+        // second part is what the photons are for.
+        let grey =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.5, 0.5, 0.5))));
+        let mirror = MaterialType::Metal(Metal::new(color_to_texture!(&vect!(0.9, 0.9, 0.9)), 0.0));
+        let light =
+            MaterialType::DiffuseLight(DiffuseLight::new(color_to_texture!(&vect!(4, 4, 4))));
+        let mut world = HitList::new();
+        world.add(Hitters::Rect(Rect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            0.0,
+            &grey,
+            Axis::Y,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            0.0,
+            1.0,
+            -1.0,
+            1.0,
+            -1.0,
+            &mirror,
+            Axis::X,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            -0.75,
+            -0.25,
+            -0.25,
+            0.25,
+            0.5,
+            &light,
+            Axis::Y,
+        )));
+        let mut lights = HitList::new();
+        lights.list = world.emitters();
+
+        // nothing specular in the way of the floor means no photons
+        let mut bare = HitList::new();
+        bare.add(world.list[0].clone());
+        bare.add(world.list[2].clone());
+        let mut sampler = IndependentSampler::new(0);
+        assert!(PhotonMap::emit(&bare, &lights, 1000, 5, 0.05, &mut sampler).is_empty());
+
+        let photons = PhotonMap::emit(&world, &lights, 200_000, 5, 0.05, &mut sampler);
+        assert!(!photons.is_empty());
+        let r = ray!(&vect!(0, 0.25, 0), &vect!(0, -1, 0));
+        let n = 20000;
+        let mut mapped = Color::default();
+        let mut mis = Color::default();
+        let mut sampler = IndependentSampler::new(1);
+        for _ in 0..n {
+            mapped += color_photon_map(
+                &r,
+                &world,
+                &lights,
+                &photons,
+                3,
+                &Background::default(),
+                &mut sampler,
+            );
+            mis += color_mis(&r, &world, &lights, 3, &Background::default(), &mut sampler);
+        }
+        let (mapped, mis) = (mapped.x / n as f64, mis.x / n as f64);
+        assert!(mis > 0.0);
+        assert!(
+            (mapped - mis).abs() < 0.05 * mis,
+            "photon map {} mis {}",
+            mapped,
+            mis
+        );
+    }
+}
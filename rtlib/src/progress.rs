@@ -0,0 +1,263 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// For whoever's running a render: how far along it is, and a way to stop it
+// early. A stopped render still finishes up normally, every pixel is the
+// mean of the samples it did get, so it's just a noisier picture (pixels
+// that got none at all are black).
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    // pixels that have had their first pass
+    pub pixels_done: u64,
+    pub pixels: u64,
+    pub samples_done: u64,
+    // the budget, adaptive sampling can finish before it's used up
+    pub samples: u64,
+    // what a resumed render already had from its checkpoint, which doesn't
+    // say anything about how fast this one's going
+    pub samples_resumed: u64,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.samples == 0 {
+            return 1.0;
+        }
+        (self.samples_done as f64 / self.samples as f64).min(1.0)
+    }
+
+    // how much longer at the rate it's been going, once there's a rate
+    pub fn eta(&self) -> Option<Duration> {
+        let done = self.samples_done.saturating_sub(self.samples_resumed);
+        if done == 0 {
+            return None;
+        }
+        let left = self.samples.saturating_sub(self.samples_done);
+        Some(self.elapsed.mul_f64(left as f64 / done as f64))
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Calculated {}/{} pixels, {}/{} samples ({:.1}%)",
+            self.pixels_done,
+            self.pixels,
+            self.samples_done,
+            self.samples,
+            self.fraction() * 100.0
+        )?;
+        match self.eta() {
+            Some(eta) => {
+                let seconds = eta.as_secs();
+                write!(
+                    f,
+                    ", {}:{:02}:{:02} left",
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// Stops a render from another thread. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Cancelled,
+    OutOfTime,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Cancelled => write!(f, "cancelled"),
+            StopReason::OutOfTime => write!(f, "out of time"),
+        }
+    }
+}
+
+type ProgressCallback = Box<dyn FnMut(&Progress) + Send>;
+
+// What a render gets handed to report its progress to and check whether it
+// should stop. The time budget starts counting when it's built. It's checked
+// between pixels from all of the render threads, the progress callback is
+// only ever called from one of them at a time.
+pub struct RenderControl {
+    cancel: CancelToken,
+    started: Instant,
+    // where the progress is timed from, a render that shares this with
+    // others before it can start it again so they don't slow down its ETA
+    progress_started: Mutex<Instant>,
+    time_budget: Option<Duration>,
+    on_progress: Mutex<Option<ProgressCallback>>,
+}
+
+impl Default for RenderControl {
+    fn default() -> Self {
+        let started = Instant::now();
+        RenderControl {
+            cancel: CancelToken::new(),
+            started,
+            progress_started: Mutex::new(started),
+            time_budget: None,
+            on_progress: Mutex::new(None),
+        }
+    }
+}
+
+impl RenderControl {
+    pub fn new() -> Self {
+        RenderControl::default()
+    }
+
+    pub fn with_cancel_token(self, cancel: CancelToken) -> Self {
+        RenderControl { cancel, ..self }
+    }
+
+    pub fn with_time_budget(self, time_budget: Duration) -> Self {
+        RenderControl {
+            time_budget: Some(time_budget),
+            ..self
+        }
+    }
+
+    pub fn with_progress(self, on_progress: impl FnMut(&Progress) + Send + 'static) -> Self {
+        RenderControl {
+            on_progress: Mutex::new(Some(Box::new(on_progress))),
+            ..self
+        }
+    }
+
+    // progress goes down a channel instead, it's fine if nobody's listening
+    pub fn with_progress_channel(self, progress: Sender<Progress>) -> Self {
+        self.with_progress(move |p| {
+            let _ = progress.send(*p);
+        })
+    }
+
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // the time budget still counts from when this was built
+    pub fn restart_progress(&self) {
+        *self.progress_started.lock().unwrap() = Instant::now();
+    }
+
+    pub fn progress_elapsed(&self) -> Duration {
+        self.progress_started.lock().unwrap().elapsed()
+    }
+
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        if self.cancel.is_cancelled() {
+            Some(StopReason::Cancelled)
+        } else if self
+            .time_budget
+            .is_some_and(|budget| self.elapsed() >= budget)
+        {
+            Some(StopReason::OutOfTime)
+        } else {
+            None
+        }
+    }
+
+    pub fn should_stop(&self) -> bool {
+        self.stop_reason().is_some()
+    }
+
+    pub fn report(&self, progress: &Progress) {
+        if let Some(on_progress) = self.on_progress.lock().unwrap().as_mut() {
+            on_progress(progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CancelToken, Progress, RenderControl, StopReason};
+    use std::time::Duration;
+
+    #[test]
+    fn test_progress() {
+        let mut progress = Progress {
+            pixels_done: 50,
+            pixels: 100,
+            samples_done: 400,
+            samples: 1000,
+            samples_resumed: 100,
+            elapsed: Duration::from_secs(30),
+        };
+        assert_eq!(progress.fraction(), 0.4);
+        // 300 samples in 30s, 600 to go
+        assert_eq!(progress.eta(), Some(Duration::from_secs(60)));
+        assert_eq!(
+            progress.to_string(),
+            "Calculated 50/100 pixels, 400/1000 samples (40.0%), 0:01:00 left"
+        );
+        progress.samples_done = 100;
+        assert_eq!(progress.eta(), None);
+    }
+
+    #[test]
+    fn test_control() {
+        let control = RenderControl::new();
+        assert_eq!(control.stop_reason(), None);
+        control.cancel_token().clone().cancel();
+        assert_eq!(control.stop_reason(), Some(StopReason::Cancelled));
+
+        let token = CancelToken::new();
+        let control = RenderControl::new()
+            .with_cancel_token(token.clone())
+            .with_time_budget(Duration::ZERO);
+        assert_eq!(control.stop_reason(), Some(StopReason::OutOfTime));
+        token.cancel();
+        assert_eq!(control.stop_reason(), Some(StopReason::Cancelled));
+        assert!(!RenderControl::new()
+            .with_time_budget(Duration::from_secs(3600))
+            .should_stop());
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let control = RenderControl::new().with_progress_channel(sender);
+        let progress = Progress {
+            pixels_done: 1,
+            pixels: 2,
+            samples_done: 3,
+            samples: 4,
+            samples_resumed: 0,
+            elapsed: Duration::from_millis(5),
+        };
+        control.report(&progress);
+        drop(control);
+        assert_eq!(receiver.iter().collect::<Vec<_>>(), vec![progress]);
+    }
+}
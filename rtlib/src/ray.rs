@@ -6,6 +6,12 @@ pub struct Ray {
     pub a: Vec3,
     pub b: Vec3,
     time: f64,
+    // the random number a ConstantMedium uses to pick how far this ray gets
+    // into it, see with_medium_sample()
+    medium_sample: Option<f64>,
+    // in nanometres, for the one wavelength a spectral render follows down
+    // the path, see spectrum.rs. RGB renders don't have one.
+    wavelength: Option<f64>,
 }
 
 impl Ray {
@@ -14,6 +20,8 @@ impl Ray {
             a: *a,
             b: *b,
             time: optional_arg::<f64>(t),
+            medium_sample: None,
+            wavelength: None,
         }
     }
 
@@ -36,6 +44,36 @@ impl Ray {
     pub fn time(&self) -> f64 {
         self.time
     }
+
+    // Volumes are picked in hit(), which has no sampler to ask, so the sample
+    // comes along with the ray. Rays without one fall back to thread_rng().
+    pub fn with_medium_sample(mut self, u: f64) -> Self {
+        self.medium_sample = Some(u);
+        self
+    }
+
+    pub fn medium_sample(&self) -> Option<f64> {
+        self.medium_sample
+    }
+
+    pub fn with_wavelength(mut self, wavelength: Option<f64>) -> Self {
+        self.wavelength = wavelength;
+        self
+    }
+
+    pub fn wavelength(&self) -> Option<f64> {
+        self.wavelength
+    }
+
+    // the same ray along a different line, for instances that move rays into
+    // their object's space
+    pub fn moved(&self, origin: &Vec3, direction: &Vec3) -> Self {
+        Self {
+            a: *origin,
+            b: *direction,
+            ..*self
+        }
+    }
 }
 
 #[cfg(test)]
@@ -81,4 +119,15 @@ mod test {
         assert_eq!(r.point_at_parameter(p), r.a + r.b * p);
         assert_eq!(r.point_at_parameter(p), r.a + p * r.b);
     }
+
+    #[test]
+    fn test_wavelength() {
+        let v = Vec3::new(1.0, 2.0, 3.0);
+        let r = ray::Ray::new(&v, &v, None);
+        assert_eq!(r.wavelength(), None);
+
+        // instances move rays around, the wavelength has to come along
+        let r = r.with_wavelength(Some(550.0));
+        assert_eq!(r.moved(&v, &(2.0 * v)).wavelength(), Some(550.0));
+    }
 }
@@ -1,8 +1,11 @@
 use super::{
     aabb::{AabbF, BoundingBox},
-    hittable::{HitRecord, Hittable, TextureCoord},
-    materials::{MaterialType, NoneMaterial},
+    hittable::{HitRecord, Hittable, Hitters, TextureCoord},
+    materials::{Material, MaterialType, NoneMaterial},
     ray::Ray,
+    sampler::Sampler,
+    stats::{count, Counter},
+    vec3::{dot, Point3, Vec3},
     vect,
 };
 
@@ -103,6 +106,7 @@ impl std::fmt::Display for Rect {
 
 impl Hittable for Rect {
     fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Option<HitRecord> {
+        count(Counter::IntersectionTests);
         //eprintln!("Rect({})::hit({:?}, {}, {})", &self, &r, &tmin, &tmax);
         let t: f64;
         let axis0: f64;
@@ -183,6 +187,81 @@ impl Hittable for Rect {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    // picking a point uniformly on the rect has a pdf of 1/area, to turn that
+    // into a pdf over directions we scale by distance^2/cosine.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        match self.hit(&Ray::new(origin, direction, None), 0.001, f64::INFINITY) {
+            Some(rec) => {
+                let area = (self.axis0_max - self.axis0_min) * (self.axis1_max - self.axis1_min);
+                let distance_squared = rec.t * rec.t * direction.length_squared();
+                let cosine = (dot(direction, &rec.normal) / direction.length()).abs();
+                if cosine <= 0.0 {
+                    return 0.0;
+                }
+                distance_squared / (cosine * area)
+            }
+            None => 0.0,
+        }
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (u, v) = sampler.get_2d();
+        let axis0 = self.axis0_min + u * (self.axis0_max - self.axis0_min);
+        let axis1 = self.axis1_min + v * (self.axis1_max - self.axis1_min);
+        let on_rect = match self.aligned_axis {
+            Axis::Z => vect!(axis0, axis1, self.k),
+            Axis::Y => vect!(axis0, self.k, axis1),
+            Axis::X => vect!(self.k, axis0, axis1),
+        };
+        on_rect - *origin
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        if self.material.is_emissive() {
+            vec![Hitters::Rect(self.clone())]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let area = (self.axis0_max - self.axis0_min) * (self.axis1_max - self.axis1_min);
+        if area <= 0.0 {
+            return None;
+        }
+        let (u, v) = sampler.get_2d();
+        let axis0 = self.axis0_min + u * (self.axis0_max - self.axis0_min);
+        let axis1 = self.axis1_min + v * (self.axis1_max - self.axis1_min);
+        let (p, normal) = match self.aligned_axis {
+            Axis::Z => (vect!(axis0, axis1, self.k), vect!(0, 0, 1)),
+            Axis::Y => (vect!(axis0, self.k, axis1), vect!(0, 1, 0)),
+            Axis::X => (vect!(self.k, axis0, axis1), vect!(1, 0, 0)),
+        };
+        let mut rec = HitRecord::new(p, 0.0, self.material.clone());
+        rec.normal = normal;
+        rec.texture_coord = Some(TextureCoord { u, v });
+        Some((rec, 1.0 / area))
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        let (k, axis0, axis1) = match self.aligned_axis {
+            Axis::Z => (p.z, p.x, p.y),
+            Axis::Y => (p.y, p.x, p.z),
+            Axis::X => (p.x, p.y, p.z),
+        };
+        // hit points come back with a little floating point noise on them
+        let eps = 1e-6 * (1.0 + self.k.abs());
+        if (k - self.k).abs() > eps
+            || axis0 < self.axis0_min - eps
+            || axis0 > self.axis0_max + eps
+            || axis1 < self.axis1_min - eps
+            || axis1 > self.axis1_max + eps
+        {
+            return 0.0;
+        }
+        1.0 / ((self.axis0_max - self.axis0_min) * (self.axis1_max - self.axis1_min))
+    }
 }
 
 #[cfg(test)]
@@ -193,6 +272,7 @@ mod test {
         hittable::{HitRecord, Hittable, TextureCoord},
         materials::{DiffuseLight, MaterialType},
         ray::Ray,
+        sampler::IndependentSampler,
         textures::{ConstantTexture, TextureType},
         vect,
     };
@@ -355,4 +435,32 @@ mod test {
 
         assert_eq!(hr, Some(hr_ans));
     }
+
+    #[test]
+    fn test_rect_light_sampling() {
+        let light = Rect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            2.0,
+            &MaterialType::DiffuseLight(DiffuseLight::new(TextureType::ConstantTexture(
+                ConstantTexture::new(&vect!(4, 4, 4)),
+            ))),
+            Axis::Y,
+        );
+        let origin = vect!(0, 0, 0);
+
+        // straight up we're 2 away and facing it, the area is 4
+        assert!((light.pdf_value(&origin, &vect!(0, 1, 0)) - 1.0).abs() < 1e-10);
+        assert_eq!(light.pdf_value(&origin, &vect!(0, -1, 0)), 0.0);
+
+        let mut sampler = IndependentSampler::new(0);
+        for _ in 0..100 {
+            let to_light = light.random(&origin, &mut sampler);
+            assert!((to_light.y - 2.0).abs() < 1e-10);
+            assert!(light.pdf_value(&origin, &to_light) > 0.0);
+        }
+        assert_eq!(light.emitters().len(), 1);
+    }
 }
@@ -0,0 +1,845 @@
+use crate::adaptive::{AdaptiveSampling, PixelStats};
+use crate::aov::AovPixel;
+use crate::bdpt::color_bdpt;
+use crate::bvh::Bvh;
+use crate::camera::Camera;
+use crate::checkpoint::{tiles, Checkpoint, Tile};
+use crate::environment::Background;
+use crate::film::{FilmTile, Filter, Splat};
+use crate::firefly::{clamp_luminance, MedianOfMeans};
+use crate::framebuffer::Framebuffer;
+use crate::hitlist::HitList;
+use crate::hittable::{Hittable, Hitters};
+use crate::photon::{color_photon_map, PhotonMap};
+use crate::progress::{Progress, RenderControl};
+use crate::ray::Ray;
+use crate::sampler::{Sampler, SamplerKind};
+use crate::spectrum::{sample_wavelength, to_film, to_spectrum};
+use crate::util::{color, color_direct_lighting, color_mis, Integrator};
+use crate::vec3::Color;
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// Everything about a render other than the scene. The defaults are the same
+// as rt's.
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub width: i32,
+    pub height: i32,
+    // per pixel, or the average per pixel with adaptive sampling
+    pub samples: i32,
+    pub max_depth: i32,
+    pub integrator: Integrator,
+    pub sampler: SamplerKind,
+    // the pixels' samplers and the BVH come from this, so it decides the render
+    pub seed: u64,
+    // above 0 turns on adaptive sampling, see adaptive.rs
+    pub noise_threshold: f64,
+    pub min_samples: i32,
+    pub max_samples: i32,
+    pub tile_size: i32,
+    pub filter: Option<Filter>,
+    pub spectral: bool,
+    pub clamp: Option<f64>,
+    pub median_of_means: Option<MedianOfMeans>,
+    pub photons: usize,
+    pub gather_radius: f64,
+    pub background: Background,
+    // only the pixels in here are rendered, the rest are left black
+    pub crop: Option<Crop>,
+    // without it every ray is tested against everything in the world, which
+    // is only good for seeing how much the BVH helps
+    pub bvh: bool,
+}
+
+impl RenderSettings {
+    pub fn new(width: i32, height: i32) -> Self {
+        RenderSettings {
+            width,
+            height,
+            samples: 500,
+            max_depth: 500,
+            integrator: Integrator::Path,
+            sampler: SamplerKind::Independent,
+            seed: 0,
+            noise_threshold: 0.0,
+            min_samples: 16,
+            max_samples: 2000,
+            tile_size: 32,
+            filter: None,
+            spectral: false,
+            clamp: None,
+            median_of_means: None,
+            photons: 200_000,
+            gather_radius: 1.0,
+            background: Background::default(),
+            crop: None,
+            bvh: true,
+        }
+    }
+}
+
+// A part of the image to render on its own, from its top left corner to its
+// bottom right, with y down from the top like the image. The camera still
+// frames the whole image, so the pixels come out just as they would in a
+// full render. It's either in pixels or in fractions of the width and
+// height, which stay put when the image size changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crop {
+    Pixels(Tile),
+    Fraction { x0: f64, y0: f64, x1: f64, y1: f64 },
+}
+
+impl Crop {
+    // the pixels it covers in a width x height image, None if that's none
+    pub fn window(&self, width: i32, height: i32) -> Option<Tile> {
+        let tile = match *self {
+            Crop::Pixels(tile) => tile,
+            // any pixel the crop touches is in
+            Crop::Fraction { x0, y0, x1, y1 } => Tile {
+                x0: (x0 * width as f64).floor() as i32,
+                y0: (y0 * height as f64).floor() as i32,
+                x1: (x1 * width as f64).ceil() as i32,
+                y1: (y1 * height as f64).ceil() as i32,
+            },
+        };
+        tile.intersection(&Tile {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        })
+    }
+}
+
+// "x0,y0,x1,y1", in fractions if any of them has a decimal point and in
+// pixels if not. The end is just past the last pixel, so 0,0,10,10 is
+// 10x10 pixels.
+impl std::str::FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        if parts.len() != 4 {
+            return Err(format!(
+                "'{}' isn't a crop, expected x0,y0,x1,y1 like 100,50,300,200 or 0.25,0.1,0.75,0.6",
+                s
+            ));
+        }
+        if parts.iter().any(|part| part.contains('.')) {
+            let v = parts
+                .iter()
+                .map(|part| part.parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("'{}' isn't a crop: {}", s, err))?;
+            if v.iter().any(|v| !(0.0..=1.0).contains(v)) || v[0] >= v[2] || v[1] >= v[3] {
+                return Err(format!(
+                    "'{}' isn't a crop, fractions go from 0 to 1 with the top left corner first",
+                    s
+                ));
+            }
+            Ok(Crop::Fraction {
+                x0: v[0],
+                y0: v[1],
+                x1: v[2],
+                y1: v[3],
+            })
+        } else {
+            let v = parts
+                .iter()
+                .map(|part| part.parse::<i32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("'{}' isn't a crop: {}", s, err))?;
+            if v.iter().any(|&v| v < 0) || v[0] >= v[2] || v[1] >= v[3] {
+                return Err(format!(
+                    "'{}' isn't a crop, pixels go from 0 with the top left corner first",
+                    s
+                ));
+            }
+            Ok(Crop::Pixels(Tile {
+                x0: v[0],
+                y0: v[1],
+                x1: v[2],
+                y1: v[3],
+            }))
+        }
+    }
+}
+
+impl std::fmt::Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Crop::Pixels(tile) => write!(f, "{},{},{},{}", tile.x0, tile.y0, tile.x1, tile.y1),
+            // with the decimal points, so it parses back the same
+            Crop::Fraction { x0, y0, x1, y1 } => write!(f, "{:?},{:?},{:?},{:?}", x0, y0, x1, y1),
+        }
+    }
+}
+
+// A tile's worth of work: its pixels as they are so far, to be given more
+// samples. Jobs only depend on the render's settings and seed, not on where
+// they're done, so they can be handed out to other processes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileJob {
+    pub tile: Tile,
+    // None for the first pass, up to min_samples. Some(n) for an adaptive
+    // pass, which gives up to n more to every pixel that still needs them.
+    pub batch: Option<i32>,
+    pub pixels: Vec<PixelStats>,
+    pub groups: Vec<Color>,
+}
+
+// a TileJob that's been done, ready to be merged
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileResult {
+    pub tile: Tile,
+    pub pixels: Vec<PixelStats>,
+    pub groups: Vec<Color>,
+    // what the samples added to the film, with a reconstruction filter
+    pub film: Option<FilmTile>,
+}
+
+// A world, the camera looking at it and the settings, ready to render. The
+// BVH and the photon map are built up front by new().
+pub struct Renderer {
+    world: Hitters,
+    // the lights get sampled directly, so they need to be in a list of their own
+    lights: HitList,
+    camera: Camera,
+    settings: RenderSettings,
+    photons: PhotonMap,
+    adaptive: AdaptiveSampling,
+    // what's rendered, all of it without a crop
+    window: Tile,
+}
+
+impl Renderer {
+    pub fn new(world: HitList, camera: Camera, settings: RenderSettings) -> Self {
+        let mut lights = HitList::new();
+        lights.list = world.emitters();
+        let world = if settings.bvh {
+            let (time0, time1) = camera.shutter();
+            let mut bvh = Bvh::new();
+            let mut rng = StdRng::seed_from_u64(settings.seed);
+            bvh.add_hitlist(&mut Arc::new(world), time0, time1, &mut rng);
+            Hitters::BVolumeHierarchy(bvh.build())
+        } else {
+            Hitters::HitList(world)
+        };
+        // caustics are traced from the lights before we start on the pixels
+        let photons = if settings.integrator == Integrator::PhotonMapping {
+            PhotonMap::emit(
+                &world,
+                &lights,
+                settings.photons,
+                settings.max_depth,
+                settings.gather_radius,
+                &mut settings
+                    .sampler
+                    .build(settings.photons as u32, settings.seed),
+            )
+        } else {
+            PhotonMap::default()
+        };
+        // without a noise threshold every pixel just gets samples
+        let adaptive = if settings.noise_threshold > 0.0 {
+            AdaptiveSampling::new(
+                settings.min_samples,
+                settings.max_samples,
+                settings.noise_threshold,
+            )
+        } else {
+            AdaptiveSampling::new(settings.samples, settings.samples, 0.0)
+        };
+        let full = Tile {
+            x0: 0,
+            y0: 0,
+            x1: settings.width,
+            y1: settings.height,
+        };
+        // a crop that misses the image leaves nothing to render
+        let window = match settings.crop {
+            Some(crop) => crop
+                .window(settings.width, settings.height)
+                .unwrap_or(Tile {
+                    x0: 0,
+                    y0: 0,
+                    x1: 0,
+                    y1: 0,
+                }),
+            None => full,
+        };
+        Renderer {
+            world,
+            lights,
+            camera,
+            settings,
+            photons,
+            adaptive,
+            window,
+        }
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    pub fn pixels(&self) -> usize {
+        self.settings.width as usize * self.settings.height as usize
+    }
+
+    // the part of the image that gets rendered
+    pub fn window(&self) -> Tile {
+        self.window
+    }
+
+    // how many median of means sums each pixel has, none without it
+    pub(crate) fn groups(&self) -> usize {
+        self.settings.median_of_means.map_or(0, |mom| mom.groups)
+    }
+
+    // A render that hasn't started, with room for everything this one keeps
+    // per pixel. The seed and settings are only there to be checked when
+    // it's saved and resumed.
+    pub fn checkpoint(&self, seed: u64, settings: String) -> Checkpoint {
+        Checkpoint {
+            width: self.settings.width,
+            height: self.settings.height,
+            seed,
+            settings,
+            pixels: vec![PixelStats::default(); self.pixels()],
+            film: if self.settings.filter.is_some() {
+                vec![Splat::default(); self.pixels()]
+            } else {
+                Vec::new()
+            },
+            groups: vec![Color::default(); self.pixels() * self.groups()],
+        }
+    }
+
+    // pixels are kept in the order they're written out. We start at the top
+    // row because the origin is at the lower left, to maintain a right handed
+    // coordinate system.
+    fn column_row(&self, index: usize) -> (i32, i32) {
+        let width = self.settings.width;
+        (
+            index as i32 % width,
+            self.settings.height - 1 - index as i32 / width,
+        )
+    }
+
+    fn trace(&self, r: &Ray, sampler: &mut dyn Sampler) -> Color {
+        let (world, lights) = (&self.world, &self.lights);
+        let depth = self.settings.max_depth;
+        let background = &self.settings.background;
+        match self.settings.integrator {
+            Integrator::Path => color(r, world, depth, background, sampler),
+            Integrator::DirectLighting => {
+                color_direct_lighting(r, world, lights, depth, background, sampler)
+            }
+            Integrator::MultipleImportance => {
+                color_mis(r, world, lights, depth, background, sampler)
+            }
+            Integrator::Bidirectional => color_bdpt(r, world, lights, depth, background, sampler),
+            Integrator::PhotonMapping => {
+                color_photon_map(r, world, lights, &self.photons, depth, background, sampler)
+            }
+        }
+    }
+
+    // Takes n more samples of the pixel in column i, row j. With a filter
+    // they're also splatted into the tile's film, and with median of means
+    // added to the pixel's groups.
+    fn sample_pixel(
+        &self,
+        (i, j): (i32, i32),
+        n: i32,
+        stats: &mut PixelStats,
+        groups: &mut [Color],
+        mut film: Option<&mut FilmTile>,
+    ) {
+        let (width, height) = (self.settings.width, self.settings.height);
+        let mut sampler = self
+            .settings
+            .sampler
+            .build(self.adaptive.max_samples as u32, self.settings.seed);
+        for _ in 0..n {
+            // carries on from the samples the pixel got in earlier passes
+            sampler.start_pixel_sample(i, j, stats.count() as u32);
+            let (du, dv) = sampler.get_2d();
+            let u = (i as f64 + du) / (width - 1) as f64;
+            let v = (j as f64 + dv) / (height - 1) as f64;
+            let r = self.camera.get_ray(u, v, &mut sampler);
+            let wavelength = self
+                .settings
+                .spectral
+                .then(|| sample_wavelength(sampler.get_1d()));
+            let r = r.with_wavelength(wavelength);
+            let sample = self.trace(&r, &mut sampler);
+            // what the path found at its wavelength, as RGB for the film
+            let sample = match wavelength {
+                Some(lambda) => to_film(to_spectrum(&sample, lambda), lambda),
+                None => sample,
+            };
+            let sample = match self.settings.clamp {
+                Some(max) => clamp_luminance(&sample, max),
+                None => sample,
+            };
+            if let Some(mom) = self.settings.median_of_means {
+                mom.add(groups, stats.count(), &sample);
+            }
+            stats.add(&sample);
+            if let Some(film) = film.as_deref_mut() {
+                // the film counts rows down from the top
+                film.add_sample(i as f64 + du, (height - j) as f64 - dv, &sample);
+            }
+        }
+    }
+
+    fn film_tile(&self, tile: &Tile) -> Option<FilmTile> {
+        let (width, height) = (self.settings.width, self.settings.height);
+        self.settings
+            .filter
+            .map(|filter| FilmTile::new(tile, filter, width, height))
+    }
+
+    // the same tiles as a full render, cut down to the crop
+    fn tiles(&self) -> Vec<Tile> {
+        tiles(
+            self.settings.width,
+            self.settings.height,
+            self.settings.tile_size,
+        )
+        .iter()
+        .filter_map(|tile| tile.intersection(&self.window))
+        .collect()
+    }
+
+    // a copy of the tile's pixels and their median of means groups to work on
+    fn job(&self, state: &Checkpoint, tile: &Tile, batch: Option<i32>) -> TileJob {
+        let n_groups = self.groups();
+        TileJob {
+            tile: *tile,
+            batch,
+            pixels: tile
+                .indices(state.width)
+                .map(|index| state.pixels[index])
+                .collect(),
+            groups: tile
+                .indices(state.width)
+                .flat_map(|index| &state.groups[index * n_groups..][..n_groups])
+                .copied()
+                .collect(),
+        }
+    }
+
+    // Every pixel gets its first min_samples a tile at a time. Tiles that a
+    // checkpoint already finished are left out.
+    pub fn first_pass(&self, state: &Checkpoint) -> Vec<TileJob> {
+        let min_samples = self.adaptive.min_samples;
+        self.tiles()
+            .iter()
+            .filter(|tile| {
+                tile.indices(state.width)
+                    .any(|index| state.pixels[index].count() < min_samples)
+            })
+            .map(|tile| self.job(state, tile, None))
+            .collect()
+    }
+
+    // Then whatever's left of the budget goes to the noisy pixels, a batch at
+    // a time until they settle down or it runs out, which is when this comes
+    // back empty. Each pass has to be merged before the next one is worked
+    // out, since how big a batch is depends on how the last one went.
+    pub fn adaptive_pass(&self, state: &Checkpoint) -> Vec<TileJob> {
+        let adaptive = self.adaptive;
+        let budget = self.settings.samples as i64 * self.window.pixels() as i64;
+        let window = self
+            .window
+            .indices(state.width)
+            .map(|index| &state.pixels[index]);
+        let (spent, noisy) = window.fold((0, 0), |(spent, noisy), stats| {
+            (
+                spent + stats.count() as i64,
+                noisy + usize::from(adaptive.needs_more(stats)),
+            )
+        });
+        let batch = adaptive.batch(budget - spent, noisy);
+        if batch == 0 {
+            return Vec::new();
+        }
+        self.tiles()
+            .iter()
+            .filter(|tile| {
+                tile.indices(state.width)
+                    .any(|index| adaptive.needs_more(&state.pixels[index]))
+            })
+            .map(|tile| self.job(state, tile, Some(batch)))
+            .collect()
+    }
+
+    // Takes the samples a job asks for. If control says to stop, the pixels
+    // that haven't been started yet are left as they are.
+    pub fn render_tile(&self, job: TileJob, control: &RenderControl) -> TileResult {
+        let adaptive = self.adaptive;
+        let n_groups = self.groups();
+        let TileJob {
+            tile,
+            batch,
+            mut pixels,
+            mut groups,
+        } = job;
+        let mut film = self.film_tile(&tile);
+        for (n, (index, stats)) in tile
+            .indices(self.settings.width)
+            .zip(pixels.iter_mut())
+            .enumerate()
+        {
+            let count = match batch {
+                None if control.should_stop() => break,
+                None => adaptive.min_samples - stats.count(),
+                Some(batch) if adaptive.needs_more(stats) && !control.should_stop() => {
+                    batch.min(adaptive.max_samples - stats.count())
+                }
+                Some(_) => continue,
+            };
+            let groups = &mut groups[n * n_groups..][..n_groups];
+            let pixel = self.column_row(index);
+            self.sample_pixel(pixel, count, stats, groups, film.as_mut());
+        }
+        TileResult {
+            tile,
+            pixels,
+            groups,
+            film,
+        }
+    }
+
+    // Puts a tile's new samples into the render. Gives back how many samples
+    // that was, and how many of its pixels got their first pass done. The
+    // film's sums depend on the order tiles are merged in, so they have to go
+    // in the order their pass handed them out for the image to come out the
+    // same every time.
+    pub fn merge(&self, state: &mut Checkpoint, result: TileResult) -> (u64, u64) {
+        let n_groups = self.groups();
+        let min_samples = self.adaptive.min_samples;
+        let (mut samples, mut finished) = (0, 0);
+        let pixels = result.tile.indices(state.width).zip(result.pixels);
+        for (n, (index, stats)) in pixels.enumerate() {
+            let before = state.pixels[index].count();
+            samples += (stats.count() - before) as u64;
+            if before < min_samples && stats.count() >= min_samples {
+                finished += 1;
+            }
+            state.pixels[index] = stats;
+            state.groups[index * n_groups..][..n_groups]
+                .copy_from_slice(&result.groups[n * n_groups..][..n_groups]);
+        }
+        if let Some(film) = result.film {
+            film.merge_into(&mut state.film, state.width);
+        }
+        (samples, finished)
+    }
+
+    // where a render that's carrying on from state starts out
+    pub fn progress(&self, state: &Checkpoint, control: &RenderControl) -> Progress {
+        let window = || {
+            self.window
+                .indices(state.width)
+                .map(|index| &state.pixels[index])
+        };
+        let samples_done = window().map(|stats| stats.count() as u64).sum();
+        Progress {
+            pixels_done: window()
+                .filter(|stats| stats.count() >= self.adaptive.min_samples)
+                .count() as u64,
+            pixels: self.window.pixels() as u64,
+            samples_done,
+            samples: self.settings.samples as u64 * self.window.pixels() as u64,
+            samples_resumed: samples_done,
+            elapsed: control.progress_elapsed(),
+        }
+    }
+
+    // Renders into state, carrying on from whatever samples it already has.
+    // Progress goes to control, and saved() is handed the render every time
+    // some more of it is done, for saving checkpoints. If control says to
+    // stop, what's there is left for image(). Pixels are only ever stopped
+    // between samples, so resuming from state comes out the same as if it
+    // had never been stopped.
+    pub fn render_into(
+        &self,
+        state: &mut Checkpoint,
+        control: &RenderControl,
+        saved: &mut dyn FnMut(&Checkpoint),
+    ) {
+        let mut progress = self.progress(state, control);
+
+        // The first pass's tiles are rendered in parallel and handed back here
+        // as they finish, so whatever is done can be saved while the rest
+        // carry on. They go into the render in the order they were handed
+        // out though, the ones that finish early wait here for their turn.
+        let jobs = self.first_pass(state);
+        let (finished, tiles_done) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                jobs.into_par_iter().enumerate().for_each_with(
+                    finished,
+                    |finished, (order, job)| {
+                        finished
+                            .send((order, self.render_tile(job, control)))
+                            .unwrap();
+                    },
+                );
+            });
+            let mut waiting = BTreeMap::new();
+            let mut next = 0;
+            for (order, result) in tiles_done {
+                waiting.insert(order, result);
+                while let Some(result) = waiting.remove(&next) {
+                    let (samples, finished) = self.merge(state, result);
+                    progress.samples_done += samples;
+                    progress.pixels_done += finished;
+                    next += 1;
+                }
+                progress.elapsed = control.progress_elapsed();
+                control.report(&progress);
+                saved(state);
+            }
+        });
+
+        // a tile at a time as well, for the same reason
+        while !control.should_stop() {
+            let jobs = self.adaptive_pass(state);
+            if jobs.is_empty() {
+                break;
+            }
+            let results: Vec<_> = jobs
+                .into_par_iter()
+                .map(|job| self.render_tile(job, control))
+                .collect();
+            for result in results {
+                let (samples, finished) = self.merge(state, result);
+                progress.samples_done += samples;
+                progress.pixels_done += finished;
+            }
+            progress.elapsed = control.progress_elapsed();
+            control.report(&progress);
+            saved(state);
+        }
+    }
+
+    // The picture so far, still linear and unclamped. Every pixel is divided
+    // by however many samples it actually got, and anything outside the crop
+    // is black.
+    pub fn image(&self, state: &Checkpoint) -> Framebuffer {
+        let n_groups = self.groups();
+        let pixels = if self.settings.filter.is_some() {
+            // the filter spreads samples a little way past the crop's edges
+            let mut pixels = vec![Color::default(); self.pixels()];
+            for index in self.window.indices(self.settings.width) {
+                pixels[index] = state.film[index].color();
+            }
+            pixels
+        } else if let Some(mom) = self.settings.median_of_means {
+            state
+                .pixels
+                .iter()
+                .enumerate()
+                .map(|(index, stats)| {
+                    mom.estimate(&state.groups[index * n_groups..][..n_groups], stats)
+                })
+                .collect()
+        } else {
+            state.pixels.iter().map(|stats| stats.mean()).collect()
+        };
+        Framebuffer::from_pixels(self.settings.width, self.settings.height, pixels)
+    }
+
+    // the whole thing in one go, without checkpoints
+    pub fn render(&self, control: &RenderControl) -> Framebuffer {
+        let mut state = self.checkpoint(self.settings.seed, String::new());
+        self.render_into(&mut state, control, &mut |_| {});
+        self.image(&state)
+    }
+
+    // The extra passes only need what the camera rays hit first, which is
+    // cheap, so they get a pass of their own. It follows the same camera rays
+    // as the first samples of the beauty pass.
+    pub fn aov_pixels(&self, samples: i32) -> Vec<AovPixel> {
+        let (width, height) = (self.settings.width, self.settings.height);
+        (0..self.pixels())
+            .into_par_iter()
+            .map(|index| {
+                let (i, j) = self.column_row(index);
+                if !self.window.contains(i, height - 1 - j) {
+                    return AovPixel::default();
+                }
+                let mut sampler = self
+                    .settings
+                    .sampler
+                    .build(self.adaptive.max_samples as u32, self.settings.seed);
+                let mut pixel = AovPixel::default();
+                for n in 0..samples {
+                    sampler.start_pixel_sample(i, j, n as u32);
+                    let (du, dv) = sampler.get_2d();
+                    let u = (i as f64 + du) / (width - 1) as f64;
+                    let v = (j as f64 + dv) / (height - 1) as f64;
+                    let r = self.camera.get_ray(u, v, &mut sampler);
+                    pixel.add(&r, self.world.hit(&r, 0.001, f64::INFINITY).as_ref());
+                }
+                pixel
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Crop, RenderSettings, Renderer};
+    use crate::camera::Camera;
+    use crate::checkpoint::Tile;
+    use crate::progress::{CancelToken, RenderControl};
+    use crate::util::{cornell_box, Integrator};
+    use crate::vect;
+    use std::time::Duration;
+
+    fn cornell(settings: RenderSettings) -> Renderer {
+        let camera = Camera::new(
+            vect!(278, 273, -800),
+            vect!(278, 273, 0),
+            vect!(0, 1, 0),
+            40.0,
+            settings.width as f64 / settings.height as f64,
+            0.0,
+            10.0,
+            0.0,
+            1.0,
+        );
+        Renderer::new(cornell_box(), camera, settings)
+    }
+
+    #[test]
+    fn test_render() {
+        let settings = RenderSettings {
+            samples: 4,
+            max_depth: 10,
+            integrator: Integrator::MultipleImportance,
+            tile_size: 8,
+            seed: 22,
+            ..RenderSettings::new(24, 16)
+        };
+        let renderer = cornell(settings.clone());
+        let image = renderer.render(&RenderControl::new());
+        assert_eq!((image.width(), image.height()), (24, 16));
+        // the walls are lit
+        assert!(image.pixels().iter().all(|c| c.x.is_finite()));
+        assert!(image.pixels().iter().filter(|c| c.length() > 0.0).count() > 150);
+
+        // the same again without the BVH, or half at a time, is the same
+        let flat = cornell(RenderSettings {
+            bvh: false,
+            ..settings.clone()
+        })
+        .render(&RenderControl::new());
+        assert_eq!(flat.pixels(), image.pixels());
+
+        let half = cornell(RenderSettings {
+            samples: 2,
+            ..settings
+        });
+        let mut state = half.checkpoint(22, String::new());
+        half.render_into(&mut state, &RenderControl::new(), &mut |_| {});
+        let mut saves = 0;
+        renderer.render_into(&mut state, &RenderControl::new(), &mut |_| saves += 1);
+        assert!(saves > 0);
+        assert_eq!(renderer.image(&state).pixels(), image.pixels());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let renderer = cornell(RenderSettings {
+            samples: 4,
+            ..RenderSettings::new(16, 8)
+        });
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let control = RenderControl::new().with_cancel_token(cancel);
+        let mut state = renderer.checkpoint(0, String::new());
+        renderer.render_into(&mut state, &control, &mut |_| {});
+        // nothing was done, and it's all black rather than divided by zero
+        assert!(state.pixels.iter().all(|stats| stats.count() == 0));
+        assert!(renderer
+            .image(&state)
+            .pixels()
+            .iter()
+            .all(|c| c.length() == 0.0));
+    }
+
+    #[test]
+    fn test_progress_per_frame() {
+        let renderer = cornell(RenderSettings {
+            samples: 2,
+            ..RenderSettings::new(16, 8)
+        });
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let control = RenderControl::new().with_progress_channel(sender);
+        // as if there'd been a frame before this one
+        std::thread::sleep(Duration::from_millis(200));
+        control.restart_progress();
+        let mut state = renderer.checkpoint(0, String::new());
+        renderer.render_into(&mut state, &control, &mut |_| {});
+        let this_frame = control.elapsed() - Duration::from_millis(200);
+        drop(control);
+        let reported: Vec<_> = receiver.iter().collect();
+        assert!(!reported.is_empty());
+        // so its ETA doesn't count the time the last one took
+        assert!(reported.iter().all(|p| p.elapsed <= this_frame));
+    }
+
+    #[test]
+    fn test_crop() {
+        let tile = |x0, y0, x1, y1| Tile { x0, y0, x1, y1 };
+        let pixels: Crop = "4,2,10,7".parse().unwrap();
+        assert_eq!(pixels, Crop::Pixels(tile(4, 2, 10, 7)));
+        assert_eq!(pixels.to_string(), "4,2,10,7");
+        let fraction: Crop = "0.25, 0.5, 1.0, 0.75".parse().unwrap();
+        assert_eq!(fraction.to_string(), "0.25,0.5,1.0,0.75");
+        assert_eq!(fraction.to_string().parse::<Crop>(), Ok(fraction));
+        for bad in ["1,2,3", "4,2,2,7", "0.5,0,1.5,1", "-1,0,4,4", "a,b,c,d"] {
+            assert!(bad.parse::<Crop>().is_err(), "{}", bad);
+        }
+        // any pixel it touches is in, and it stops at the edges
+        assert_eq!(fraction.window(10, 10), Some(tile(2, 5, 10, 8)));
+        assert_eq!(pixels.window(8, 8), Some(tile(4, 2, 8, 7)));
+        assert_eq!(pixels.window(4, 8), None);
+
+        // the pixels come out just like they do in the full image
+        let settings = RenderSettings {
+            samples: 2,
+            max_depth: 5,
+            tile_size: 4,
+            ..RenderSettings::new(16, 12)
+        };
+        let full = cornell(settings.clone()).render(&RenderControl::new());
+        let renderer = cornell(RenderSettings {
+            crop: Some(pixels),
+            ..settings
+        });
+        let cropped = renderer.render(&RenderControl::new());
+        for y in 0..12 {
+            for x in 0..16 {
+                if tile(4, 2, 10, 7).contains(x, y) {
+                    assert_eq!(cropped.get(x, y), full.get(x, y));
+                } else {
+                    assert_eq!(cropped.get(x, y).length(), 0.0);
+                }
+            }
+        }
+        let window = cropped.crop(&renderer.window());
+        assert_eq!((window.width(), window.height()), (6, 5));
+        assert_eq!(window.get(0, 0), full.get(4, 2));
+    }
+}
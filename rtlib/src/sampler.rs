@@ -0,0 +1,611 @@
+// Where the random numbers come from. Everything that needs one (the pixel
+// jitter, the lens, the shutter time, bounces, light samples, roulette) asks
+// the sampler for the next "dimension" of the current sample instead of
+// calling thread_rng() itself. Since a path asks for its numbers in the same
+// order every time, the n-th number of every sample in a pixel belongs to the
+// same dimension, and the samplers other than Independent spread each
+// dimension evenly over [0, 1) across the samples of the pixel, rather than
+// letting them clump the way independent random numbers do. That takes a good
+// bit of noise out at the same sample count.
+pub trait Sampler {
+    // Start sample number index of pixel (i, j), going back to the first
+    // dimension. The pixel is only used to tell the sequences apart, so
+    // anything that isn't a pixel (like the photons) can use one of its own.
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32);
+
+    // the next dimension of the current sample, in [0, 1)
+    fn get_1d(&mut self) -> f64;
+
+    // the next two dimensions, for things like picking a point on a square
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+// Which sampler the renderer should hand out, picked on the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SamplerKind {
+    #[default]
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    // samples_per_pixel is how many samples a pixel can get at most, the
+    // stratified sampler cuts every dimension into that many strata
+    pub fn build(&self, samples_per_pixel: u32, seed: u64) -> SamplerType {
+        match self {
+            SamplerKind::Independent => SamplerType::Independent(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => {
+                SamplerType::Stratified(StratifiedSampler::new(samples_per_pixel, seed))
+            }
+            SamplerKind::Halton => SamplerType::Halton(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => SamplerType::Sobol(SobolSampler::new(seed)),
+        }
+    }
+}
+
+impl std::str::FromStr for SamplerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" | "random" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!(
+                "unknown sampler '{}', expected one of: independent, stratified, halton, sobol",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for SamplerKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SamplerKind::Independent => write!(f, "independent"),
+            SamplerKind::Stratified => write!(f, "stratified"),
+            SamplerKind::Halton => write!(f, "halton"),
+            SamplerKind::Sobol => write!(f, "sobol"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SamplerType {
+    Independent(IndependentSampler),
+    Stratified(StratifiedSampler),
+    Halton(HaltonSampler),
+    Sobol(SobolSampler),
+}
+
+impl Sampler for SamplerType {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        match self {
+            SamplerType::Independent(innertype) => innertype.start_pixel_sample(i, j, index),
+            SamplerType::Stratified(innertype) => innertype.start_pixel_sample(i, j, index),
+            SamplerType::Halton(innertype) => innertype.start_pixel_sample(i, j, index),
+            SamplerType::Sobol(innertype) => innertype.start_pixel_sample(i, j, index),
+        }
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        match self {
+            SamplerType::Independent(innertype) => innertype.get_1d(),
+            SamplerType::Stratified(innertype) => innertype.get_1d(),
+            SamplerType::Halton(innertype) => innertype.get_1d(),
+            SamplerType::Sobol(innertype) => innertype.get_1d(),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        match self {
+            SamplerType::Independent(innertype) => innertype.get_2d(),
+            SamplerType::Stratified(innertype) => innertype.get_2d(),
+            SamplerType::Halton(innertype) => innertype.get_2d(),
+            SamplerType::Sobol(innertype) => innertype.get_2d(),
+        }
+    }
+}
+
+// Plain random numbers, what we had before. Every sample starts its own
+// stream from the pixel and the sample number, so a sample is the same no
+// matter which thread renders it or in what order. The stream is splitmix64
+// style, a counter run through mix_bits(), which is plenty for this and keeps
+// the sampler small.
+#[derive(Clone, Debug)]
+pub struct IndependentSampler {
+    seed: u64,
+    state: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        IndependentSampler {
+            seed,
+            state: hash(&[seed]),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.state = hash(&[self.seed, i as u64, j as u64, index as u64]);
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        to_unit(mix_bits(self.state))
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Cuts every dimension into samples_per_pixel strata (a grid of them for 2d)
+// and gives each sample of the pixel a different one, jittered inside it.
+// Which sample gets which stratum is shuffled separately for each dimension,
+// otherwise the dimensions would all line up with each other.
+#[derive(Clone, Debug)]
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    x_strata: u32,
+    y_strata: u32,
+    pixel: (i32, i32),
+    index: u32,
+    dimension: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u64) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        // the squarest grid that has exactly samples_per_pixel cells
+        let mut x_strata = (samples_per_pixel as f64).sqrt() as u32;
+        while samples_per_pixel.rem_euclid(x_strata) > 0 {
+            x_strata -= 1;
+        }
+        StratifiedSampler {
+            seed,
+            samples_per_pixel,
+            x_strata,
+            y_strata: samples_per_pixel / x_strata,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn dimension_hash(&mut self) -> u64 {
+        let h = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ]);
+        self.dimension += 1;
+        h
+    }
+
+    // which stratum the current sample gets out of count of them, and the
+    // jitter inside it
+    fn stratum(&self, count: u32, h: u64) -> (u32, u64) {
+        let stratum = permutation_element(self.index % count, count, h as u32);
+        (stratum, hash(&[h, self.index as u64]))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.pixel = (i, j);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let h = self.dimension_hash();
+        let (stratum, jitter) = self.stratum(self.samples_per_pixel, h);
+        ((stratum as f64 + to_unit(jitter)) / self.samples_per_pixel as f64).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.dimension_hash();
+        let (stratum, jitter) = self.stratum(self.x_strata * self.y_strata, h);
+        let x = (stratum % self.x_strata) as f64 + to_unit(jitter);
+        let y = (stratum / self.x_strata) as f64 + to_unit(mix_bits(jitter));
+        (
+            (x / self.x_strata as f64).min(ONE_MINUS_EPSILON),
+            (y / self.y_strata as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+// The Halton sequence, dimension d is the radical inverse of the sample
+// number in the d-th prime. Every pixel would get the exact same points, and
+// in the bigger primes the first few points are all near 0 (with 16 samples
+// in base 31 they'd never get past 0.5), so the digits are Owen scrambled
+// with a different seed for every pixel and dimension. Past the primes we
+// have, the dimensions are just random.
+#[derive(Clone, Debug)]
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: (i32, i32),
+    index: u32,
+    dimension: u32,
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        HaltonSampler {
+            seed,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.pixel = (i, j);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let h = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ]);
+        let value = match PRIMES.get(self.dimension as usize) {
+            Some(base) => owen_scrambled_radical_inverse(*base, self.index, h),
+            None => to_unit(hash(&[h, self.index as u64])),
+        };
+        self.dimension += 1;
+        value.min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Sobol points, Owen scrambled. Only the first two Sobol dimensions are used:
+// each pair of dimensions gets those two with the sample numbers shuffled
+// differently (padding), so the pairs don't line up with each other. The
+// scrambling (Laine and Karras' hash version of it, from Burley's "Practical
+// Hash-based Owen Scrambling") keeps the points evenly spread but makes every
+// pixel's set different, and gets rid of the patterns plain Sobol points have.
+#[derive(Clone, Debug)]
+pub struct SobolSampler {
+    seed: u64,
+    pixel: (i32, i32),
+    index: u32,
+    dimension: u32,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        SobolSampler {
+            seed,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    // the sample number shuffled for this dimension, and the seeds to
+    // scramble the two values with
+    fn next_dimension(&mut self) -> (u32, u64) {
+        let h = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ]);
+        self.dimension += 1;
+        (nested_uniform_scramble(self.index, h as u32), mix_bits(h))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, i: i32, j: i32, index: u32) {
+        self.pixel = (i, j);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let (index, h) = self.next_dimension();
+        let x = nested_uniform_scramble(sobol_0(index), h as u32);
+        (x as f64 / 4294967296.0).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let (index, h) = self.next_dimension();
+        let x = nested_uniform_scramble(sobol_0(index), h as u32);
+        let y = nested_uniform_scramble(sobol_1(index), (h >> 32) as u32);
+        (
+            (x as f64 / 4294967296.0).min(ONE_MINUS_EPSILON),
+            (y as f64 / 4294967296.0).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+// which of n things u in [0, 1) picks, each with the same chance
+pub fn sample_index(u: f64, n: usize) -> usize {
+    ((u * n as f64) as usize).min(n - 1)
+}
+
+// the largest f64 below 1
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// a 64 bit finalizer (the one from splitmix64), every bit of v ends up
+// affecting all of the bits of the result
+fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5d329728ea185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81dadef4bc2dd44d);
+    v ^= v >> 33;
+    v
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, v| {
+        mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15))
+    })
+}
+
+// a number in [0, 1) that stays the same for the same text, for things that
+// want a fixed random offset of their own
+pub fn hash_to_unit(text: &str) -> f64 {
+    let words: Vec<u64> = text.bytes().map(|b| b as u64).collect();
+    to_unit(hash(&words))
+}
+
+// the top 53 bits of h as a number in [0, 1)
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Element i of a random permutation of 0..l picked by p, without having to
+// build the permutation. Andrew Kensler's "Correlated Multi-Jittered
+// Sampling".
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+// The digits of index in base, mirrored around the decimal point. Each digit
+// goes through a permutation picked by h and all of the digits before it, and
+// we keep going past the last digit of index (they're zeros, but they don't
+// stay zeros once permuted) until the result has all the precision it can get.
+fn owen_scrambled_radical_inverse(base: u32, mut index: u32, h: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut value = 0.0;
+    // the digits so far, only used to pick the permutations, so it's fine
+    // for it to wrap around
+    let mut prefix: u64 = 0;
+    let mut inv_base_n = 1.0;
+    while 1.0 - (base - 1) as f64 * inv_base_n < 1.0 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit = permutation_element(digit, base, mix_bits(h ^ prefix) as u32);
+        prefix = prefix.wrapping_mul(base as u64).wrapping_add(digit as u64);
+        inv_base_n *= inv_base;
+        value += digit as f64 * inv_base_n;
+        index = next;
+    }
+    value.min(ONE_MINUS_EPSILON)
+}
+
+// the first Sobol dimension is the van der Corput sequence, the bits of the
+// index reversed
+fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+// the second one, where the generator matrix is Pascal's triangle mod 2
+fn sobol_1(mut index: u32) -> u32 {
+    let mut v: u32 = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Owen scrambling: flips each bit depending on all of the bits above it,
+// which shuffles the points around without breaking up the strata. Done in
+// reverse since the Laine-Karras permutation works from the low bits up.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        owen_scrambled_radical_inverse, sobol_1, HaltonSampler, IndependentSampler, Sampler,
+        SamplerKind, SobolSampler, StratifiedSampler,
+    };
+
+    // n samples of the first two dimensions of one pixel
+    fn pixel_samples(sampler: &mut dyn Sampler, n: u32) -> Vec<(f64, f64, f64)> {
+        (0..n)
+            .map(|index| {
+                sampler.start_pixel_sample(3, 7, index);
+                let a = sampler.get_1d();
+                let (b, c) = sampler.get_2d();
+                (a, b, c)
+            })
+            .collect()
+    }
+
+    // with n samples, each of the n intervals of [0, 1) should get one
+    fn one_per_stratum(values: &[f64]) -> bool {
+        let n = values.len();
+        let mut seen = vec![false; n];
+        for v in values {
+            let k = (v * n as f64) as usize;
+            if seen[k] {
+                return false;
+            }
+            seen[k] = true;
+        }
+        true
+    }
+
+    #[test]
+    fn test_in_range_and_repeatable() {
+        for kind in [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            let mut sampler = kind.build(16, 42);
+            let mut again = kind.build(16, 42);
+            for index in 0..64 {
+                sampler.start_pixel_sample(1, 2, index);
+                again.start_pixel_sample(1, 2, index);
+                for _ in 0..50 {
+                    let u = sampler.get_1d();
+                    let (v, w) = sampler.get_2d();
+                    assert!((0.0..1.0).contains(&u), "{} gave {}", kind, u);
+                    assert!((0.0..1.0).contains(&v), "{} gave {}", kind, v);
+                    assert!((0.0..1.0).contains(&w), "{} gave {}", kind, w);
+                    assert_eq!(u, again.get_1d());
+                    assert_eq!((v, w), again.get_2d());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stratified() {
+        let mut sampler = StratifiedSampler::new(16, 1);
+        let samples = pixel_samples(&mut sampler, 16);
+        let a: Vec<f64> = samples.iter().map(|s| s.0).collect();
+        assert!(one_per_stratum(&a));
+        // 2d is a 4x4 grid, one sample in each cell
+        let mut cells: Vec<usize> = samples
+            .iter()
+            .map(|s| (s.1 * 4.0) as usize + 4 * (s.2 * 4.0) as usize)
+            .collect();
+        cells.sort_unstable();
+        assert_eq!(cells, (0..16).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_halton() {
+        // scrambling keeps the first base^k points one per stratum
+        for base in [2, 3, 5, 31] {
+            let values: Vec<f64> = (0..base * base)
+                .map(|index| owen_scrambled_radical_inverse(base, index, 11))
+                .collect();
+            assert!(one_per_stratum(&values), "base {}", base);
+        }
+        let mut sampler = HaltonSampler::new(5);
+        let samples = pixel_samples(&mut sampler, 8);
+        let a: Vec<f64> = samples.iter().map(|s| s.0).collect();
+        assert!(one_per_stratum(&a));
+        // and in the bigger bases the points don't all end up near 0 anymore
+        let high: Vec<f64> = (0..16)
+            .map(|index| owen_scrambled_radical_inverse(31, index, 11))
+            .collect();
+        assert!(high.iter().any(|v| *v > 0.6));
+    }
+
+    #[test]
+    fn test_sobol() {
+        assert_eq!(sobol_1(0), 0);
+        assert_eq!(sobol_1(1), 1 << 31);
+        assert_eq!(sobol_1(2), 3 << 30);
+        // scrambling keeps the (0, 2) net property, the first 16 points of
+        // every dimension pair have one per stratum in each direction
+        let mut sampler = SobolSampler::new(9);
+        let samples = pixel_samples(&mut sampler, 16);
+        for values in [
+            samples.iter().map(|s| s.0).collect::<Vec<f64>>(),
+            samples.iter().map(|s| s.1).collect(),
+            samples.iter().map(|s| s.2).collect(),
+        ] {
+            assert!(one_per_stratum(&values));
+        }
+        // and pixels get different points
+        let mut other = SobolSampler::new(9);
+        other.start_pixel_sample(4, 7, 0);
+        sampler.start_pixel_sample(3, 7, 0);
+        assert_ne!(sampler.get_2d(), other.get_2d());
+    }
+
+    #[test]
+    fn test_independent() {
+        let mut sampler = IndependentSampler::new(3);
+        let n = 100_000;
+        let mean = (0..n).map(|_| sampler.get_1d()).sum::<f64>() / n as f64;
+        assert!((mean - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sampler_names() {
+        for kind in [
+            SamplerKind::Independent,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ] {
+            assert_eq!(kind.to_string().parse::<SamplerKind>(), Ok(kind));
+        }
+        assert!("qmc".parse::<SamplerKind>().is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::onb::Onb;
+use crate::sampler::Sampler;
+use crate::spectrum::xyz_to_rgb;
+use crate::vec3::{dot, unit_vector, Color, Vec3};
+use crate::vect;
+use std::f64::consts::PI;
+
+// A clear daylight sky, from Preetham, Shirley and Smits, "A Practical
+// Analytic Model for Daylight" (1999). The sky's brightness and color in
+// any direction come from the angle up from the horizon and the angle to
+// the sun, fitted to a proper simulation of the atmosphere for a given
+// turbidity: 2 is a very clear day, 3 or so a normal one, 10 is hazy.
+//
+// Below the horizon there's flat ground, lit by the sky and the sun and
+// reflecting ground_albedo of it. The sun itself is a small disk that the
+// integrators that sample lights can find, see sample().
+
+// how big the sun looks, in degrees from its middle to the edge
+pub const SUN_RADIUS: f64 = 0.2665;
+
+// the sun's brightness before the atmosphere gets to it, in the same
+// thousands of candela per square metre the sky model uses
+const SUN_LUMINANCE: f64 = 1.6e6;
+
+// From the model's kcd/m^2 to what we render with, so that a white floor
+// under a sun 45 degrees up comes out about white.
+const SKY_SCALE: f64 = 0.04;
+
+#[derive(Clone, Debug)]
+pub struct Sky {
+    // unit vector towards the middle of the sun
+    sun: Vec3,
+    turbidity: f64,
+    ground_albedo: f64,
+    // in degrees
+    sun_radius: f64,
+    cos_sun_radius: f64,
+    // Perez coefficients A to E, for Y, x and y
+    perez: [[f64; 5]; 3],
+    // luminance and chromaticity straight up, Y x y
+    zenith: [f64; 3],
+    // the Perez function at the zenith, to scale the others by
+    normalize: [f64; 3],
+    // the sun's radiance times how big it is, which stays the same when
+    // the size changes
+    sun_light: Color,
+    ground: Color,
+}
+
+// F(theta, gamma), how bright the sky is at theta from the zenith and gamma
+// from the sun, relative to somewhere else
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *c;
+    (1.0 + a * (b / cos_theta.max(1e-3)).exp())
+        * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+// a compass direction: up from the horizon, and around from +z towards +x
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vec3 {
+    let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+    vect!(
+        elevation.cos() * azimuth.sin(),
+        elevation.sin(),
+        elevation.cos() * azimuth.cos()
+    )
+}
+
+// How much of the sun's light gets through the air at theta from the zenith,
+// for red, green and blue. Rayleigh scattering and haze, after the model's
+// appendix, ignoring ozone and water vapour.
+fn sun_transmittance(theta: f64, turbidity: f64) -> Color {
+    // how much air there is on the way, relative to straight up
+    let mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let through = |lambda: f64| {
+        let rayleigh = (-0.008735 * lambda.powf(-4.08) * mass).exp();
+        let haze = (-beta * lambda.powf(-1.3) * mass).exp();
+        rayleigh * haze
+    };
+    // micrometres
+    vect!(through(0.65), through(0.55), through(0.45))
+}
+
+impl Sky {
+    pub fn new(sun: &Vec3, turbidity: f64, ground_albedo: f64) -> Self {
+        let t = turbidity;
+        let sun = unit_vector(sun);
+        // the model only covers the sun above the horizon
+        let theta_s = sun.y.clamp(0.0, 1.0).acos();
+        let coefficients = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let chromaticity = |m: [[f64; 4]; 3]| {
+            let angles = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let turbidities = [t * t, t, 1.0];
+            (0..3)
+                .map(|r| turbidities[r] * (0..4).map(|c| m[r][c] * angles[c]).sum::<f64>())
+                .sum::<f64>()
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_yc = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+        let normalize = [
+            perez(&coefficients[0], 1.0, theta_s),
+            perez(&coefficients[1], 1.0, theta_s),
+            perez(&coefficients[2], 1.0, theta_s),
+        ];
+        let mut sky = Sky {
+            sun,
+            turbidity,
+            ground_albedo,
+            sun_radius: 0.0,
+            cos_sun_radius: 1.0,
+            perez: coefficients,
+            zenith: [zenith_y.max(0.0), zenith_x, zenith_yc],
+            normalize,
+            sun_light: Color::default(),
+            ground: Color::default(),
+        };
+        sky = sky.with_sun_radius(SUN_RADIUS);
+        sky.sun_light = sun_transmittance(theta_s, turbidity)
+            * (SUN_LUMINANCE * SKY_SCALE * sky.sun_solid_angle());
+        sky.ground = sky.ground_radiance();
+        sky
+    }
+
+    // A bigger sun is easier to hit, so plain path tracing with color()
+    // gets less noisy, and the shadows get softer. It's just as bright
+    // overall.
+    pub fn with_sun_radius(mut self, degrees: f64) -> Self {
+        self.sun_radius = degrees;
+        self.cos_sun_radius = degrees.to_radians().cos();
+        self
+    }
+
+    fn sun_solid_angle(&self) -> f64 {
+        2.0 * PI * (1.0 - self.cos_sun_radius)
+    }
+
+    fn sun_radiance(&self) -> Color {
+        self.sun_light / self.sun_solid_angle()
+    }
+
+    pub fn sun(&self) -> Vec3 {
+        self.sun
+    }
+
+    pub fn sun_radius(&self) -> f64 {
+        self.sun_radius
+    }
+
+    // the sky alone, without the sun, for a unit direction above the horizon
+    fn sky_radiance(&self, direction: &Vec3) -> Color {
+        let cos_theta = direction.y.max(0.0);
+        let gamma = dot(direction, &self.sun).clamp(-1.0, 1.0).acos();
+        let value =
+            |i: usize| self.zenith[i] * perez(&self.perez[i], cos_theta, gamma) / self.normalize[i];
+        let (luminance, x, y) = (value(0), value(1), value(2));
+        if y <= 0.0 {
+            return Color::default();
+        }
+        let xyz = vect!(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        let rgb = xyz_to_rgb(&xyz) * SKY_SCALE;
+        vect!(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+    }
+
+    // What the flat ground reflects: the light falling on it from the sky
+    // (added up over the sky a patch at a time) and the sun, spread out
+    // evenly like a diffuse surface would.
+    fn ground_radiance(&self) -> Color {
+        let (rows, columns) = (64, 128);
+        let mut irradiance = Color::default();
+        for row in 0..rows {
+            let theta = (row as f64 + 0.5) / rows as f64 * PI / 2.0;
+            let patch = (PI / 2.0 / rows as f64) * (2.0 * PI / columns as f64) * theta.sin();
+            for column in 0..columns {
+                let phi = (column as f64 + 0.5) / columns as f64 * 2.0 * PI;
+                let direction = vect!(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin()
+                );
+                irradiance += self.sky_radiance(&direction) * (theta.cos() * patch);
+            }
+        }
+        irradiance += self.sun_light * self.sun.y.max(0.0);
+        irradiance * (self.ground_albedo / PI)
+    }
+
+    // the light coming from direction
+    pub fn value(&self, direction: &Vec3) -> Color {
+        let direction = unit_vector(direction);
+        if direction.y < 0.0 {
+            return self.ground;
+        }
+        let sky = self.sky_radiance(&direction);
+        if dot(&direction, &self.sun) >= self.cos_sun_radius {
+            sky + self.sun_radiance()
+        } else {
+            sky
+        }
+    }
+
+    // Half the directions go at the sun, the rest anywhere at all, so both
+    // the sun and the sky get found. Returns the direction and its pdf.
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<(Vec3, f64)> {
+        let (u1, u2) = sampler.get_2d();
+        let direction = if u1 < 0.5 {
+            let cos = 1.0 - 2.0 * u1 * (1.0 - self.cos_sun_radius);
+            let sin = (1.0 - cos * cos).max(0.0).sqrt();
+            let phi = 2.0 * PI * u2;
+            Onb::build_from_w(&self.sun).local(sin * phi.cos(), sin * phi.sin(), cos)
+        } else {
+            let y = 1.0 - 2.0 * (2.0 * u1 - 1.0);
+            let r = (1.0 - y * y).max(0.0).sqrt();
+            let phi = 2.0 * PI * u2;
+            vect!(r * phi.cos(), y, r * phi.sin())
+        };
+        Some((direction, self.pdf(&direction)))
+    }
+
+    // the chance of sample() picking direction
+    pub fn pdf(&self, direction: &Vec3) -> f64 {
+        let everywhere = 0.5 / (4.0 * PI);
+        if dot(&unit_vector(direction), &self.sun) >= self.cos_sun_radius {
+            everywhere + 0.5 / self.sun_solid_angle()
+        } else {
+            everywhere
+        }
+    }
+}
+
+impl std::fmt::Display for Sky {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "sky sun {} radius {} turbidity {} albedo {}",
+            self.sun, self.sun_radius, self.turbidity, self.ground_albedo
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sun_direction, Sky};
+    use crate::sampler::{IndependentSampler, Sampler};
+    use crate::vec3::{Color, Vec3};
+    use crate::vect;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn test_sky() {
+        let sun = sun_direction(45.0, 90.0);
+        assert!((sun - vect!(0.5f64.sqrt(), 0.5f64.sqrt(), 0)).length() < 1e-12);
+        let sky = Sky::new(&sun, 3.0, 0.3);
+
+        // blue overhead, brighter towards the sun than away from it, and
+        // the sun itself far brighter than any of it
+        let up = sky.value(&vect!(0, 1, 0));
+        assert!(up.z > up.x, "{}", up);
+        let near = sky.value(&sun_direction(35.0, 90.0));
+        let away = sky.value(&sun_direction(35.0, 270.0));
+        assert!(near.y > away.y, "{} {}", near, away);
+        assert!(sky.value(&sun).y > 1000.0 * near.y);
+
+        // the ground only shows what lands on it
+        assert_eq!(sky.value(&vect!(0, -1, 0)), sky.value(&vect!(1, -0.1, 1)));
+        let black = Sky::new(&sun, 3.0, 0.0);
+        assert_eq!(black.value(&vect!(0, -1, 0)), Color::default());
+        let white = Sky::new(&sun, 3.0, 1.0).value(&vect!(0, -1, 0));
+        assert!(white.y > 0.5 && white.y < 2.0, "{}", white);
+
+        // a low sun comes through more air, so it's dimmer and redder
+        let high = Sky::new(&sun_direction(60.0, 0.0), 3.0, 0.3);
+        let low = Sky::new(&sun_direction(5.0, 0.0), 3.0, 0.3);
+        let (high, low) = (high.value(&high.sun()), low.value(&low.sun()));
+        assert!(low.y < high.y);
+        assert!(low.x / low.z > high.x / high.z);
+        // and hazier skies are brighter around it
+        let hazy = Sky::new(&sun, 8.0, 0.3);
+        assert!(hazy.value(&sun_direction(35.0, 90.0)).y > near.y);
+    }
+
+    #[test]
+    fn test_sun_size() {
+        // a bigger sun is the same light spread wider
+        let sun = sun_direction(30.0, 10.0);
+        let small = Sky::new(&sun, 3.0, 0.3);
+        let big = small.clone().with_sun_radius(5.0);
+        let power = |sky: &Sky| sky.sun_radiance().y * sky.sun_solid_angle();
+        assert!((power(&small) - power(&big)).abs() < 1e-9 * power(&small));
+        assert!(big.sun_radiance().y < small.sun_radiance().y / 300.0);
+        assert!(big.value(&sun_direction(33.0, 10.0)).y > 100.0);
+        assert!(small.value(&sun_direction(33.0, 10.0)).y < 100.0);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let sky = Sky::new(&sun_direction(40.0, 200.0), 3.0, 0.3).with_sun_radius(2.0);
+        let mut sampler = IndependentSampler::new(5);
+        sampler.start_pixel_sample(0, 0, 0);
+        let n = 200_000;
+        let mut estimate = Color::default();
+        for _ in 0..n {
+            let (d, pdf) = sky.sample(&mut sampler).unwrap();
+            assert!((d.length() - 1.0).abs() < 1e-9);
+            assert_eq!(sky.pdf(&d), pdf);
+            estimate += sky.value(&d) / pdf;
+        }
+        // all the light, against adding it up a patch at a time
+        let (rows, columns) = (400, 800);
+        let mut exact = Color::default();
+        for row in 0..rows {
+            let theta = (row as f64 + 0.5) / rows as f64 * PI;
+            let patch = (PI / rows as f64) * (2.0 * PI / columns as f64) * theta.sin();
+            for column in 0..columns {
+                let phi = (column as f64 + 0.5) / columns as f64 * 2.0 * PI;
+                let d = Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                exact += sky.value(&d) * patch;
+            }
+        }
+        let estimate = estimate / n as f64;
+        assert!(
+            (estimate - exact).length() < 0.02 * exact.length(),
+            "{} {}",
+            estimate,
+            exact
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use crate::vec3::{Color, Vec3};
+use std::sync::OnceLock;
+
+// Spectral rendering, so glass can split white light into colors. Every
+// camera ray picks one wavelength and the whole path follows it, a Dielectric
+// bending it by the index of refraction for that wavelength.
+//
+// The scenes are still described in RGB. Whenever a material hands back an
+// attenuation it's turned into a spectrum and looked up at the path's
+// wavelength, which leaves a gray Color with the same value in each channel,
+// so the integrators can carry on multiplying Colors as before. Lights and
+// the interior light are left in RGB on the way, since the conversion is
+// linear the path's total can be converted in one go at the end (see
+// to_spectrum()). Then that one value at that one wavelength goes on the
+// film through the CIE color matching functions and back to RGB.
+//
+// The conversion is set up so that anything that isn't glass comes out the
+// same as the RGB render on average. Only the noise changes, and colors that
+// bounce around a lot, since multiplying spectra isn't the same as
+// multiplying RGB.
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 730.0;
+
+// picks a wavelength for a number from 0 to 1, all equally likely
+pub fn sample_wavelength(u: f64) -> f64 {
+    LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+// The CIE 1931 color matching functions, from the multi-lobe Gaussian fit in
+// Wyman, Sloan and Shirley (2013). Close enough to the tables for this.
+pub fn cie_xyz(lambda: f64) -> Vec3 {
+    let g = |mu: f64, below: f64, above: f64| {
+        let t = (lambda - mu) / if lambda < mu { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// CIE XYZ to linear sRGB, with the D65 white point
+const XYZ_TO_RGB: [[f64; 3]; 3] = [
+    [3.2406, -1.5372, -0.4986],
+    [-0.9689, 1.8758, 0.0415],
+    [0.0557, -0.2040, 1.0570],
+];
+
+// linear sRGB for a CIE XYZ color
+pub fn xyz_to_rgb(xyz: &Vec3) -> Color {
+    times(&XYZ_TO_RGB, xyz)
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// How much of red, green and blue a spectrum has at lambda. The three add up
+// to 1 everywhere and never go negative, so white is a flat spectrum and any
+// color from 0 to 1 is a reflectance a real surface could have.
+fn basis(lambda: f64) -> [f64; 3] {
+    let blue = 1.0 - smoothstep(450.0, 500.0, lambda);
+    let red = smoothstep(570.0, 620.0, lambda);
+    [red, 1.0 - red - blue, blue]
+}
+
+// the spectrum for an RGB color, at lambda
+pub fn to_spectrum(c: &Color, lambda: f64) -> f64 {
+    let [r, g, b] = basis(lambda);
+    r * c.x + g * c.y + b * c.z
+}
+
+// the same as a gray Color, to multiply a path's throughput by
+pub fn at_wavelength(c: &Color, lambda: f64) -> Color {
+    let v = to_spectrum(c, lambda);
+    Color::new(v, v, v)
+}
+
+fn times(m: &[[f64; 3]; 3], v: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+fn inverse(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = m[0][0] * cofactor(0, 0) + m[0][1] * cofactor(0, 1) + m[0][2] * cofactor(0, 2);
+    let mut out = [[0.0; 3]; 3];
+    for (r, row) in out.iter_mut().enumerate() {
+        for (c, v) in row.iter_mut().enumerate() {
+            *v = cofactor(c, r) / det;
+        }
+    }
+    out
+}
+
+// From the color matching functions to RGB, but corrected so that the
+// spectra to_spectrum() makes come back as the RGB they started as. Without
+// it white would come out a bit pink, the basis spectra aren't what sRGB's
+// primaries look like.
+fn film_matrix() -> &'static [[f64; 3]; 3] {
+    static MATRIX: OnceLock<[[f64; 3]; 3]> = OnceLock::new();
+    MATRIX.get_or_init(|| {
+        // what each basis spectrum integrates to in sRGB, a nanometre at a time
+        let mut columns = [Vec3::default(); 3];
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        for step in 0..steps {
+            let lambda = LAMBDA_MIN + step as f64 + 0.5;
+            let rgb = times(&XYZ_TO_RGB, &cie_xyz(lambda));
+            for (column, weight) in columns.iter_mut().zip(basis(lambda)) {
+                *column += weight * rgb;
+            }
+        }
+        let m = [
+            [columns[0].x, columns[1].x, columns[2].x],
+            [columns[0].y, columns[1].y, columns[2].y],
+            [columns[0].z, columns[1].z, columns[2].z],
+        ];
+        let correct = inverse(&m);
+        let mut out = [[0.0; 3]; 3];
+        for (r, row) in out.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = (0..3).map(|k| correct[r][k] * XYZ_TO_RGB[k][c]).sum();
+            }
+        }
+        out
+    })
+}
+
+// What a path carrying `value` at lambda adds to a pixel, in linear RGB. A
+// single wavelength is often outside what RGB can show, so some channels can
+// be negative, they even out over the samples.
+pub fn to_film(value: f64, lambda: f64) -> Color {
+    // divided by the chance of picking lambda, 1 / (LAMBDA_MAX - LAMBDA_MIN)
+    times(film_matrix(), &cie_xyz(lambda)) * (value * (LAMBDA_MAX - LAMBDA_MIN))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{at_wavelength, cie_xyz, sample_wavelength, to_film, to_spectrum};
+    use crate::vec3::Color;
+    use crate::vect;
+
+    #[test]
+    fn test_cie() {
+        // y peaks around 555nm at about 1, and nothing much at the ends
+        assert!((cie_xyz(555.0).y - 1.0).abs() < 0.02);
+        assert!(cie_xyz(555.0).y > cie_xyz(500.0).y);
+        assert!(cie_xyz(380.0).length() < 0.01);
+        assert!(cie_xyz(730.0).length() < 0.01);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // with samples spread evenly, what goes in comes back out
+        let n = 3500;
+        for c in [
+            vect!(1, 1, 1),
+            vect!(1, 0, 0),
+            vect!(0, 1, 0),
+            vect!(0.2, 0.5, 0.9),
+            vect!(15, 15, 15),
+        ] {
+            let mut total = Color::default();
+            for i in 0..n {
+                let lambda = sample_wavelength((i as f64 + 0.5) / n as f64);
+                total += to_film(to_spectrum(&c, lambda), lambda);
+            }
+            let mean = total / n as f64;
+            assert!(
+                (mean - c).length() < 1e-3 * c.length(),
+                "{} came out {}",
+                c,
+                mean
+            );
+        }
+        // reflectances stay reflectances
+        for lambda in [380.0, 450.0, 480.0, 550.0, 600.0, 700.0] {
+            let v = at_wavelength(&vect!(0.9, 0.1, 0.5), lambda);
+            assert!(v.x >= 0.1 && v.x <= 0.9);
+            assert_eq!((v.x, v.x), (v.y, v.z));
+        }
+    }
+}
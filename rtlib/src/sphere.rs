@@ -1,11 +1,15 @@
 use super::aabb::{AabbF, AABB};
-use super::hittable::{HitRecord, Hittable};
-use super::materials::MaterialType;
+use super::hittable::{HitRecord, Hittable, Hitters};
+use super::materials::{Material, MaterialType};
+use super::onb::Onb;
 use super::ray::Ray;
+use super::sampler::Sampler;
+use super::stats::{count, Counter};
 use super::util::{ffmax, ffmin, uv_for_sphere};
-use super::vec3::{dot, Vec3};
+use super::vec3::{dot, Point3, Vec3};
 use super::vect;
 use crate::prelude::BoundingBox;
+use std::f64::consts::PI;
 
 #[derive(Clone)]
 pub struct Sphere {
@@ -36,6 +40,7 @@ impl Sphere {
 
 impl Hittable for Sphere {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count(Counter::IntersectionTests);
         let mut rec: Option<HitRecord> = None;
         let oc = r.origin() - self.center;
         let a = dot(&r.direction(), &r.direction());
@@ -95,6 +100,76 @@ impl Hittable for Sphere {
     fn hitter_fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.inner_fmt(f)
     }
+
+    // From outside, we sample the cone of directions that the sphere covers,
+    // so the pdf is constant over that solid angle.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> f64 {
+        if self
+            .hit(&Ray::new(origin, direction, None), 0.001, f64::INFINITY)
+            .is_none()
+        {
+            return 0.0;
+        }
+        let distance_squared = (self.center - *origin).length_squared();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            // we're inside, every direction hits it
+            return 1.0 / (4.0 * PI);
+        }
+        let cos_theta_max = (1.0 - radius_squared / distance_squared).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+        1.0 / solid_angle
+    }
+
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let (r1, r2) = sampler.get_2d();
+        let direction = self.center - *origin;
+        let distance_squared = direction.length_squared();
+        let radius_squared = self.radius * self.radius;
+        let phi = 2.0 * PI * r1;
+        if distance_squared <= radius_squared {
+            let z = 1.0 - 2.0 * r2;
+            let r = (1.0 - z * z).sqrt();
+            return vect!(phi.cos() * r, phi.sin() * r, z);
+        }
+        let z = 1.0 + r2 * ((1.0 - radius_squared / distance_squared).sqrt() - 1.0);
+        let r = (1.0 - z * z).sqrt();
+        Onb::build_from_w(&direction).local(phi.cos() * r, phi.sin() * r, z)
+    }
+
+    fn emitters(&self) -> Vec<Hitters> {
+        if self.material.is_emissive() {
+            vec![Hitters::Sphere(self.clone())]
+        } else {
+            Vec::new()
+        }
+    }
+
+    // uniform over the whole surface, unlike random() which only covers what
+    // can be seen from the origin
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let (u, v) = sampler.get_2d();
+        let z = 1.0 - 2.0 * u;
+        let r = (1.0 - z * z).sqrt();
+        let phi = 2.0 * PI * v;
+        let normal = vect!(phi.cos() * r, phi.sin() * r, z);
+        let mut rec = HitRecord::new(
+            self.center + normal * self.radius,
+            0.0,
+            self.material.clone(),
+        );
+        rec.normal = normal;
+        rec.texture_coord = Some(uv_for_sphere(&normal));
+        Some((rec, 1.0 / (4.0 * PI * self.radius * self.radius)))
+    }
+
+    fn surface_pdf(&self, p: &Point3) -> f64 {
+        let distance = (*p - self.center).length();
+        if (distance - self.radius).abs() > 1e-6 * (1.0 + self.radius) {
+            return 0.0;
+        }
+        1.0 / (4.0 * PI * self.radius * self.radius)
+    }
 }
 
 #[allow(unused_imports, dead_code)]
@@ -137,6 +212,7 @@ impl MovingSphere {
 
 impl Hittable for MovingSphere {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        count(Counter::IntersectionTests);
         let mut retrec: Option<HitRecord> = None;
         let oc = r.origin() - self.center_at_time(r.time());
         let a = dot(&r.direction(), &r.direction());
@@ -223,9 +299,10 @@ mod test {
     use crate::hittable::HitRecord;
     use crate::hittable::Hittable;
     #[allow(unused_imports)]
-    use crate::materials::{Lambertian, MaterialType};
+    use crate::materials::{DiffuseLight, Lambertian, MaterialType};
     #[allow(unused_imports)]
     use crate::ray::Ray;
+    use crate::sampler::IndependentSampler;
     use crate::sphere::Sphere;
     #[allow(unused_imports)]
     use crate::textures::ConstantTexture;
@@ -270,4 +347,30 @@ mod test {
         }
         //println!("the result front_face: {}", result.material);
     }
+
+    #[test]
+    fn test_sphere_light_sampling() {
+        let light = MaterialType::DiffuseLight(DiffuseLight::new(color_to_texture!(&Color::new(
+            4.0, 4.0, 4.0
+        ))));
+        let s = Sphere::new(&Point3::new(0.0, 0.0, 10.0), 1.0, light);
+        let origin = Point3::new(0.0, 0.0, 0.0);
+
+        // everything we hand out has to actually point at the sphere
+        let mut sampler = IndependentSampler::new(0);
+        for _ in 0..100 {
+            let to_light = s.random(&origin, &mut sampler);
+            assert!(s
+                .hit(&Ray::new(&origin, &to_light, None), 0.001, f64::INFINITY)
+                .is_some());
+        }
+        let cos_theta_max = (1.0 - 1.0 / 100.0_f64).sqrt();
+        let pdf = 1.0 / (2.0 * std::f64::consts::PI * (1.0 - cos_theta_max));
+        assert!((s.pdf_value(&origin, &Vec3::new(0.0, 0.0, 1.0)) - pdf).abs() < 1e-6);
+        assert_eq!(s.pdf_value(&origin, &Vec3::new(0.0, 0.0, -1.0)), 0.0);
+        assert_eq!(s.emitters().len(), 1);
+
+        let dull = Sphere::new(&Point3::new(0.0, 0.0, 10.0), 1.0, MaterialType::default());
+        assert!(dull.emitters().is_empty());
+    }
 }
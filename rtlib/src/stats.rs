@@ -0,0 +1,333 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Counts of what the renderer did, to see where the time goes. Every thread
+// has its own set of counters that only it writes to, so counting is just a
+// load and a store with nothing shared between threads. They're all kept in
+// a list as well, and collect() adds them up whenever we want a total.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Counter {
+    // leaving the camera
+    CameraRays,
+    // following a path on after it scattered
+    BounceRays,
+    // checking if a light can be seen from a point
+    ShadowRays,
+    // leaving a light, for photons and bidirectional light paths
+    LightRays,
+    // a ray against a sphere or a rectangle
+    IntersectionTests,
+    BvhNodeVisits,
+    // a ray stopping inside a ConstantMedium
+    MediumScatters,
+}
+
+const COUNTERS: usize = 7;
+
+// how many bounces are told apart in the path length histogram, the last
+// one has everything that's longer
+pub const PATH_LENGTHS: usize = 32;
+
+struct ThreadCounters {
+    counts: [AtomicU64; COUNTERS],
+    path_lengths: [AtomicU64; PATH_LENGTHS],
+}
+
+impl ThreadCounters {
+    fn new() -> Self {
+        ThreadCounters {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            path_lengths: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+static ALL_THREADS: Mutex<Vec<Arc<ThreadCounters>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static THIS_THREAD: Arc<ThreadCounters> = {
+        let counters = Arc::new(ThreadCounters::new());
+        ALL_THREADS.lock().unwrap().push(counters.clone());
+        counters
+    };
+}
+
+// this thread is the only one that writes to it, so it doesn't need an
+// atomic add
+fn bump(value: &AtomicU64) {
+    value.store(value.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+}
+
+pub fn count(counter: Counter) {
+    THIS_THREAD.with(|counters| bump(&counters.counts[counter as usize]));
+}
+
+// a path that's finished, after this many bounces
+pub fn count_path(bounces: i32) {
+    let bucket = (bounces.max(0) as usize).min(PATH_LENGTHS - 1);
+    THIS_THREAD.with(|counters| bump(&counters.path_lengths[bucket]));
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub camera_rays: u64,
+    pub bounce_rays: u64,
+    pub shadow_rays: u64,
+    pub light_rays: u64,
+    pub intersection_tests: u64,
+    pub bvh_node_visits: u64,
+    pub medium_scatters: u64,
+    // how many paths ended after n bounces, see PATH_LENGTHS
+    pub path_lengths: Vec<u64>,
+}
+
+impl RenderStats {
+    fn from_counters<'a>(all: impl Iterator<Item = &'a ThreadCounters>) -> Self {
+        let mut counts = [0; COUNTERS];
+        let mut path_lengths = vec![0; PATH_LENGTHS];
+        for counters in all {
+            for (total, value) in counts.iter_mut().zip(&counters.counts) {
+                *total += value.load(Ordering::Relaxed);
+            }
+            for (total, value) in path_lengths.iter_mut().zip(&counters.path_lengths) {
+                *total += value.load(Ordering::Relaxed);
+            }
+        }
+        RenderStats {
+            camera_rays: counts[Counter::CameraRays as usize],
+            bounce_rays: counts[Counter::BounceRays as usize],
+            shadow_rays: counts[Counter::ShadowRays as usize],
+            light_rays: counts[Counter::LightRays as usize],
+            intersection_tests: counts[Counter::IntersectionTests as usize],
+            bvh_node_visits: counts[Counter::BvhNodeVisits as usize],
+            medium_scatters: counts[Counter::MediumScatters as usize],
+            path_lengths,
+        }
+    }
+
+    // everything counted so far, by every thread
+    pub fn collect() -> Self {
+        let all = ALL_THREADS.lock().unwrap();
+        RenderStats::from_counters(all.iter().map(|counters| counters.as_ref()))
+    }
+
+    // just what this thread has counted
+    pub fn this_thread() -> Self {
+        THIS_THREAD.with(|counters| RenderStats::from_counters(std::iter::once(counters.as_ref())))
+    }
+
+    // what was counted after `before` was collected
+    pub fn since(&self, before: &RenderStats) -> Self {
+        RenderStats {
+            camera_rays: self.camera_rays - before.camera_rays,
+            bounce_rays: self.bounce_rays - before.bounce_rays,
+            shadow_rays: self.shadow_rays - before.shadow_rays,
+            light_rays: self.light_rays - before.light_rays,
+            intersection_tests: self.intersection_tests - before.intersection_tests,
+            bvh_node_visits: self.bvh_node_visits - before.bvh_node_visits,
+            medium_scatters: self.medium_scatters - before.medium_scatters,
+            path_lengths: self
+                .path_lengths
+                .iter()
+                .zip(&before.path_lengths)
+                .map(|(now, then)| now - then)
+                .collect(),
+        }
+    }
+
+    // counts another lot in too, like the next frame of an animation
+    pub fn add(&mut self, other: &RenderStats) {
+        self.camera_rays += other.camera_rays;
+        self.bounce_rays += other.bounce_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.light_rays += other.light_rays;
+        self.intersection_tests += other.intersection_tests;
+        self.bvh_node_visits += other.bvh_node_visits;
+        self.medium_scatters += other.medium_scatters;
+        // a default one hasn't got any yet
+        self.path_lengths.resize(PATH_LENGTHS, 0);
+        for (total, n) in self.path_lengths.iter_mut().zip(&other.path_lengths) {
+            *total += n;
+        }
+    }
+
+    // every ray that was traced through the scene
+    pub fn rays(&self) -> u64 {
+        self.camera_rays + self.bounce_rays + self.shadow_rays + self.light_rays
+    }
+
+    pub fn paths(&self) -> u64 {
+        self.path_lengths.iter().sum()
+    }
+
+    pub fn mean_path_length(&self) -> f64 {
+        let bounces: u64 = self
+            .path_lengths
+            .iter()
+            .enumerate()
+            .map(|(n, &paths)| n as u64 * paths)
+            .sum();
+        bounces as f64 / self.paths().max(1) as f64
+    }
+
+    // For people, with a bar chart of the path lengths. Lengths past the
+    // longest path anyone took are left off.
+    pub fn summary(&self, seconds: f64) -> String {
+        let mut s = String::new();
+        let per_second = |n: u64| n as f64 / seconds.max(1e-9);
+        let _ = writeln!(
+            s,
+            "Render time: {:.2}s, {} rays ({:.0} rays/s)",
+            seconds,
+            self.rays(),
+            per_second(self.rays())
+        );
+        for (name, n) in [
+            ("camera rays", self.camera_rays),
+            ("bounce rays", self.bounce_rays),
+            ("shadow rays", self.shadow_rays),
+            ("light rays", self.light_rays),
+            ("intersection tests", self.intersection_tests),
+            ("BVH node visits", self.bvh_node_visits),
+            ("medium scatters", self.medium_scatters),
+        ] {
+            let _ = writeln!(s, "  {:<20}{:>15}", name, n);
+        }
+        let _ = writeln!(
+            s,
+            "Path lengths ({} paths, {:.2} bounces on average):",
+            self.paths(),
+            self.mean_path_length()
+        );
+        let most = self.path_lengths.iter().copied().max().unwrap_or(0).max(1);
+        let longest = self.path_lengths.iter().rposition(|&n| n > 0).unwrap_or(0);
+        for (n, &paths) in self.path_lengths.iter().enumerate().take(longest + 1) {
+            let label = if n == PATH_LENGTHS - 1 {
+                format!("{}+", n)
+            } else {
+                n.to_string()
+            };
+            let _ = writeln!(
+                s,
+                "  {:>4} {:>12} {}",
+                label,
+                paths,
+                "#".repeat((paths * 50).div_ceil(most) as usize)
+            );
+        }
+        s
+    }
+
+    // For the benchmark dashboard. path_lengths[n] is how many paths had n
+    // bounces, the last one is that many or more.
+    pub fn to_json(&self, seconds: f64) -> String {
+        let path_lengths: Vec<String> = self.path_lengths.iter().map(u64::to_string).collect();
+        format!(
+            concat!(
+                "{{\n",
+                "  \"seconds\": {},\n",
+                "  \"rays\": {},\n",
+                "  \"rays_per_second\": {},\n",
+                "  \"camera_rays\": {},\n",
+                "  \"bounce_rays\": {},\n",
+                "  \"shadow_rays\": {},\n",
+                "  \"light_rays\": {},\n",
+                "  \"intersection_tests\": {},\n",
+                "  \"bvh_node_visits\": {},\n",
+                "  \"medium_scatters\": {},\n",
+                "  \"paths\": {},\n",
+                "  \"mean_path_length\": {},\n",
+                "  \"path_lengths\": [{}]\n",
+                "}}\n"
+            ),
+            seconds,
+            self.rays(),
+            self.rays() as f64 / seconds.max(1e-9),
+            self.camera_rays,
+            self.bounce_rays,
+            self.shadow_rays,
+            self.light_rays,
+            self.intersection_tests,
+            self.bvh_node_visits,
+            self.medium_scatters,
+            self.paths(),
+            self.mean_path_length(),
+            path_lengths.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{count, count_path, Counter, RenderStats, PATH_LENGTHS};
+    use crate::environment::Background;
+    use crate::hittable::Hitters;
+    use crate::materials::{Lambertian, MaterialType};
+    use crate::sampler::IndependentSampler;
+    use crate::sphere::Sphere;
+    use crate::util::color;
+    use crate::{color_to_texture, ray, vect};
+
+    #[test]
+    fn test_counters() {
+        let before = RenderStats::this_thread();
+        count(Counter::ShadowRays);
+        count(Counter::ShadowRays);
+        count(Counter::MediumScatters);
+        count_path(2);
+        count_path(1000);
+        let counted = RenderStats::this_thread().since(&before);
+        assert_eq!(counted.shadow_rays, 2);
+        assert_eq!(counted.medium_scatters, 1);
+        assert_eq!(counted.camera_rays, 0);
+        assert_eq!(counted.path_lengths[2], 1);
+        assert_eq!(counted.path_lengths[PATH_LENGTHS - 1], 1);
+        assert_eq!(counted.paths(), 2);
+
+        // other threads count separately, collect() sees them all
+        let everything = RenderStats::collect();
+        std::thread::spawn(|| count(Counter::ShadowRays))
+            .join()
+            .unwrap();
+        assert_eq!(RenderStats::this_thread().since(&before).shadow_rays, 2);
+        assert!(RenderStats::collect().shadow_rays > everything.shadow_rays);
+
+        // adding up two lots, starting from nothing
+        let mut total = RenderStats::default();
+        total.add(&counted);
+        total.add(&counted);
+        assert_eq!(total.camera_rays, 2 * counted.camera_rays);
+        assert_eq!(total.paths(), 2 * counted.paths());
+        assert_eq!(total.path_lengths[PATH_LENGTHS - 1], 2);
+    }
+
+    #[test]
+    fn test_path() {
+        // inside a sphere there's always something to hit, so every path
+        // bounces until it runs out of depth or roulette ends it
+        let gray =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.5, 0.5, 0.5))));
+        let world = Hitters::Sphere(Sphere::new(&vect!(0, 0, 0), 10.0, gray));
+        let mut sampler = IndependentSampler::new(20);
+        let before = RenderStats::this_thread();
+        for _ in 0..10 {
+            let r = ray!(&vect!(0, 0, 0), &vect!(0, 0, -1));
+            color(&r, &world, 4, &Background::default(), &mut sampler);
+        }
+        let counted = RenderStats::this_thread().since(&before);
+        assert_eq!(counted.paths(), 10);
+        // one bounce ray for every bounce a path took
+        let bounces: u64 = (0..PATH_LENGTHS)
+            .map(|n| n as u64 * counted.path_lengths[n])
+            .sum();
+        assert_eq!(counted.bounce_rays, bounces);
+        // and every ray was tested against the sphere once
+        assert_eq!(counted.intersection_tests, 10 + counted.bounce_rays);
+        assert!(counted.path_lengths[5..].iter().all(|&n| n == 0));
+
+        assert!(counted.summary(2.0).contains("rays/s"));
+        assert!(counted.to_json(2.0).contains("\"paths\": 10,"));
+    }
+}
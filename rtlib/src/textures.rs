@@ -182,6 +182,14 @@ impl NoiseTexture {
         }
     }
 
+    // see Perlin::with_rng()
+    pub fn with_rng(rng: &mut impl rand::Rng) -> Self {
+        NoiseTexture {
+            inner_noise: Perlin::with_rng(rng),
+            scale: Some(1.0),
+        }
+    }
+
     pub fn noise(&self, p: &Vec3) -> f64 {
         self.inner_noise.noise(p)
     }
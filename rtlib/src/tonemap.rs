@@ -0,0 +1,327 @@
+use crate::vec3::Color;
+use crate::vect;
+
+// Turning the linear light we render into values for a screen happens in
+// three steps: exposure scales everything up or down, a tone mapper squeezes
+// whatever is brighter than white back into range instead of letting it
+// clip, and a transfer function encodes the result for the display. The
+// defaults give the look we've always had, plain clamping with gamma 2.
+
+pub trait ToneMapper {
+    // linear light in, linear light between 0 and 1 out
+    fn map(&self, c: &Color) -> Color;
+}
+
+// how bright white is for ReinhardExtended unless told otherwise
+pub const DEFAULT_WHITE_POINT: f64 = 4.0;
+
+fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ToneMapperType {
+    // nothing, anything over 1 clips
+    #[default]
+    Linear,
+    // L / (1 + L) on the luminance, which keeps the hue. Never quite reaches
+    // white.
+    Reinhard,
+    // the same, but luminance `white` and above comes out white
+    ReinhardExtended {
+        white: f64,
+    },
+    // John Hable's filmic curve from Uncharted 2, with its usual exposure bias
+    Hable,
+    // Stephen Hill's fit of the ACES reference and output transforms
+    Aces,
+}
+
+impl ToneMapperType {
+    // only ReinhardExtended has one, the others are left as they are
+    pub fn white_point(self, white: f64) -> Self {
+        match self {
+            ToneMapperType::ReinhardExtended { .. } => ToneMapperType::ReinhardExtended { white },
+            other => other,
+        }
+    }
+}
+
+fn hable_partial(x: f64) -> f64 {
+    const A: f64 = 0.15;
+    const B: f64 = 0.50;
+    const C: f64 = 0.10;
+    const D: f64 = 0.20;
+    const E: f64 = 0.02;
+    const F: f64 = 0.30;
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+fn aces_fitted(c: &Color) -> Color {
+    // sRGB into the ACES working space, with the RRT's saturation change
+    let input = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    // and back out of it
+    let output = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let times = |m: [[f64; 3]; 3], v: &Color| {
+        vect!(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z
+        )
+    };
+    let rrt_and_odt =
+        |v: f64| (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.432951) + 0.238081);
+    let v = times(input, c);
+    let v = vect!(rrt_and_odt(v.x), rrt_and_odt(v.y), rrt_and_odt(v.z));
+    times(output, &v)
+}
+
+impl ToneMapper for ToneMapperType {
+    fn map(&self, c: &Color) -> Color {
+        let clamp = |c: Color| {
+            vect!(
+                c.x.clamp(0.0, 1.0),
+                c.y.clamp(0.0, 1.0),
+                c.z.clamp(0.0, 1.0)
+            )
+        };
+        match self {
+            ToneMapperType::Linear => clamp(*c),
+            ToneMapperType::Reinhard => {
+                let l = luminance(c);
+                if l <= 0.0 {
+                    return Color::default();
+                }
+                clamp(*c * (1.0 / (1.0 + l)))
+            }
+            ToneMapperType::ReinhardExtended { white } => {
+                let l = luminance(c);
+                if l <= 0.0 {
+                    return Color::default();
+                }
+                let mapped = l * (1.0 + l / (white * white)) / (1.0 + l);
+                clamp(*c * (mapped / l))
+            }
+            ToneMapperType::Hable => {
+                const EXPOSURE_BIAS: f64 = 2.0;
+                // the linear value that comes out white
+                const WHITE: f64 = 11.2;
+                let scale = 1.0 / hable_partial(WHITE);
+                let curve = |v: f64| hable_partial(EXPOSURE_BIAS * v.max(0.0)) * scale;
+                clamp(vect!(curve(c.x), curve(c.y), curve(c.z)))
+            }
+            ToneMapperType::Aces => clamp(aces_fitted(&vect!(
+                c.x.max(0.0),
+                c.y.max(0.0),
+                c.z.max(0.0)
+            ))),
+        }
+    }
+}
+
+impl std::str::FromStr for ToneMapperType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" | "clamp" => Ok(ToneMapperType::Linear),
+            "reinhard" => Ok(ToneMapperType::Reinhard),
+            "reinhard_extended" => Ok(ToneMapperType::ReinhardExtended {
+                white: DEFAULT_WHITE_POINT,
+            }),
+            "hable" | "filmic" | "uncharted2" => Ok(ToneMapperType::Hable),
+            "aces" => Ok(ToneMapperType::Aces),
+            _ => Err(format!(
+                "unknown tone mapper '{}', expected one of: linear, reinhard, reinhard_extended, hable, aces",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ToneMapperType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToneMapperType::Linear => write!(f, "linear"),
+            ToneMapperType::Reinhard => write!(f, "reinhard"),
+            ToneMapperType::ReinhardExtended { .. } => write!(f, "reinhard_extended"),
+            ToneMapperType::Hable => write!(f, "hable"),
+            ToneMapperType::Aces => write!(f, "aces"),
+        }
+    }
+}
+
+// how 0..1 linear values are encoded for the screen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transfer {
+    // square root, what we've always done
+    #[default]
+    Gamma2,
+    // the real sRGB curve, a little linear bit near black and then 2.4
+    Srgb,
+    // none at all
+    Linear,
+}
+
+impl Transfer {
+    pub fn encode(&self, v: f64) -> f64 {
+        match self {
+            Transfer::Gamma2 => v.sqrt(),
+            Transfer::Srgb => {
+                if v <= 0.0031308 {
+                    12.92 * v
+                } else {
+                    1.055 * v.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            Transfer::Linear => v,
+        }
+    }
+}
+
+impl std::str::FromStr for Transfer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gamma2" => Ok(Transfer::Gamma2),
+            "srgb" => Ok(Transfer::Srgb),
+            "linear" => Ok(Transfer::Linear),
+            _ => Err(format!(
+                "unknown transfer function '{}', expected one of: gamma2, srgb, linear",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transfer::Gamma2 => write!(f, "gamma2"),
+            Transfer::Srgb => write!(f, "srgb"),
+            Transfer::Linear => write!(f, "linear"),
+        }
+    }
+}
+
+// everything it takes to get from a pixel to bytes on the screen
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DisplayTransform {
+    pub tone_mapper: ToneMapperType,
+    // in stops, each one doubles the light
+    pub exposure: f64,
+    pub transfer: Transfer,
+}
+
+impl DisplayTransform {
+    pub fn new(tone_mapper: ToneMapperType, exposure: f64, transfer: Transfer) -> Self {
+        DisplayTransform {
+            tone_mapper,
+            exposure,
+            transfer,
+        }
+    }
+
+    pub fn to_rgb8(&self, c: &Color) -> [u8; 3] {
+        let mapped = self.tone_mapper.map(&(*c * 2f64.powf(self.exposure)));
+        let byte = |v: f64| {
+            ((self.transfer.encode(v) * (u8::MAX as f64)) as i32).clamp(0, u8::MAX as i32) as u8
+        };
+        [byte(mapped.x), byte(mapped.y), byte(mapped.z)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DisplayTransform, ToneMapper, ToneMapperType, Transfer};
+    use crate::util::color_to_rgb8;
+    use crate::vect;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const ALL: [ToneMapperType; 5] = [
+        ToneMapperType::Linear,
+        ToneMapperType::Reinhard,
+        ToneMapperType::ReinhardExtended { white: 4.0 },
+        ToneMapperType::Hable,
+        ToneMapperType::Aces,
+    ];
+
+    #[test]
+    fn test_default_is_legacy() {
+        // has to match write_color() byte for byte
+        let mut rng = StdRng::seed_from_u64(3);
+        let display = DisplayTransform::default();
+        for _ in 0..10_000 {
+            let c = vect!(
+                rng.gen::<f64>() * 1.5,
+                rng.gen::<f64>(),
+                rng.gen::<f64>() * 0.01
+            );
+            assert_eq!(display.to_rgb8(&c), color_to_rgb8(c, 1));
+        }
+    }
+
+    #[test]
+    fn test_tone_mappers() {
+        for op in ALL {
+            assert_eq!(op.to_string().parse::<ToneMapperType>().unwrap(), op);
+            assert_eq!(op.map(&vect!(0, 0, 0)).length(), 0.0, "{}", op);
+            // brighter in is never darker out, and it stays in range
+            let mut last = -1.0;
+            for i in 0..200 {
+                let v = i as f64 * 0.1;
+                let out = op.map(&vect!(v, v, v));
+                assert!(out.x >= last - 1e-12, "{} at {}", op, v);
+                assert!((0.0..=1.0).contains(&out.x), "{} at {}", op, v);
+                last = out.x;
+            }
+        }
+        // ones that don't clip keep highlights apart that linear doesn't
+        let bright = |op: ToneMapperType| op.map(&vect!(3, 3, 3)).x < op.map(&vect!(6, 6, 6)).x;
+        assert!(!bright(ToneMapperType::Linear));
+        assert!(
+            bright(ToneMapperType::Reinhard)
+                && bright(ToneMapperType::Hable)
+                && bright(ToneMapperType::Aces)
+        );
+        // the extended one reaches white at its white point
+        let extended = ToneMapperType::ReinhardExtended { white: 4.0 };
+        assert!((extended.map(&vect!(4, 4, 4)).x - 1.0).abs() < 1e-12);
+        assert_eq!(
+            ToneMapperType::Reinhard.white_point(2.0),
+            ToneMapperType::Reinhard
+        );
+        assert_eq!(
+            "reinhard_extended"
+                .parse::<ToneMapperType>()
+                .unwrap()
+                .white_point(2.0),
+            ToneMapperType::ReinhardExtended { white: 2.0 }
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        assert_eq!(Transfer::Srgb.encode(0.0), 0.0);
+        assert!((Transfer::Srgb.encode(1.0) - 1.0).abs() < 1e-12);
+        // 18% grey is about 46% in sRGB
+        assert!((Transfer::Srgb.encode(0.18) - 0.4613).abs() < 1e-3);
+        // both pieces of the curve meet
+        let knee = 0.0031308;
+        assert!((Transfer::Srgb.encode(knee) - Transfer::Srgb.encode(knee + 1e-9)).abs() < 1e-6);
+        assert_eq!(Transfer::Gamma2.encode(0.25), 0.5);
+
+        // a stop of exposure is twice the light
+        let half = DisplayTransform::new(ToneMapperType::Linear, -1.0, Transfer::Linear);
+        assert_eq!(half.to_rgb8(&vect!(1, 0.5, 0)), [127, 63, 0]);
+    }
+}
@@ -1,12 +1,15 @@
 #[allow(unused_imports)]
 use super::cube::Cube;
+use super::environment::Background;
 use super::hitlist::HitList;
 #[allow(unused_imports)]
-use super::hittable::{Hitters, TextureCoord};
+use super::hittable::{HitRecord, Hitters, TextureCoord};
 use super::materials::{Dielectric, DiffuseLight, Lambertian, Material, MaterialType, Metal};
 use super::ray::Ray;
 use super::rectangle::{Axis, Rect};
+use super::sampler::Sampler;
 use super::sphere::{MovingSphere, Sphere};
+use super::stats::{count, count_path, Counter};
 use super::textures::{
     CheckerTexture, ConstantTexture, MappedTextureBuilder, NoiseTexture, TextureType,
 };
@@ -213,7 +216,13 @@ impl Assigned for Yes {}
 impl NotAssigned for No {}
 
 #[allow(unused_imports, dead_code)]
-pub fn color(ray: &Ray, world: &dyn Hittable, depth: i32, interior_light: &Color) -> Color {
+pub fn color(
+    ray: &Ray,
+    world: &dyn Hittable,
+    depth: i32,
+    background: &Background,
+    sampler: &mut dyn Sampler,
+) -> Color {
     // the 0.001 ignores hits very close to 0, which handles issues with
     // floating point approximation, which generates "shadow acne"
 
@@ -223,64 +232,90 @@ pub fn color(ray: &Ray, world: &dyn Hittable, depth: i32, interior_light: &Color
     // the method to iterative instead; the result used far less stack and was
     // considerably faster.
     //
-    // Now, we're adding lighting. This is an addative to the result of the recursive
-    // call. Now the method looks like a Taylors Series. Using Horners method, you would
-    // structure your polynomial similarly to how this algorithm is layed out. Meaning,
-    // this form of the equation x + fn(x + fn()) is the most efficient way to calculate
-    // a polynomial, but you have to start from the inside and work your way out. Since
-    // we can't be recursive due to size, we'll store values in a Vec/stack rather than
-    // on THE stack.
-    let mut emitts: Vec<Color> = Vec::with_capacity(depth as usize + 1);
-    let mut attenuations: Vec<Color> = Vec::with_capacity(depth as usize + 1);
-    //let start_color = vect!(1,1,1);
-    //let start_color = vect!(0,0,0);
-    let start_color = *interior_light;
+    // With lighting it became a Horner's method fold, the emitted and attenuation
+    // of every bounce went in a Vec and got folded from the inside out at the end.
+    // That's the same as multiplying the attenuations together on the way out
+    // (the throughput) and adding emitted * throughput as we go, which doesn't
+    // need the Vecs. Knowing the throughput also means we can stop paths that
+    // aren't going to add anything anymore, see russian_roulette().
+    //
     // since this will reduce the color by a percent, we'll default to (1,1,1) for
     // interior lighting. For scenes with explicit lighting, (0,0,0) should be used.
+    // The fold started from the interior light and also pushed it as the last
+    // attenuation, so it was applied twice when the path ended. Keep that so the
+    // scenes look the way they always have, see Background::ended().
+    let mut radiance = Color::default();
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
     let mut tmpray = *ray;
-    let mut depth = depth;
+    let mut bounces = 0;
     loop {
-        // does the ray we currently have
-        let hit_record = world.hit(&tmpray, 0.001, f64::INFINITY);
-        if let Some(hr) = hit_record {
-            // as soon as we know we have a hit, generate the emitted value
-            let emitted = hr.material.emitted(
-                hr.texture_coord.unwrap_or_default().u,
-                hr.texture_coord.unwrap_or_default().v,
-                &hr.p,
-            );
-            // now, do we scatter the ray?
-            if depth > 0 &&
-                let Some((attenuation, sray)) =
-                   hr.material.scatter(&tmpray, &hr) {
+        // does the ray we currently have hit anything
+        let hr = match world.hit(&tmpray, 0.001, f64::INFINITY) {
+            Some(hr) => hr,
+            None => {
+                radiance += throughput * background.escaped(&tmpray);
+                break;
+            }
+        };
+        // as soon as we know we have a hit, add the emitted value. Likely the
+        // default of black
+        let emitted = hr.material.emitted(
+            hr.texture_coord.unwrap_or_default().u,
+            hr.texture_coord.unwrap_or_default().v,
+            &hr.p,
+        );
+        radiance += throughput * emitted;
 
-                emitts.push(emitted);
-                attenuations.push(attenuation);
+        // now, do we scatter the ray?
+        let scattered = if bounces < depth {
+            hr.material.scatter(&tmpray, &hr, sampler)
+        } else {
+            None
+        };
+        match scattered {
+            Some((attenuation, sray)) => {
+                throughput *= attenuation;
+                if !russian_roulette(&mut throughput, bounces, sampler) {
+                    break;
+                }
+                count(Counter::BounceRays);
                 tmpray = sray;
-                depth -= 1;
-            } else {
-                // return emitted. Likely the default of black
-                emitts.push(emitted);
-                attenuations.push(start_color);
+                bounces += 1;
+            }
+            None => {
+                radiance += throughput * background.ended();
                 break;
             }
-        } else {
-            // return emitted. Likely the default of black
-            emitts.push(vect!(0, 0, 0));
-            attenuations.push(start_color);
-            break;
         }
     }
+    count_path(bounces);
+    radiance
+}
 
-    // we've checked to see if we've hit anything, we've accumulated attenuation and color
-    // return the overall result
-    // multiplicitive for color, so use 1 for identity
-    let it = emitts.iter().zip(attenuations.iter()).rev();
-    let mut color = start_color;
-    for (e, a) in it {
-        color = (*a * color) + e;
+// How many bounces a path always gets before russian roulette can end it. The
+// first few bounces carry most of the light and are cheap to keep.
+pub const ROULETTE_MIN_BOUNCES: i32 = 3;
+
+// Russian roulette. Once the throughput has dropped, whatever the rest of the
+// path finds gets multiplied by something small, so it's mostly wasted work.
+// We end the path with probability q, and scale up the paths that survive by
+// 1/(1-q) to make up for the ones we killed, so on average the result doesn't
+// change (it's unbiased, just a little noisier). Returns false if the path
+// should stop here.
+pub fn russian_roulette(throughput: &mut Color, bounces: i32, sampler: &mut dyn Sampler) -> bool {
+    if bounces < ROULETTE_MIN_BOUNCES {
+        return true;
+    }
+    let max_component = throughput.x.max(throughput.y).max(throughput.z);
+    if max_component >= 1.0 {
+        return true;
+    }
+    let q = (1.0 - max_component).max(0.05);
+    if sampler.get_1d() < q {
+        return false;
     }
-    color
+    *throughput /= 1.0 - q;
+    true
 }
 
 // This is used for all of the initial images that we've done where you just see
@@ -288,22 +323,329 @@ pub fn color(ray: &Ray, world: &dyn Hittable, depth: i32, interior_light: &Color
 // of color(subsequent colors/hits are multiplied to each other, this reduces the value
 // and makes things darker). You use this if you want to see the scene light fully.
 #[allow(unused_imports, dead_code)]
-pub fn color_use_interior_lighting(ray: &Ray, world: &dyn Hittable, depth: i32) -> Color {
-    let interior_light = Color::new(1.0, 1.0, 1.0);
-    color(ray, world, depth, &interior_light)
+pub fn color_use_interior_lighting(
+    ray: &Ray,
+    world: &dyn Hittable,
+    depth: i32,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let interior_light = Background::Constant(Color::new(1.0, 1.0, 1.0));
+    color(ray, world, depth, &interior_light, sampler)
 }
 
 // This is used when you've placed lights in the scene and want to have anything not lit
 // dark, or unable to be seen. You could also call the color function and pass
 // in the color/light directly.
 #[allow(unused_imports, dead_code)]
-pub fn color_use_explicit_lighting(ray: &Ray, world: &dyn Hittable, depth: i32) -> Color {
-    let interior_light = Color::new(0.0, 0.0, 0.0);
-    color(ray, world, depth, &interior_light)
+pub fn color_use_explicit_lighting(
+    ray: &Ray,
+    world: &dyn Hittable,
+    depth: i32,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let interior_light = Background::Constant(Color::new(0.0, 0.0, 0.0));
+    color(ray, world, depth, &interior_light, sampler)
+}
+
+// Which of the color functions the renderer should call for each sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Integrator {
+    // plain path tracing with color()
+    #[default]
+    Path,
+    // path tracing that also samples the lights at every diffuse hit
+    DirectLighting,
+    // light sampling and bounces both, weighed against each other
+    MultipleImportance,
+    // paths from the camera and from the lights, joined up, see bdpt.rs
+    Bidirectional,
+    // direct lighting, with caustics from a photon map, see photon.rs
+    PhotonMapping,
+}
+
+impl std::str::FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Integrator::Path),
+            "direct" | "nee" => Ok(Integrator::DirectLighting),
+            "mis" => Ok(Integrator::MultipleImportance),
+            "bdpt" => Ok(Integrator::Bidirectional),
+            "photon" => Ok(Integrator::PhotonMapping),
+            _ => Err(format!(
+                "unknown integrator '{}', expected one of: path, direct, mis, bdpt, photon",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Integrator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Integrator::Path => write!(f, "path"),
+            Integrator::DirectLighting => write!(f, "direct"),
+            Integrator::MultipleImportance => write!(f, "mis"),
+            Integrator::Bidirectional => write!(f, "bdpt"),
+            Integrator::PhotonMapping => write!(f, "photon"),
+        }
+    }
+}
+
+// Next event estimation. With small lights, like the one in the cornell box,
+// almost none of the bounced rays ever find the light, so the picture is all
+// noise. Here, every time we land on a diffuse surface we also pick a point on
+// one of the lights and send a shadow ray at it. The bounce is still followed
+// for the indirect light, but if it runs into a light we already sampled from
+// there, that light was counted by the shadow ray and we skip it.
+//
+// lights should hold everything world.emitters() gives back. Mirrors and glass
+// can't use the shadow rays (they only reflect one direction), so from those we
+// count whatever light the bounce hits, same as color(). Anything else gets
+// lit through its bsdf(). An environment map is sampled like another light.
+#[allow(unused_imports, dead_code)]
+pub fn color_direct_lighting(
+    ray: &Ray,
+    world: &dyn Hittable,
+    lights: &HitList,
+    depth: i32,
+    background: &Background,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    if lights.list.is_empty() && !background.is_sampled() {
+        return color(ray, world, depth, background, sampler);
+    }
+    let mut radiance = Color::default();
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    // whether a shadow ray was sent from where the bounce started
+    let mut sampled = false;
+    let mut tmpray = *ray;
+    let mut bounces = 0;
+    loop {
+        let hr = match world.hit(&tmpray, 0.001, f64::INFINITY) {
+            Some(hr) => hr,
+            None => {
+                // the shadow ray had the same chance of finding it
+                if !sampled || !background.is_sampled() {
+                    radiance += throughput * background.escaped(&tmpray);
+                }
+                break;
+            }
+        };
+        let emitted = hr.material.emitted(
+            hr.texture_coord.unwrap_or_default().u,
+            hr.texture_coord.unwrap_or_default().v,
+            &hr.p,
+        );
+        // It's whatever the bounce landed on that counts. The shadow ray
+        // only counts the lights in the list, so anything else that glows,
+        // even right in front of one of them, is left to us.
+        let already_counted = sampled && lights.surface_pdf(&hr.p) > 0.0;
+        if !already_counted {
+            radiance += throughput * emitted;
+        }
+
+        let scattered = if bounces < depth {
+            hr.material.scatter(&tmpray, &hr, sampler)
+        } else {
+            None
+        };
+        let (attenuation, sray) = match scattered {
+            Some(scattered) => scattered,
+            None => {
+                radiance += throughput * background.ended();
+                break;
+            }
+        };
+        if hr.material.is_specular() {
+            sampled = false;
+        } else {
+            radiance += throughput
+                * sample_one_light(world, lights, background, &tmpray, &hr, false, sampler);
+            sampled = true;
+        }
+        throughput *= attenuation;
+        if !russian_roulette(&mut throughput, bounces, sampler) {
+            break;
+        }
+        count(Counter::BounceRays);
+        tmpray = sray;
+        bounces += 1;
+    }
+    count_path(bounces);
+    radiance
 }
 
+// Multiple importance sampling. Like color_direct_lighting() we send a shadow
+// ray at a light from every non specular hit, but the bounce is also allowed
+// to count the lights it runs into. Each of the two gets weighed by the power
+// heuristic, so whichever of them was more likely to find that light gets
+// most of the say. Light sampling is great for small lights on rough
+// surfaces, bounces are better for big lights or shiny surfaces, this handles
+// both.
 #[allow(unused_imports, dead_code)]
-pub fn color_just_attenuation(ray: &Ray, world: &dyn Hittable, _depth: i32) -> Color {
+pub fn color_mis(
+    ray: &Ray,
+    world: &dyn Hittable,
+    lights: &HitList,
+    depth: i32,
+    background: &Background,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    if lights.list.is_empty() && !background.is_sampled() {
+        return color(ray, world, depth, background, sampler);
+    }
+    let mut radiance = Color::default();
+    let mut throughput = Color::new(1.0, 1.0, 1.0);
+    // where we bounced from and the pdf of the bounce, None after the camera
+    // or a specular bounce since light sampling can't find those paths
+    let mut bounced_from: Option<(Point3, f64)> = None;
+    let mut tmpray = *ray;
+    let mut bounces = 0;
+    loop {
+        let hr = match world.hit(&tmpray, 0.001, f64::INFINITY) {
+            Some(hr) => hr,
+            None => {
+                let weight = match bounced_from {
+                    Some((origin, bsdf_pdf)) if background.is_sampled() => power_heuristic(
+                        1,
+                        bsdf_pdf,
+                        1,
+                        light_pdf(lights, background, &origin, &tmpray.direction()),
+                    ),
+                    _ => 1.0,
+                };
+                radiance += throughput * background.escaped(&tmpray) * weight;
+                break;
+            }
+        };
+        let emitted = hr.material.emitted(
+            hr.texture_coord.unwrap_or_default().u,
+            hr.texture_coord.unwrap_or_default().v,
+            &hr.p,
+        );
+        // only the lights in the list could have been found by a shadow ray
+        let weight = match bounced_from {
+            Some((origin, bsdf_pdf)) if lights.surface_pdf(&hr.p) > 0.0 => power_heuristic(
+                1,
+                bsdf_pdf,
+                1,
+                light_pdf(lights, background, &origin, &tmpray.direction()),
+            ),
+            _ => 1.0,
+        };
+        radiance += throughput * emitted * weight;
+
+        let scattered = if bounces < depth {
+            hr.material.scatter(&tmpray, &hr, sampler)
+        } else {
+            None
+        };
+        let (attenuation, sray) = match scattered {
+            Some(scattered) => scattered,
+            None => {
+                radiance += throughput * background.ended();
+                break;
+            }
+        };
+        let bsdf_pdf = hr.material.scattering_pdf(&tmpray, &hr, &sray);
+        if hr.material.is_specular() || bsdf_pdf <= 0.0 {
+            bounced_from = None;
+        } else {
+            radiance += throughput
+                * sample_one_light(world, lights, background, &tmpray, &hr, true, sampler);
+            bounced_from = Some((hr.p, bsdf_pdf));
+        }
+        throughput *= attenuation;
+        if !russian_roulette(&mut throughput, bounces, sampler) {
+            break;
+        }
+        count(Counter::BounceRays);
+        tmpray = sray;
+        bounces += 1;
+    }
+    count_path(bounces);
+    radiance
+}
+
+// The chance of sample_one_light() sending its shadow ray in direction. When
+// the background can be sampled too, it gets half of the shadow rays.
+pub(crate) fn light_pdf(
+    lights: &HitList,
+    background: &Background,
+    origin: &Point3,
+    direction: &Vec3,
+) -> f64 {
+    if !background.is_sampled() {
+        lights.pdf_value(origin, direction)
+    } else if lights.list.is_empty() {
+        background.pdf(direction)
+    } else {
+        0.5 * lights.pdf_value(origin, direction) + 0.5 * background.pdf(direction)
+    }
+}
+
+// The light reflected back along ray_in from a point picked on one of the
+// lights, with the bsdf and cosine already applied. With mis it's also weighed
+// against the chance of the material bouncing that way on its own.
+pub(crate) fn sample_one_light(
+    world: &dyn Hittable,
+    lights: &HitList,
+    background: &Background,
+    ray_in: &Ray,
+    rec: &HitRecord,
+    mis: bool,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let to_light = if background.is_sampled() && (lights.list.is_empty() || sampler.get_1d() < 0.5)
+    {
+        match background.sample(sampler) {
+            Some((direction, _)) => direction,
+            None => return Color::default(),
+        }
+    } else {
+        lights.random(&rec.p, sampler)
+    };
+    let light_pdf = light_pdf(lights, background, &rec.p, &to_light);
+    let cosine = dot(&unit_vector(&to_light), &rec.normal).abs();
+    if light_pdf <= 0.0 || cosine <= 0.0 {
+        return Color::default();
+    }
+    let shadow_ray =
+        Ray::new(&rec.p, &to_light, Some(ray_in.time())).with_medium_sample(sampler.get_1d());
+    let bsdf = rec.material.bsdf(ray_in, rec, &shadow_ray);
+    if bsdf == Color::default() {
+        return bsdf;
+    }
+    count(Counter::ShadowRays);
+    let light = match world.hit(&shadow_ray, 0.001, f64::INFINITY) {
+        Some(light_rec) if lights.surface_pdf(&light_rec.p) > 0.0 => light_rec.material.emitted(
+            light_rec.texture_coord.unwrap_or_default().u,
+            light_rec.texture_coord.unwrap_or_default().v,
+            &light_rec.p,
+        ),
+        // something else is in the way, if it glows the bounces count it
+        Some(_) => return Color::default(),
+        None if background.is_sampled() => background.escaped(&shadow_ray),
+        None => return Color::default(),
+    };
+    let weight = if mis {
+        let bsdf_pdf = rec.material.scattering_pdf(ray_in, rec, &shadow_ray);
+        power_heuristic(1, light_pdf, 1, bsdf_pdf)
+    } else {
+        1.0
+    };
+    light * bsdf * (cosine * weight / light_pdf)
+}
+
+#[allow(unused_imports, dead_code)]
+pub fn color_just_attenuation(
+    ray: &Ray,
+    world: &dyn Hittable,
+    _depth: i32,
+    sampler: &mut dyn Sampler,
+) -> Color {
     // the 0.001 ignores hits very close to 0, which handles issues with
     // floating point approximation, which generates "shadow acne"
     let last_color: Color;
@@ -311,7 +653,7 @@ pub fn color_just_attenuation(ray: &Ray, world: &dyn Hittable, _depth: i32) -> C
     // does the ray we currently have
     if let Some(hit_record) = world.hit(ray, 0.001, f64::INFINITY) {
         // attenuation IS the color returned
-        if let Some((attenuation, _)) = hit_record.material.scatter(ray, &hit_record) {
+        if let Some((attenuation, _)) = hit_record.material.scatter(ray, &hit_record, sampler) {
             attenuation
         } else {
             Color::new(0.0, 0.0, 0.0)
@@ -332,6 +674,12 @@ pub fn write_color(
     pixel_color: Color,
     samples_per_pixel: i32,
 ) -> Result<(), io::Error> {
+    let [r, g, b] = color_to_rgb8(pixel_color, samples_per_pixel);
+    writeln!(stream, "{} {} {}", r, g, b).map(|_| ())
+}
+
+// what write_color() prints, for the image writers that want bytes
+pub fn color_to_rgb8(pixel_color: Color, samples_per_pixel: i32) -> [u8; 3] {
     let mut r = pixel_color.x;
     let mut g = pixel_color.y;
     let mut b = pixel_color.z;
@@ -342,14 +690,11 @@ pub fn write_color(
     g = (scale * g).sqrt();
     b = (scale * b).sqrt();
 
-    writeln!(
-        stream,
-        "{} {} {}",
-        ((r * (u8::MAX as f64 * 1.)) as i32).clamp(0, u8::MAX as i32),
-        ((g * (u8::MAX as f64 * 1.)) as i32).clamp(0, u8::MAX as i32),
-        ((b * (u8::MAX as f64 * 1.)) as i32).clamp(0, u8::MAX as i32)
-    )
-    .map(|_| ())
+    [
+        ((r * (u8::MAX as f64 * 1.)) as i32).clamp(0, u8::MAX as i32) as u8,
+        ((g * (u8::MAX as f64 * 1.)) as i32).clamp(0, u8::MAX as i32) as u8,
+        ((b * (u8::MAX as f64 * 1.)) as i32).clamp(0, u8::MAX as i32) as u8,
+    ]
 }
 
 #[allow(unused_imports, dead_code)]
@@ -386,13 +731,53 @@ pub fn random_in_unit_sphere() -> Vec3 {
             2.0 * Vec3::new(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>())
                 - Vec3::new(1.0, 1.0, 1.0),
         );
-        if p.unwrap().length_squared() >= 1.0 {
+        // keep going until we land inside the sphere
+        if p.unwrap().length_squared() < 1.0 {
             break;
         }
     }
     p.unwrap()
 }
 
+// Also uniform in the unit ball, but from three numbers in [0, 1) instead of
+// trying until one lands inside: a uniform direction, then a distance. The
+// cube root is because there's more room further out.
+pub fn sample_in_unit_sphere(u: f64, v: f64, w: f64) -> Vec3 {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).sqrt();
+    let phi = 2.0 * std::f64::consts::PI * v;
+    w.cbrt() * vect!(phi.cos() * r, phi.sin() * r, z)
+}
+
+// A direction around +z with pdf cos(theta)/pi, use an Onb to point it along
+// a normal.
+#[allow(unused_imports, dead_code)]
+pub fn random_cosine_direction() -> Vec3 {
+    let mut rng = rand::thread_rng();
+    cosine_direction(rng.gen(), rng.gen())
+}
+
+// random_cosine_direction() for numbers that come from a sampler
+pub fn cosine_direction(r1: f64, r2: f64) -> Vec3 {
+    let phi = 2.0 * std::f64::consts::PI * r1;
+    vect!(
+        phi.cos() * r2.sqrt(),
+        phi.sin() * r2.sqrt(),
+        (1.0 - r2).sqrt()
+    )
+}
+
+// Veach's power heuristic (with beta = 2) for weighing a sample from strategy
+// f against the other strategy g that could have made it.
+pub fn power_heuristic(nf: i32, f_pdf: f64, ng: i32, g_pdf: f64) -> f64 {
+    let f = nf as f64 * f_pdf;
+    let g = ng as f64 * g_pdf;
+    if f * f + g * g == 0.0 {
+        return 0.0;
+    }
+    (f * f) / (f * f + g * g)
+}
+
 #[allow(unused_imports, dead_code)]
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
     *v - (2_f64 * v.dot(n) * *n)
@@ -536,8 +921,8 @@ pub fn two_spheres() -> HitList {
 }
 
 #[allow(unused_imports, dead_code)]
-pub fn two_perlin_spheres() -> HitList {
-    let pertext = TextureType::NoiseTexture(NoiseTexture::new().scale(5.7));
+pub fn two_perlin_spheres(rng: &mut impl rand::Rng) -> HitList {
+    let pertext = TextureType::NoiseTexture(NoiseTexture::with_rng(rng).scale(5.7));
     let mut hl: HitList = HitList::new();
     hl.add(Hitters::Sphere(Sphere::new(
         &vect!(0, -1000, 0),
@@ -704,8 +1089,8 @@ pub fn earth_scene() -> HitList {
 }
 
 #[allow(unused_imports, dead_code)]
-pub fn simple_light_scene() -> HitList {
-    let mut hl = two_perlin_spheres();
+pub fn simple_light_scene(rng: &mut impl rand::Rng) -> HitList {
+    let mut hl = two_perlin_spheres(rng);
 
     hl.add(Hitters::Sphere(Sphere::new(
         &vect!(0, 7, 0),
@@ -902,8 +1287,7 @@ pub fn cornell_smoke() -> HitList {
     hl
 }
 
-pub fn final_scene() -> HitList {
-    let mut rng = rand::thread_rng();
+pub fn final_scene(rng: &mut impl rand::Rng) -> HitList {
     let mut hl = HitList::new();
     let mut boxlist = HitList::new();
     let mut boxlist2 = HitList::new();
@@ -933,7 +1317,7 @@ pub fn final_scene() -> HitList {
         }
     }
     let mut bvh = Bvh::new();
-    bvh.add_hitlist(&mut Arc::new(boxlist), 0.0, 1.0);
+    bvh.add_hitlist(&mut Arc::new(boxlist), 0.0, 1.0, rng);
     let bvh = bvh.build();
     hl.add(Hitters::BvhNode(bvh));
     let light: MaterialType = MaterialType::DiffuseLight(DiffuseLight::new(
@@ -1015,7 +1399,7 @@ pub fn final_scene() -> HitList {
         100.,
         ematerial,
     )));
-    let pertext = TextureType::NoiseTexture(NoiseTexture::new().scale(0.03));
+    let pertext = TextureType::NoiseTexture(NoiseTexture::with_rng(rng).scale(0.03));
     hl.add(Hitters::Sphere(Sphere::new(
         &vect!(220, 280, 300),
         80.,
@@ -1034,7 +1418,7 @@ pub fn final_scene() -> HitList {
     }
 
     let mut bvh = Bvh::new();
-    bvh.add_hitlist(&mut Arc::new(boxlist2), 0.0, 1.0);
+    bvh.add_hitlist(&mut Arc::new(boxlist2), 0.0, 1.0, rng);
     let bvh = bvh.build();
     hl.add(Hitters::Custom(Custom::new(
         &TranslateHittable::new(
@@ -1063,7 +1447,8 @@ pub fn one_million_ants_er_spheres(rng: &mut impl rand::Rng) -> HitList {
                 );
                 if (center - vect!(4.0, 0.2, 0.0)).length() > 0.9 {
                     if choose_mat < 0.3 {
-                        let text = TextureType::NoiseTexture(NoiseTexture::new().scale(5.7));
+                        let text =
+                            TextureType::NoiseTexture(NoiseTexture::with_rng(rng).scale(5.7));
                         hl.add(Hitters::Sphere(Sphere::new(
                             &center,
                             0.3,
@@ -1130,9 +1515,11 @@ pub fn one_million_ants_er_spheres(rng: &mut impl rand::Rng) -> HitList {
 #[cfg(test)]
 mod test {
     use super::super::{color_to_texture, ray, wrap_material};
+    use crate::environment::Background;
     use crate::hitlist::HitList;
     use crate::hittable::Hittable;
     use crate::hittable::{HitRecord, Hitters};
+    use crate::sampler::IndependentSampler;
     use crate::sphere::Sphere;
     #[allow(unused_imports)]
     use crate::vec3::{Color, Vec3};
@@ -1160,7 +1547,14 @@ mod test {
             3.0,
             metal,
         )));
-        let c = crate::util::color(&r, &world, 100, &Color::new(1.0, 1.0, 1.0));
+        let mut sampler = IndependentSampler::new(0);
+        let c = crate::util::color(
+            &r,
+            &world,
+            100,
+            &Color::new(1.0, 1.0, 1.0).into(),
+            &mut sampler,
+        );
         // so, now that the world has a depth, and there are random bounces for refraction,
         // this becomes a whole lot more difficult to test. Even giving it perfect reflection
         // surface (metal, all white, no fuzz) it'll return some random bounces.
@@ -1226,6 +1620,234 @@ mod test {
         //println!("the hitrec i: {:?}", &hitrec);
     }
 
+    #[test]
+    fn test_color_direct_lighting() {
+        use crate::materials::{DiffuseLight, Lambertian, MaterialType};
+        use crate::rectangle::{Axis, Rect};
+
+        // with nothing to sample it's the same as color()
+        let mut world = HitList::new();
+        let metal = wrap_material!(Metal, color_to_texture!(&Color::new(1.0, 1.0, 1.0)), 0.0);
+        world
+            .list
+            .push(Hitters::Sphere(Sphere::new(&vect!(2, 2, 2), 3.0, metal)));
+        let r = ray!(&vect!(0, 0, 0), &vect!(1, 1, 1));
+        let mut sampler = IndependentSampler::new(0);
+        let c = util::color_direct_lighting(
+            &r,
+            &world,
+            &HitList::new(),
+            100,
+            &vect!(1, 1, 1).into(),
+            &mut sampler,
+        );
+        assert_eq!(c, vect!(1, 1, 1));
+
+        // a small light right above a grey floor
+        let floor =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.5, 0.5, 0.5))));
+        let light =
+            MaterialType::DiffuseLight(DiffuseLight::new(color_to_texture!(&vect!(1, 1, 1))));
+        let mut world = HitList::new();
+        world.add(Hitters::Rect(Rect::new(
+            -100.0,
+            100.0,
+            -100.0,
+            100.0,
+            0.0,
+            &floor,
+            Axis::Y,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            -0.05,
+            0.05,
+            -0.05,
+            0.05,
+            1.0,
+            &light,
+            Axis::Y,
+        )));
+        let mut lights = HitList::new();
+        lights.list = world.emitters();
+        assert_eq!(lights.list.len(), 1);
+
+        // one bounce, so all we get is the shadow ray: albedo * area / (pi * d^2)
+        let r = ray!(&vect!(0, 0.5, 0), &vect!(0, -1, 0));
+        let n = 1000;
+        let mut sum = Color::default();
+        for _ in 0..n {
+            sum += util::color_direct_lighting(
+                &r,
+                &world,
+                &lights,
+                1,
+                &Background::default(),
+                &mut sampler,
+            );
+        }
+        let expected = 0.5 * 0.01 / std::f64::consts::PI;
+        assert!((sum.x / n as f64 - expected).abs() < 0.03 * expected);
+
+        // A big light hidden behind something else that glows, which isn't
+        // in the list. The bounces towards the light land on that instead,
+        // and have to count it, same as plain bounces would.
+        let mut world = HitList::new();
+        world.add(Hitters::Rect(Rect::new(
+            -100.0,
+            100.0,
+            -100.0,
+            100.0,
+            0.0,
+            &floor,
+            Axis::Y,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            -4.0,
+            4.0,
+            -4.0,
+            4.0,
+            10.0,
+            &light,
+            Axis::Y,
+        )));
+        let mut lights = HitList::new();
+        lights.list = world.emitters();
+        world.add(Hitters::Rect(Rect::new(
+            -3.0,
+            3.0,
+            -3.0,
+            3.0,
+            5.0,
+            &light,
+            Axis::Y,
+        )));
+        let n = 20000;
+        let (mut nee, mut mis, mut path) = (0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let background = Background::default();
+            nee += util::color_direct_lighting(&r, &world, &lights, 1, &background, &mut sampler).x;
+            mis += util::color_mis(&r, &world, &lights, 1, &background, &mut sampler).x;
+            path += util::color(&r, &world, 1, &background, &mut sampler).x;
+        }
+        let (nee, mis, path) = (nee / n as f64, mis / n as f64, path / n as f64);
+        assert!(path > 0.05);
+        assert!((nee - path).abs() < 0.05 * path, "{} {}", nee, path);
+        assert!((mis - path).abs() < 0.05 * path, "{} {}", mis, path);
+    }
+
+    #[test]
+    fn test_color_mis() {
+        use crate::materials::{DiffuseLight, Lambertian, MaterialType};
+        use crate::rectangle::{Axis, Rect};
+
+        // light sampling and bounces should agree on a light that both can
+        // find easily, an overhead light as big as the floor
+        let floor =
+            MaterialType::Lambertian(Lambertian::new(&color_to_texture!(&vect!(0.5, 0.5, 0.5))));
+        let light =
+            MaterialType::DiffuseLight(DiffuseLight::new(color_to_texture!(&vect!(1, 1, 1))));
+        let mut world = HitList::new();
+        world.add(Hitters::Rect(Rect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            0.0,
+            &floor,
+            Axis::Y,
+        )));
+        world.add(Hitters::Rect(Rect::new(
+            -1.0,
+            1.0,
+            -1.0,
+            1.0,
+            1.0,
+            &light,
+            Axis::Y,
+        )));
+        let mut lights = HitList::new();
+        lights.list = world.emitters();
+
+        let r = ray!(&vect!(0, 0.5, 0), &vect!(0, -1, 0));
+        let n = 20000;
+        let mut sampler = IndependentSampler::new(0);
+        let mut mis = Color::default();
+        let mut nee = Color::default();
+        let mut path = Color::default();
+        for _ in 0..n {
+            mis += util::color_mis(&r, &world, &lights, 1, &Background::default(), &mut sampler);
+            nee += util::color_direct_lighting(
+                &r,
+                &world,
+                &lights,
+                1,
+                &Background::default(),
+                &mut sampler,
+            );
+            path += util::color(&r, &world, 1, &Background::default(), &mut sampler);
+        }
+        let (mis, nee, path) = (mis.x / n as f64, nee.x / n as f64, path.x / n as f64);
+        assert!((mis - nee).abs() < 0.02 * nee);
+        assert!((mis - path).abs() < 0.05 * path);
+    }
+
+    #[test]
+    fn test_russian_roulette() {
+        // the first bounces are always kept, as are bright paths
+        let mut sampler = IndependentSampler::new(0);
+        let mut throughput = vect!(0.01, 0.01, 0.01);
+        assert!(util::russian_roulette(&mut throughput, 0, &mut sampler));
+        assert_eq!(throughput, vect!(0.01, 0.01, 0.01));
+        let mut throughput = vect!(1, 0.5, 0.5);
+        assert!(util::russian_roulette(&mut throughput, 10, &mut sampler));
+        assert_eq!(throughput, vect!(1, 0.5, 0.5));
+
+        // dim paths survive with about the chance of their brightness, but
+        // on average still carry the same throughput
+        let n = 100_000;
+        let mut survived = 0;
+        let mut sum = Color::default();
+        for _ in 0..n {
+            let mut throughput = vect!(0.2, 0.1, 0.05);
+            if util::russian_roulette(&mut throughput, 10, &mut sampler) {
+                survived += 1;
+                sum += throughput;
+            }
+        }
+        assert!((survived as f64 / n as f64 - 0.2).abs() < 0.01);
+        assert!((sum.x / n as f64 - 0.2).abs() < 0.01);
+        assert!((sum.z / n as f64 - 0.05).abs() < 0.0025);
+    }
+
+    #[test]
+    fn test_power_heuristic() {
+        assert_eq!(util::power_heuristic(1, 1.0, 1, 0.0), 1.0);
+        assert_eq!(util::power_heuristic(1, 0.0, 1, 0.0), 0.0);
+        assert!((util::power_heuristic(1, 1.0, 1, 1.0) - 0.5).abs() < 1e-12);
+        assert!((util::power_heuristic(1, 3.0, 1, 1.0) - 0.9).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_integrator_names() {
+        use super::Integrator;
+        assert_eq!("path".parse::<Integrator>(), Ok(Integrator::Path));
+        assert_eq!(
+            "direct".parse::<Integrator>(),
+            Ok(Integrator::DirectLighting)
+        );
+        assert_eq!(
+            "mis".parse::<Integrator>(),
+            Ok(Integrator::MultipleImportance)
+        );
+        assert_eq!("bdpt".parse::<Integrator>(), Ok(Integrator::Bidirectional));
+        assert_eq!(
+            "photon".parse::<Integrator>(),
+            Ok(Integrator::PhotonMapping)
+        );
+        assert!("bogus".parse::<Integrator>().is_err());
+        assert_eq!(Integrator::DirectLighting.to_string(), "direct");
+    }
+
     #[test]
     fn test_reflect() {
         let v1 = Vec3::new(2.0, -1.0, -1.0);
@@ -3,6 +3,8 @@ use crate::{
     hittable::{HitRecord, Hittable},
     materials::MaterialType,
     ray::Ray,
+    sampler,
+    stats::{count, Counter},
     util, vect,
 };
 use rand::Rng;
@@ -13,6 +15,9 @@ pub struct ConstantMedium {
     boundary: Box<dyn Hittable>,
     density: f64,
     phase_function: MaterialType,
+    // added to the ray's medium sample, so a ray going through two media
+    // doesn't end up the same distance into both
+    sample_offset: f64,
 }
 
 impl ConstantMedium {
@@ -104,7 +109,9 @@ where
 
 impl ConstantMediumBuilder<util::Yes, util::Yes, util::Yes> {
     pub fn build(self) -> ConstantMedium {
+        let sample_offset = sampler::hash_to_unit(&format!("{} {}", self.boundary, self.density));
         ConstantMedium {
+            sample_offset,
             boundary: self.boundary,
             density: self.density,
             phase_function: self.phase_function,
@@ -137,7 +144,11 @@ impl Hittable for ConstantMedium {
                 }
                 let distance_inside_boundary: f64 =
                     (hitrec1.t - hitrec0.t) * r.direction().length();
-                let hit_distance: f64 = -1. * (1. / self.density) * rng.gen::<f64>().log(10.);
+                let u = match r.medium_sample() {
+                    Some(u) => (u + self.sample_offset).fract(),
+                    None => rng.gen::<f64>(),
+                };
+                let hit_distance: f64 = -1. * (1. / self.density) * u.log(10.);
                 if hit_distance < distance_inside_boundary {
                     if !db {
                         eprintln!("hit_distance = {}", &hit_distance);
@@ -154,6 +165,7 @@ impl Hittable for ConstantMedium {
                     let mat = self.phase_function.clone();
                     let mut retval = HitRecord::new(p, t, mat);
                     retval.normal = normal;
+                    count(Counter::MediumScatters);
                     Some(retval)
                 } else {
                     None